@@ -7,3 +7,7 @@ declare global {
     htmx: typeof htmx;
   }
 }
+
+declare module "@alpinejs/csp" {
+  export { default } from "alpinejs";
+}
@@ -1,4 +1,6 @@
-import Alpine from "alpinejs";
+// The CSP build evaluates expressions without `eval`, so the page runs
+// under a strict script-src.
+import Alpine from "@alpinejs/csp";
 import htmx from "htmx.org";
 import "@phosphor-icons/webcomponents/PhCircleHalf";
 import "@phosphor-icons/webcomponents/PhSun";
@@ -30,6 +32,11 @@ interface LayoutStateContext {
   startResize(side: SidebarSide, e: MouseEvent): void;
   doResize(e: MouseEvent): void;
   stopResize(): void;
+  isMobile(): boolean;
+  toggleSidebar(side: SidebarSide): void;
+  closeSidebarOnMobile(side: SidebarSide): void;
+  sidebarStyle(side: SidebarSide): Record<string, string>;
+  closeDropdown(el: HTMLElement): void;
   cycleTheme(): void;
   applyTheme(): void;
   $watch: <T>(property: string, callback: (value: T) => void) => void;
@@ -171,6 +178,38 @@ Alpine.data("layoutState", () => ({
     document.body.style.userSelect = "";
   },
 
+  isMobile() {
+    return window.innerWidth < 1024;
+  },
+
+  // On mobile the sidebars overlay the content, so only one opens at a time.
+  toggleSidebar(this: LayoutStateContext, side: SidebarSide) {
+    if (side === "left") {
+      if (this.isMobile() && !this.leftSidebar) this.rightSidebar = false;
+      this.leftSidebar = !this.leftSidebar;
+    } else {
+      if (this.isMobile() && !this.rightSidebar) this.leftSidebar = false;
+      this.rightSidebar = !this.rightSidebar;
+    }
+  },
+
+  closeSidebarOnMobile(this: LayoutStateContext, side: SidebarSide) {
+    if (!this.isMobile()) return;
+    if (side === "left") {
+      this.leftSidebar = false;
+    } else {
+      this.rightSidebar = false;
+    }
+  },
+
+  sidebarStyle(this: LayoutStateContext, side: SidebarSide) {
+    return { width: `${side === "left" ? this.leftWidth : this.rightWidth}px` };
+  },
+
+  closeDropdown(el: HTMLElement) {
+    el.removeAttribute("open");
+  },
+
   cycleTheme(this: LayoutStateContext) {
     if (this.theme === "system") {
       this.theme = "light";
@@ -0,0 +1,67 @@
+use {{crate_name}}::tokio_postgres_sessions::SessionCodec;
+use std::{collections::HashMap, hint::black_box};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use serde_json::json;
+use time::{Duration, OffsetDateTime};
+use tower_sessions_core::session::{Id, Record};
+
+const CODECS: [(&str, SessionCodec); 2] = [
+  ("msgpack", SessionCodec::MessagePack),
+  ("json", SessionCodec::Json),
+];
+
+/// Builds a record with `entries` keys shaped like typical session state.
+fn sample_record(entries: usize) -> Record {
+  let data: HashMap<_, _> = (0..entries)
+    .map(|i| {
+      (
+        format!("key-{i}"),
+        json!({
+          "user_id": i,
+          "name": "Ada Lovelace",
+          "roles": ["admin", "editor"],
+          "preferences": { "theme": "dark", "sidebar": true },
+        }),
+      )
+    })
+    .collect();
+
+  Record {
+    id: Id::default(),
+    data,
+    expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+  }
+}
+
+fn bench_encode(c: &mut Criterion) {
+  let mut group = c.benchmark_group("session_codec/encode");
+  for entries in [1, 16, 256] {
+    let record = sample_record(entries);
+    group.throughput(Throughput::Elements(entries as u64));
+    for (name, codec) in CODECS {
+      group.bench_with_input(BenchmarkId::new(name, entries), &record, |b, record| {
+        b.iter(|| codec.encode(black_box(record)).unwrap())
+      });
+    }
+  }
+  group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+  let mut group = c.benchmark_group("session_codec/decode");
+  for entries in [1, 16, 256] {
+    let record = sample_record(entries);
+    group.throughput(Throughput::Elements(entries as u64));
+    for (name, codec) in CODECS {
+      let encoded = codec.encode(&record).unwrap();
+      group.bench_with_input(BenchmarkId::new(name, entries), &encoded, |b, encoded| {
+        b.iter(|| black_box(encoded).decode().unwrap())
+      });
+    }
+  }
+  group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_decode);
+criterion_main!(benches);
@@ -1,10 +1,18 @@
 use std::{fs, path::PathBuf, process::Command};
 
+// Shared with the server so embedded assets are compressed by the same rules.
+#[cfg(feature = "embed-assets")]
+#[allow(dead_code)]
+#[path = "src/content_type.rs"]
+mod content_type;
+
 fn main() {
   // trigger recompilation when a new migration is added
   println!("cargo:rerun-if-changed=migrations");
   println!("cargo:rerun-if-changed=queries/*.sql");
   println!("cargo:rerun-if-changed=assets/");
+  println!("cargo:rerun-if-changed=public/");
+  println!("cargo:rerun-if-changed=src/content_type.rs");
 
   // Command::new("sqlc")
   //   .args(["generate"])
@@ -31,10 +39,12 @@ fn main() {
       "build",
       "--minify",
       "--outdir=target/frontend/js",
+      // `AssetCache` puts a content hash in each URL itself, so files keep
+      // their logical names.
       "--entry-naming",
-      "[name].[hash].[ext]",
+      "[name].[ext]",
       "--asset-naming",
-      "[name].[hash].[ext]",
+      "[name].[ext]",
       "assets/js/main.ts",
     ])
     .status()
@@ -42,6 +52,111 @@ fn main() {
 
   // std::fs::remove_file("target/frontend/css/main.css").unwrap_or_default();
   copy_files("public", "target/frontend");
+
+  #[cfg(feature = "embed-assets")]
+  embed_assets("target/frontend");
+}
+
+/// Generates `$OUT_DIR/embedded_assets.rs`, a slice of every file under `dir`
+/// for `AssetCache::load_embedded`. Gzip and Brotli variants are compressed
+/// here so the release binary starts without compressing anything.
+#[cfg(feature = "embed-assets")]
+fn embed_assets(dir: &str) {
+  use std::{io::Write, path::Path, time::UNIX_EPOCH};
+
+  fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("failed to read frontend dir") {
+      let path = entry.expect("failed to read entry").path();
+      if path.is_dir() {
+        list_files(&path, files);
+      } else {
+        files.push(path);
+      }
+    }
+  }
+
+  fn compress(filename: &str, bytes: &[u8]) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    let mime = content_type::detect(filename, bytes);
+    if !content_type::should_compress(&mime, bytes.len()) {
+      return (None, None);
+    }
+
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    gzip.write_all(bytes).expect("failed to gzip asset");
+    let gzip = gzip.finish().expect("failed to gzip asset");
+
+    let mut brotli = Vec::new();
+    let params = brotli::enc::BrotliEncoderParams {
+      quality: 11,
+      ..Default::default()
+    };
+    brotli::BrotliCompress(&mut &bytes[..], &mut brotli, &params)
+      .expect("failed to brotli-compress asset");
+
+    let smaller = |compressed: Vec<u8>| Some(compressed).filter(|c| c.len() < bytes.len());
+    (smaller(gzip), smaller(brotli))
+  }
+
+  let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+  let root = fs::canonicalize(dir).expect("frontend build output is missing");
+  let mut files = Vec::new();
+  list_files(&root, &mut files);
+  files.sort();
+
+  let mut entries = String::new();
+  for path in files {
+    // `AssetCache` skips dotfiles such as `.DS_Store`.
+    if path
+      .file_name()
+      .and_then(|name| name.to_str())
+      .is_none_or(|name| name.starts_with('.'))
+    {
+      continue;
+    }
+    let filename = path
+      .strip_prefix(&root)
+      .unwrap()
+      .to_str()
+      .expect("asset paths must be UTF-8")
+      .replace('\\', "/");
+    let bytes = fs::read(&path).expect("failed to read asset");
+    let modified = fs::metadata(&path)
+      .and_then(|meta| meta.modified())
+      .expect("failed to read asset mtime")
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs();
+
+    let (gzip, brotli) = compress(&filename, &bytes);
+    let variant = |suffix: &str, data: Option<Vec<u8>>| match data {
+      Some(data) => {
+        let variant_path = out_dir
+          .join("embedded")
+          .join(format!("{filename}.{suffix}"));
+        fs::create_dir_all(variant_path.parent().unwrap()).unwrap();
+        fs::write(&variant_path, data).expect("failed to write compressed asset");
+        format!("Some(include_bytes!({variant_path:?}))")
+      }
+      None => "None".to_string(),
+    };
+
+    // The braces go in on their own, as doubled ones would read as a
+    // placeholder when cargo-generate renders this file.
+    entries.push_str("  EmbeddedAsset ");
+    entries.push('{');
+    entries.push_str(&format!(
+      " filename: {filename:?}, modified: {modified}, identity: include_bytes!({path:?}), gzip: {}, brotli: {} ",
+      variant("gz", gzip),
+      variant("br", brotli),
+    ));
+    entries.push_str("},\n");
+  }
+
+  fs::write(
+    out_dir.join("embedded_assets.rs"),
+    format!("&[\n{entries}]\n"),
+  )
+  .expect("failed to write embedded_assets.rs");
 }
 
 fn copy_files<S: Into<PathBuf>, D: Into<PathBuf>>(src: S, dst: D) {
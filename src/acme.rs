@@ -0,0 +1,492 @@
+//! ACME certificates shared between replicas through Postgres.
+//!
+//! [`PostgresAcmeCache`] keeps `rustls_acme`'s accounts and certificates in a
+//! table, so replicas serve the same certificate and restarts don't order new
+//! ones. [`PostgresAcme`] runs on every replica: it deploys whatever the table
+//! holds and, once that is due for renewal, the replica winning a Postgres
+//! advisory lock orders the next certificate while the others wait for it to
+//! show up.
+//!
+//! Certificates are validated with TLS-ALPN-01, which the ordering replica
+//! answers itself. A load balancer in front of several replicas therefore has
+//! to pass TLS through untouched; validations that land on another replica
+//! fail and the order is retried.
+
+use std::{
+  convert::Infallible,
+  path::Path,
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL};
+use deadpool_postgres::Pool;
+use futures::StreamExt as _;
+use jiff::Timestamp;
+use rustls::{
+  ClientConfig, RootCertStore,
+  pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject as _},
+  server::{ClientHello, ResolvesServerCert},
+  sign::CertifiedKey,
+};
+use rustls_acme::{
+  AccountCache, AcmeConfig, CertCache, EventError, EventOk, ResolvesServerCertAcme,
+  acme::{LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY},
+};
+use sha2::{Digest, Sha256};
+use tokio_postgres::error::SqlState;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+  config,
+  leader::{LeaderError, Leadership},
+  tokio_postgres_sessions::is_valid_identifier,
+};
+
+/// How often a replica looks for a certificate another one renewed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How often a replica checks back while another one is placing an order.
+const ORDER_WAIT_INTERVAL: Duration = Duration::from_secs(15);
+/// How often the replica placing an order checks it still holds the lock.
+const ORDER_LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An error type for the Postgres ACME cache.
+#[derive(thiserror::Error, Debug)]
+pub enum AcmeCacheError {
+  /// A variant to map Postgres driver errors.
+  #[error(transparent)]
+  Postgres(#[from] tokio_postgres::Error),
+
+  /// A variant to map pool acquisition errors.
+  #[error(transparent)]
+  Pool(#[from] deadpool_postgres::PoolError),
+
+  /// A variant to map advisory lock errors.
+  #[error(transparent)]
+  Lock(#[from] LeaderError),
+}
+
+/// What `rustls_acme` needs to place orders, from `[server]` and `[acme]`.
+#[derive(Clone)]
+pub struct AcmeSettings {
+  domains: Vec<String>,
+  contact: Vec<String>,
+  directory_url: String,
+  client_config: Option<Arc<ClientConfig>>,
+}
+
+impl AcmeSettings {
+  pub fn from_config(server: &config::Server, acme: &config::Acme) -> eyre::Result<Self> {
+    let directory_url = match &acme.directory {
+      Some(url) => url.clone(),
+      None if server.production => LETS_ENCRYPT_PRODUCTION_DIRECTORY.to_string(),
+      None => LETS_ENCRYPT_STAGING_DIRECTORY.to_string(),
+    };
+    let client_config = acme.ca_cert.as_deref().map(client_config).transpose()?;
+
+    Ok(Self {
+      domains: server.domains.clone(),
+      contact: server.email.iter().map(|e| format!("mailto:{e}")).collect(),
+      directory_url,
+      client_config,
+    })
+  }
+
+  /// A fresh `rustls_acme` config, without a cache.
+  pub fn config(&self) -> AcmeConfig<Infallible> {
+    let config = AcmeConfig::new(&self.domains)
+      .contact(&self.contact)
+      .directory(&self.directory_url);
+    match &self.client_config {
+      Some(client_config) => config.client_tls_config(client_config.clone()),
+      None => config,
+    }
+  }
+}
+
+/// A client config trusting only the CA certificates in `path`.
+fn client_config(path: &Path) -> eyre::Result<Arc<ClientConfig>> {
+  let mut roots = RootCertStore::empty();
+  for cert in CertificateDer::pem_file_iter(path)? {
+    roots.add(cert?)?;
+  }
+  let config = ClientConfig::builder()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+  Ok(Arc::new(config))
+}
+
+/// A `rustls_acme` cache storing accounts and certificates in Postgres.
+#[derive(Clone, Debug)]
+pub struct PostgresAcmeCache {
+  pool: Pool,
+  schema_name: String,
+  table_name: String,
+}
+
+impl PostgresAcmeCache {
+  /// Create a new cache with the provided connection pool.
+  pub fn new(pool: Pool) -> Self {
+    Self {
+      pool,
+      schema_name: "acme".to_string(),
+      table_name: "cache".to_string(),
+    }
+  }
+
+  /// Set the cache table schema name with the provided name.
+  pub fn with_schema_name(mut self, schema_name: impl AsRef<str>) -> Result<Self, String> {
+    let schema_name = schema_name.as_ref();
+    if !is_valid_identifier(schema_name) {
+      return Err(format!("Invalid schema name '{schema_name}'"));
+    }
+
+    schema_name.clone_into(&mut self.schema_name);
+    Ok(self)
+  }
+
+  /// Set the cache table name with the provided name.
+  pub fn with_table_name(mut self, table_name: impl AsRef<str>) -> Result<Self, String> {
+    let table_name = table_name.as_ref();
+    if !is_valid_identifier(table_name) {
+      return Err(format!("Invalid table name '{table_name}'"));
+    }
+
+    table_name.clone_into(&mut self.table_name);
+    Ok(self)
+  }
+
+  /// Migrate the cache schema.
+  pub async fn migrate(&self) -> Result<(), AcmeCacheError> {
+    let mut client = self.pool.get().await?;
+    let tx = client.transaction().await?;
+
+    let create_schema_query = format!(
+      r#"create schema if not exists "{schema_name}""#,
+      schema_name = self.schema_name,
+    );
+
+    if let Err(err) = tx.batch_execute(&create_schema_query).await {
+      let duplicate = matches!(
+        err.code(),
+        Some(code) if code == &SqlState::DUPLICATE_SCHEMA || code == &SqlState::UNIQUE_VIOLATION
+      );
+
+      if !duplicate {
+        return Err(err.into());
+      }
+    }
+
+    let create_table_query = format!(
+      r#"
+            create table if not exists "{schema_name}"."{table_name}"
+            (
+                kind text not null,
+                key text not null,
+                contents bytea not null,
+                updated_at timestamptz not null default now(),
+                primary key (kind, key)
+            )
+            "#,
+      schema_name = self.schema_name,
+      table_name = self.table_name
+    );
+    tx.batch_execute(&create_table_query).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+  }
+
+  /// Takes the lock for ordering a certificate for `domains`, unless another
+  /// replica holds it.
+  pub async fn try_lock_order(
+    &self,
+    domains: &[String],
+    directory_url: &str,
+  ) -> Result<Option<Leadership>, AcmeCacheError> {
+    let key = format!(
+      "{}.{}:{}",
+      self.schema_name,
+      self.table_name,
+      cache_key(domains, directory_url)
+    );
+    Ok(Leadership::try_acquire(&self.pool, key, ORDER_LOCK_CHECK_INTERVAL).await?)
+  }
+
+  async fn load(&self, kind: &str, key: &str) -> Result<Option<Vec<u8>>, AcmeCacheError> {
+    let query = format!(
+      r#"select contents from "{schema_name}"."{table_name}" where kind = $1 and key = $2"#,
+      schema_name = self.schema_name,
+      table_name = self.table_name
+    );
+    let client = self.pool.get().await?;
+    let row = client.query_opt(query.as_str(), &[&kind, &key]).await?;
+    Ok(row.map(|row| row.get(0)))
+  }
+
+  async fn store(&self, kind: &str, key: &str, contents: &[u8]) -> Result<(), AcmeCacheError> {
+    let query = format!(
+      r#"
+            insert into "{schema_name}"."{table_name}" (kind, key, contents)
+            values ($1, $2, $3)
+            on conflict (kind, key) do update
+            set contents = excluded.contents, updated_at = now()
+            "#,
+      schema_name = self.schema_name,
+      table_name = self.table_name
+    );
+    let client = self.pool.get().await?;
+    client
+      .execute(query.as_str(), &[&kind, &key, &contents])
+      .await?;
+    Ok(())
+  }
+}
+
+/// Names an account or certificate by its contacts or domains and directory,
+/// the way `rustls_acme`'s `DirCache` names its files.
+fn cache_key(items: &[String], directory_url: &str) -> String {
+  let mut hasher = Sha256::new();
+  for item in items {
+    hasher.update(item.as_bytes());
+    hasher.update([0]);
+  }
+  hasher.update(directory_url.as_bytes());
+  BASE64_URL.encode(hasher.finalize())
+}
+
+#[async_trait]
+impl CertCache for PostgresAcmeCache {
+  type EC = AcmeCacheError;
+
+  async fn load_cert(
+    &self,
+    domains: &[String],
+    directory_url: &str,
+  ) -> Result<Option<Vec<u8>>, Self::EC> {
+    self.load("cert", &cache_key(domains, directory_url)).await
+  }
+
+  async fn store_cert(
+    &self,
+    domains: &[String],
+    directory_url: &str,
+    cert: &[u8],
+  ) -> Result<(), Self::EC> {
+    self
+      .store("cert", &cache_key(domains, directory_url), cert)
+      .await
+  }
+}
+
+#[async_trait]
+impl AccountCache for PostgresAcmeCache {
+  type EA = AcmeCacheError;
+
+  async fn load_account(
+    &self,
+    contact: &[String],
+    directory_url: &str,
+  ) -> Result<Option<Vec<u8>>, Self::EA> {
+    self
+      .load("account", &cache_key(contact, directory_url))
+      .await
+  }
+
+  async fn store_account(
+    &self,
+    contact: &[String],
+    directory_url: &str,
+    account: &[u8],
+  ) -> Result<(), Self::EA> {
+    self
+      .store("account", &cache_key(contact, directory_url), account)
+      .await
+  }
+}
+
+/// Serves the certificate from Postgres, and TLS-ALPN-01 challenges while
+/// this replica is placing an order.
+#[derive(Debug, Default)]
+pub struct SharedCertResolver {
+  cert: ArcSwapOption<CertifiedKey>,
+  challenges: ArcSwapOption<ResolvesServerCertAcme>,
+}
+
+impl ResolvesServerCert for SharedCertResolver {
+  fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    if rustls_acme::is_tls_alpn_challenge(&client_hello) {
+      return self.challenges.load_full()?.resolve(client_hello);
+    }
+    self.cert.load_full()
+  }
+}
+
+/// Parses a certificate as `rustls_acme` stores it, a PKCS#8 key followed by
+/// the chain, and works out when to renew it: with a third of its lifetime
+/// left, like `rustls_acme` does.
+fn parse_cert(pem: &[u8]) -> Result<(CertifiedKey, Timestamp), String> {
+  let key = PrivateKeyDer::from_pem_slice(pem).map_err(|e| format!("private key: {e}"))?;
+  let chain = CertificateDer::pem_slice_iter(pem)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| format!("certificate chain: {e}"))?;
+  let leaf = chain.first().ok_or("no certificates")?;
+
+  let (_, parsed) =
+    x509_parser::parse_x509_certificate(leaf).map_err(|e| format!("certificate: {e}"))?;
+  let validity = parsed.validity();
+  let not_before = validity.not_before.timestamp();
+  let not_after = validity.not_after.timestamp();
+  let renew_at = Timestamp::from_second(not_after - (not_after - not_before) / 3)
+    .map_err(|e| format!("validity: {e}"))?;
+
+  let key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)
+    .map_err(|e| format!("private key: {e}"))?;
+  Ok((CertifiedKey::new(chain, key), renew_at))
+}
+
+/// Keeps this replica's certificate current, see the module docs.
+pub struct PostgresAcme {
+  settings: AcmeSettings,
+  cache: PostgresAcmeCache,
+  resolver: Arc<SharedCertResolver>,
+}
+
+impl PostgresAcme {
+  pub fn new(settings: AcmeSettings, cache: PostgresAcmeCache) -> Self {
+    Self {
+      settings,
+      cache,
+      resolver: Arc::default(),
+    }
+  }
+
+  /// The resolver for the TLS config.
+  pub fn resolver(&self) -> Arc<SharedCertResolver> {
+    self.resolver.clone()
+  }
+
+  /// Deploys and renews certificates until the task is dropped.
+  pub async fn run(self) {
+    let mut failures = 0;
+    loop {
+      let wait = match self.refresh().await {
+        Ok(wait) => {
+          failures = 0;
+          wait
+        }
+        Err(e) => {
+          error!("ACME certificate refresh failed: {e:#}");
+          failures += 1;
+          ORDER_WAIT_INTERVAL * (1 << failures.min(6))
+        }
+      };
+      tokio::time::sleep(wait).await;
+    }
+  }
+
+  /// Deploys the stored certificate, ordering a new one if it's due and no
+  /// other replica is, and says when to look again.
+  async fn refresh(&self) -> eyre::Result<Duration> {
+    if let Some(wait) = until(self.deploy_stored().await?) {
+      return Ok(wait.min(RELOAD_INTERVAL));
+    }
+
+    let Some(lock) = self
+      .cache
+      .try_lock_order(&self.settings.domains, &self.settings.directory_url)
+      .await?
+    else {
+      debug!("another replica is ordering a certificate");
+      return Ok(ORDER_WAIT_INTERVAL);
+    };
+    // Another replica may have finished its order since the first look.
+    let result = match until(self.deploy_stored().await?) {
+      Some(_) => Ok(()),
+      // Without the lock another replica may be ordering too, and their
+      // challenges would clash.
+      None => tokio::select! {
+        result = self.order() => result,
+        () = lock.lost() => Err(eyre::eyre!("lost the order lock")),
+      },
+    };
+    self.resolver.challenges.store(None);
+    if !lock.is_lost() {
+      lock.release().await?;
+    }
+    result.map(|()| Duration::ZERO)
+  }
+
+  /// Deploys the stored certificate, returning when it is due for renewal.
+  async fn deploy_stored(&self) -> eyre::Result<Option<Timestamp>> {
+    let stored = self
+      .cache
+      .load_cert(&self.settings.domains, &self.settings.directory_url)
+      .await?;
+    let Some(pem) = stored else {
+      return Ok(None);
+    };
+    match parse_cert(&pem) {
+      Ok((cert, renew_at)) => {
+        self.resolver.cert.store(Some(Arc::new(cert)));
+        Ok(Some(renew_at))
+      }
+      Err(e) => {
+        warn!("ignoring the stored ACME certificate: {e}");
+        Ok(None)
+      }
+    }
+  }
+
+  /// Orders a certificate and stores it.
+  async fn order(&self) -> eyre::Result<()> {
+    info!(domains = ?self.settings.domains, "ordering an ACME certificate");
+    let mut state = self.settings.config().cache(self.cache.clone()).state();
+    self.resolver.challenges.store(Some(state.resolver()));
+
+    let result = loop {
+      match state.next().await.expect("ACME events never end") {
+        Ok(EventOk::CertCacheStore) => break Ok(()),
+        Ok(event) => debug!("ACME event: {event:?}"),
+        Err(EventError::Order(e)) => break Err(eyre::eyre!("order failed: {e}")),
+        Err(EventError::NewCertParse(e)) => break Err(eyre::eyre!("bad certificate: {e}")),
+        Err(EventError::CertCacheStore(e)) => break Err(e.into()),
+        Err(e) => warn!("ACME error: {e:?}"),
+      }
+    };
+
+    self.resolver.challenges.store(None);
+    result
+  }
+}
+
+/// How long until `at`, or `None` once it has passed.
+fn until(at: Option<Timestamp>) -> Option<Duration> {
+  let at = SystemTime::from(at?);
+  at.duration_since(SystemTime::now()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn stored_certs_renew_with_a_third_of_their_lifetime_left() {
+    let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let mut params = rcgen::CertificateParams::new(vec!["example.com".into()]).unwrap();
+    params.not_before = rcgen::date_time_ymd(2026, 1, 1);
+    params.not_after = rcgen::date_time_ymd(2026, 4, 1);
+    let cert = params.self_signed(&key).unwrap();
+
+    let pem = format!("{}\n{}", key.serialize_pem(), cert.pem());
+    let (certified, renew_at) = parse_cert(pem.as_bytes()).unwrap();
+    assert_eq!(certified.cert.len(), 1);
+    assert_eq!(renew_at, "2026-03-02T00:00:00Z".parse().unwrap());
+
+    assert!(parse_cert(cert.pem().as_bytes()).is_err());
+    assert!(parse_cert(key.serialize_pem().as_bytes()).is_err());
+  }
+}
@@ -1,10 +1,12 @@
+use arc_swap::ArcSwap;
 use deadpool_postgres::Pool;
 use http::StatusCode;
 
 use crate::{
-  assets::{AssetCache, SharedAssetCache},
+  assets::{self, AssetCache, SharedAssetCache},
   config::AppConfig,
   error::AppError,
+  jobs::JobQueue,
   pgdb,
 };
 
@@ -16,20 +18,51 @@ fn leak_alloc<G>(value: G) -> &'static G {
 pub struct AppState {
   pgdb: Pool,
   assets: SharedAssetCache,
+  jobs: JobQueue,
 }
 
 impl AppState {
   pub async fn new(config: &AppConfig) -> eyre::Result<Self> {
     let pgdb = pgdb::create_pg_pool(&config.postgres.url)?;
-    let assets = leak_alloc(AssetCache::load_files(None, &[]).await);
+    let jobs = JobQueue::from_config(&config.jobs, pgdb.clone()).map_err(|e| eyre::eyre!(e))?;
+    #[cfg(feature = "embed-assets")]
+    let cache = AssetCache::load_embedded();
+    #[cfg(not(feature = "embed-assets"))]
+    let cache = {
+      // Development serves `public/` straight from the source tree so edits
+      // show up without a rebuild.
+      #[cfg(debug_assertions)]
+      let extra_dirs = [std::path::Path::new(assets::PUBLIC_DIR)];
+      #[cfg(not(debug_assertions))]
+      let extra_dirs: [&std::path::Path; 0] = [];
 
-    Ok(Self { pgdb, assets })
+      AssetCache::load_files(None, &extra_dirs).await
+    };
+    let cache = cache.with_content_types(config.assets.content_types());
+    let assets = leak_alloc(ArcSwap::from_pointee(cache));
+    assets::set_global_cache(assets);
+
+    Ok(Self { pgdb, assets, jobs })
+  }
+
+  /// A state serving `cache`, for handler tests. Its pool never connects.
+  #[cfg(test)]
+  pub(crate) fn with_assets(cache: AssetCache) -> Self {
+    let pgdb = pgdb::create_pg_pool("postgres://localhost/unused").unwrap();
+    let jobs = JobQueue::new(pgdb.clone());
+    let assets = leak_alloc(ArcSwap::from_pointee(cache));
+    Self { pgdb, assets, jobs }
   }
 
   pub fn assets(&self) -> SharedAssetCache {
     self.assets
   }
 
+  /// Where background jobs are enqueued.
+  pub fn jobs(&self) -> &JobQueue {
+    &self.jobs
+  }
+
   pub fn pgdb(&self) -> Pool {
     self.pgdb.clone()
   }
@@ -1,87 +1,231 @@
-use std::collections::BTreeMap;
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{Arc, OnceLock},
+  time::SystemTime,
+};
 
-use async_compression::tokio::write::BrotliEncoder;
-use axum::extract::Path;
+use arc_swap::ArcSwap;
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use bytes::Bytes;
 use futures::StreamExt;
+use headers::ETag;
+use http::HeaderValue;
+use mime_guess::Mime;
+use sha2::Sha384;
+use sha3::{Digest, Sha3_256};
 use tokio::io::AsyncWriteExt;
 use walkdir::WalkDir;
 
-/// A shared reference to the static asset cache.
-pub type SharedAssetCache = &'static AssetCache;
+use crate::content_type;
+
+/// A shared reference to the static asset cache. The cache is swapped out
+/// wholesale when assets change on disk during development.
+pub type SharedAssetCache = &'static ArcSwap<AssetCache>;
+
+/// A file read from an asset directory: its stored path, contents, path
+/// relative to the directory and modification time.
+type LoadedFile = (String, Vec<u8>, String, SystemTime);
+
+/// A frontend file compiled into the binary by `build.rs`, along with its
+/// precompressed variants.
+#[cfg(feature = "embed-assets")]
+struct EmbeddedAsset {
+  filename: &'static str,
+  /// Seconds since the Unix epoch.
+  modified: u64,
+  identity: &'static [u8],
+  gzip: Option<&'static [u8]>,
+  brotli: Option<&'static [u8]>,
+}
+
+#[cfg(feature = "embed-assets")]
+static EMBEDDED_ASSETS: &[EmbeddedAsset] =
+  include!(concat!(env!("OUT_DIR"), "/embedded_assets.rs"));
+
+/// The directory `build.rs` writes the frontend build to.
+pub const FRONTEND_DIR: &str = "target/frontend";
+/// The directory of static files served as-is.
+#[cfg(debug_assertions)]
+pub const PUBLIC_DIR: &str = "public";
 
 const HASH_SPLIT_CHAR: char = '.';
 
+/// The URL prefix static assets are served under.
+const STATIC_PREFIX: &str = "/static";
+
+/// The cache consulted by [`asset_url`], set once the app state is built.
+static GLOBAL_CACHE: OnceLock<SharedAssetCache> = OnceLock::new();
+
+/// Makes `cache` the one [`asset_url`] resolves names against. Only the first
+/// call has an effect.
+pub fn set_global_cache(cache: SharedAssetCache) {
+  let _ = GLOBAL_CACHE.set(cache);
+}
+
+/// Returns the content-hashed URL for a logical asset name such as
+/// `"js/main.js"`, for use in templates. Unknown names fall back to their
+/// unhashed URL so a missing asset shows up as a 404 rather than a panic.
+pub fn asset_url(name: &str) -> String {
+  GLOBAL_CACHE
+    .get()
+    .and_then(|cache| cache.load().url(name).map(str::to_string))
+    .unwrap_or_else(|| format!("{STATIC_PREFIX}/{name}"))
+}
+
+/// Returns the Subresource Integrity digest for a logical asset name, or
+/// `None` when the asset is unknown.
+pub fn asset_integrity(name: &str) -> Option<String> {
+  GLOBAL_CACHE
+    .get()
+    .and_then(|cache| cache.load().get(name).map(|asset| asset.integrity.clone()))
+}
+
+/// How a requested asset path relates to the asset's current content hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetVersion {
+  /// The path carries the current content hash and may be cached forever.
+  Current,
+  /// The path carries a hash from a different build of the asset.
+  Stale,
+  /// The path is the logical name without any hash.
+  Unversioned,
+}
+
 /// Maps static asset filenames to their compressed bytes and content type. This
 /// is used to serve static assets from the build directory without reading from
 /// disk, as the cache stays in RAM for the life of the server.
 ///
 /// This type should be accessed via the `cache` property in `AppState`.
-pub struct AssetCache(BTreeMap<String, StaticAsset>);
+pub struct AssetCache {
+  assets: BTreeMap<String, StaticAsset>,
+  /// Media types that replace the detected ones, keyed by asset name.
+  content_types: Arc<HashMap<String, Mime>>,
+}
 
 impl AssetCache {
+  fn new(assets: BTreeMap<String, StaticAsset>) -> Self {
+    Self {
+      assets,
+      content_types: Arc::default(),
+    }
+  }
+
+  /// Serves the assets named in `content_types` with the given media type
+  /// instead of the detected one, including after they are reloaded.
+  pub fn with_content_types(mut self, content_types: HashMap<String, Mime>) -> Self {
+    for (key, mime) in &content_types {
+      if let Some(asset) = self.assets.get_mut(key) {
+        asset.set_mime(mime.clone());
+      }
+    }
+    self.content_types = Arc::new(content_types);
+    self
+  }
+
   /// Attempts to return a static asset from the cache from a cache key. If
   /// the asset is not found, `None` is returned.
   pub fn get(&self, key: &str) -> Option<&StaticAsset> {
-    self.0.get(key)
+    self.assets.get(key)
   }
 
-  /// Helper method to get a static asset from an extracted request path.
-  pub fn get_from_path(&self, path: &Path<String>) -> Option<&StaticAsset> {
-    let key = Self::get_cache_key(path);
-    self.get(&key)
+  /// Returns the content-hashed URL for a logical asset name.
+  pub fn url(&self, name: &str) -> Option<&str> {
+    self.get(name).map(|asset| asset.url.as_str())
   }
 
-  fn get_cache_key(path: &str) -> String {
-    // Remove leading "static/" if present
-    let path = path.strip_prefix("static/").unwrap_or(path);
-
-    // Split the path into directory and filename parts
-    let mut parts = path.rsplitn(2, '/');
-    let filename = parts.next().unwrap_or(path);
-    let directory = parts.next().unwrap_or("");
-
-    // Process the filename to get basename and extension
-    let mut file_parts = filename.split(['.', HASH_SPLIT_CHAR]);
-    let basename = file_parts.next().unwrap_or_default();
-    let ext = file_parts.next_back().unwrap_or_default();
+  /// Resolves a request path to its asset, along with whether the path names
+  /// the asset's current content hash. Only a segment shaped like a content
+  /// hash is taken for one, so any other name has to match an asset exactly.
+  pub fn resolve(&self, path: &str) -> Option<(&StaticAsset, AssetVersion)> {
+    let key = Self::get_cache_key(path);
+    if let Some(asset) = self.get(&key) {
+      return Some((asset, AssetVersion::Unversioned));
+    }
 
-    if directory.is_empty() {
-      format!("{}.{}", basename, ext)
+    let (key, hash) = split_content_hash(&key)?;
+    let asset = self.get(&key)?;
+    let version = if hash == asset.hash {
+      AssetVersion::Current
     } else {
-      format!("{}/{}.{}", directory, basename, ext)
-    }
+      AssetVersion::Stale
+    };
+    Some((asset, version))
+  }
+
+  /// Assets are keyed by their path under the asset directory, e.g.
+  /// `js/main.js`.
+  fn get_cache_key(path: &str) -> String {
+    path.strip_prefix("static/").unwrap_or(path).to_string()
   }
 
-  async fn load_directory(directory: &std::path::Path) -> Vec<(String, Vec<u8>, String, String)> {
+  #[cfg_attr(feature = "embed-assets", allow(dead_code))]
+  async fn load_directory(directory: &std::path::Path) -> Vec<LoadedFile> {
     WalkDir::new(directory)
       .follow_links(true)
       .into_iter()
       .filter_map(Result::ok)
       .filter(|entry| entry.file_type().is_file())
-      .filter_map(|entry| {
-        let path = entry.path();
-        let relative_path = path.strip_prefix(directory).ok()?;
-        let filename = relative_path.to_str()?;
-        let ext = path.extension()?.to_str()?;
+      .filter_map(|entry| Self::read_file(directory, entry.path()))
+      .collect()
+  }
 
-        let stored_path = format!("static/{}", filename);
+  fn read_file(directory: &std::path::Path, path: &std::path::Path) -> Option<LoadedFile> {
+    let relative_path = path.strip_prefix(directory).ok()?;
+    let filename = relative_path.to_str()?;
+    // Skip dotfiles such as `.DS_Store`; `build.rs` does the same.
+    if path.file_name()?.to_str()?.starts_with('.') {
+      return None;
+    }
 
-        std::fs::read(path)
-          .ok()
-          .map(|bytes| (stored_path, bytes, ext.to_string(), filename.to_string()))
-      })
-      .collect()
+    let stored_path = format!("static/{}", filename);
+
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+
+    std::fs::read(path)
+      .ok()
+      .map(|bytes| (stored_path, bytes, filename.to_string(), modified))
+  }
+
+  async fn build_asset(
+    &self,
+    (stored_path, bytes, filename, modified): LoadedFile,
+  ) -> (String, StaticAsset) {
+    let key = Self::get_cache_key(&filename);
+
+    // Compression follows the detected type, as it does for embedded assets.
+    let detected = content_type::detect(&filename, &bytes);
+    let (gzip, brotli) = if content_type::should_compress(&detected, bytes.len()) {
+      (
+        compress_smaller(Encoding::Gzip, &bytes).await,
+        compress_smaller(Encoding::Brotli, &bytes).await,
+      )
+    } else {
+      (None, None)
+    };
+    let mime = self.content_types.get(&key).cloned().unwrap_or(detected);
+
+    let asset = StaticAsset::new(
+      &key,
+      stored_path,
+      modified,
+      mime,
+      bytes.into(),
+      gzip,
+      brotli,
+    );
+    (key, asset)
   }
 
+  #[cfg_attr(feature = "embed-assets", allow(dead_code))]
   pub async fn load_files(
     base_path: Option<&std::path::Path>,
     extra_dirs: &[&std::path::Path],
   ) -> Self {
-    let mut cache = BTreeMap::default();
+    let mut cache = Self::new(BTreeMap::default());
     let base_path = base_path
       .as_ref()
-      .map_or(std::path::Path::new("target/frontend"), |p| p);
+      .map_or(std::path::Path::new(FRONTEND_DIR), |p| p);
 
     let assets: Vec<_> = Self::load_directory(base_path)
       .await
@@ -94,63 +238,606 @@ impl AssetCache {
       )
       .collect();
 
-    for (stored_path, bytes, ext, filename) in assets {
-      let contents = match ext.as_str() {
-        "css" | "js" => compress_data(&bytes).await.unwrap_or(Bytes::from(bytes)),
-        _ => bytes.into(),
-      };
+    for file in assets {
+      let (key, asset) = cache.build_asset(file).await;
+      cache.assets.insert(key, asset);
+    }
 
-      cache.insert(
-        Self::get_cache_key(&filename),
-        StaticAsset {
-          path: stored_path,
-          contents,
-        },
-      );
+    tracing::debug!("loaded {} assets", cache.assets.len());
+    for (key, asset) in &cache.assets {
+      tracing::debug!("{} -> {} ({}, {})", key, asset.path, asset.url, asset.mime);
     }
 
-    tracing::debug!("loaded {} assets", cache.len());
-    for (key, asset) in &cache {
-      tracing::debug!("{} -> {}", key, asset.path);
+    cache
+  }
+
+  /// Builds the cache from the frontend files embedded into the binary at
+  /// compile time.
+  #[cfg(feature = "embed-assets")]
+  pub fn load_embedded() -> Self {
+    let cache: BTreeMap<_, _> = EMBEDDED_ASSETS
+      .iter()
+      .map(|embedded| {
+        let key = Self::get_cache_key(embedded.filename);
+        let asset = StaticAsset::new(
+          &key,
+          format!("static/{}", embedded.filename),
+          SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(embedded.modified),
+          content_type::detect(embedded.filename, embedded.identity),
+          Bytes::from_static(embedded.identity),
+          embedded.gzip.map(Bytes::from_static),
+          embedded.brotli.map(Bytes::from_static),
+        );
+        (key, asset)
+      })
+      .collect();
+
+    tracing::debug!("loaded {} embedded assets", cache.len());
+    Self::new(cache)
+  }
+
+  /// Returns a copy of the cache with the entries for `changed` files
+  /// refreshed from disk. `roots` are the directories the cache was loaded
+  /// from, in the same order as for [`AssetCache::load_files`], so a file
+  /// present in several roots resolves to the same copy it did at load.
+  #[cfg(debug_assertions)]
+  pub async fn reload_paths(
+    &self,
+    roots: &[&std::path::Path],
+    changed: &[std::path::PathBuf],
+  ) -> Self {
+    let mut cache = Self {
+      assets: self.assets.clone(),
+      content_types: self.content_types.clone(),
+    };
+
+    for path in changed {
+      let Some(relative_path) = roots.iter().find_map(|root| path.strip_prefix(root).ok()) else {
+        continue;
+      };
+
+      let winner = roots
+        .iter()
+        .rev()
+        .find_map(|root| Self::read_file(root, &root.join(relative_path)));
+
+      match winner {
+        Some(file) => {
+          let (key, asset) = cache.build_asset(file).await;
+          tracing::debug!("reloaded {} -> {} ({})", key, asset.path, asset.url);
+          cache.assets.insert(key, asset);
+        }
+        None => {
+          let Some(filename) = relative_path.to_str() else {
+            continue;
+          };
+          let key = Self::get_cache_key(filename);
+          if cache.assets.remove(&key).is_some() {
+            tracing::debug!("removed {}", key);
+          }
+        }
+      }
     }
 
-    Self(cache)
+    cache
   }
 
   // /// Returns an iterator over the static assets in the cache.
   // pub fn values(&self) -> impl Iterator<Item = &StaticAsset> {
-  //   self.0.values()
+  //   self.assets.values()
   // }
 
   // /// Returns an iterator over the static asset cache keys.
   // pub fn keys(&self) -> impl Iterator<Item = &String> {
-  //   self.0.keys()
+  //   self.assets.keys()
   // }
 }
 
-/// Represents a single static asset from the build directory. Assets are
-/// represented as pre-compressed bytes via Brotli and their original content
-/// type so the set_content_type middleware service can set the correct
-/// Content-Type header.
+/// Watches `roots` and swaps refreshed entries into `cache` whenever files
+/// under them change, calling `on_reload` only once the new content is being
+/// served. Watching stops when the returned watcher is dropped.
+#[cfg(debug_assertions)]
+pub fn watch(
+  cache: SharedAssetCache,
+  roots: &[&std::path::Path],
+  on_reload: impl Fn() + Send + 'static,
+) -> notify::Result<notify::RecommendedWatcher> {
+  use notify::{EventKind, RecursiveMode, Watcher};
+  use std::{path::PathBuf, sync::Arc, time::Duration};
+
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Vec<PathBuf>>();
+  let mut watcher =
+    notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+      Ok(event)
+        if matches!(
+          event.kind,
+          EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) =>
+      {
+        let _ = tx.send(event.paths);
+      }
+      Ok(_) => {}
+      Err(e) => tracing::warn!("asset watcher error: {e}"),
+    })?;
+
+  // Events report canonical paths, so match them against canonical roots.
+  let roots: Vec<PathBuf> = roots
+    .iter()
+    .filter_map(|root| root.canonicalize().ok())
+    .collect();
+  for root in &roots {
+    watcher.watch(root, RecursiveMode::Recursive)?;
+  }
+
+  tokio::spawn(async move {
+    while let Some(mut changed) = rx.recv().await {
+      // Bundlers and editors emit bursts of events per save, so settle first.
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      while let Ok(more) = rx.try_recv() {
+        changed.extend(more);
+      }
+      changed.sort();
+      changed.dedup();
+
+      let roots: Vec<&std::path::Path> = roots.iter().map(PathBuf::as_path).collect();
+      let reloaded = cache.load_full().reload_paths(&roots, &changed).await;
+      cache.store(Arc::new(reloaded));
+      on_reload();
+    }
+  });
+
+  Ok(watcher)
+}
+
+/// Represents a single static asset from the build directory. Assets keep
+/// their original bytes alongside gzip and Brotli variants precompressed at
+/// load time, so the handler can serve whichever the client accepts.
+#[derive(Clone)]
 pub struct StaticAsset {
   pub path: String,
-  pub contents: Bytes,
+  /// A hash of the uncompressed contents, embedded in the asset's URL.
+  pub hash: String,
+  /// The content-hashed URL the asset is linked under.
+  pub url: String,
+  /// The Subresource Integrity digest of the uncompressed contents, e.g.
+  /// `sha384-<base64>`.
+  pub integrity: String,
+  /// When the source file was last modified.
+  pub modified: SystemTime,
+  /// The media type, without parameters.
+  pub mime: Mime,
+  /// The `Content-Type` header value: `mime`, plus a UTF-8 charset for text.
+  pub content_type: HeaderValue,
+  /// The uncompressed contents.
+  pub identity: Bytes,
+  /// The gzip variant, when compression made the asset smaller.
+  pub gzip: Option<Bytes>,
+  /// The Brotli variant, when compression made the asset smaller.
+  pub brotli: Option<Bytes>,
 }
 
 impl StaticAsset {
-  /// Returns the content type of the asset based on its file extension.
-  pub fn ext(&self) -> Option<&str> {
-    let parts: Vec<&str> = self.path.split('.').collect();
+  /// Builds the asset stored under cache key `key`, hashing its contents and
+  /// precomputing its `Content-Type`.
+  fn new(
+    key: &str,
+    path: String,
+    modified: SystemTime,
+    mime: Mime,
+    identity: Bytes,
+    gzip: Option<Bytes>,
+    brotli: Option<Bytes>,
+  ) -> Self {
+    let hash = content_hash(&identity);
+    let url = hashed_url(key, &hash);
+    let integrity = integrity_digest(&identity);
+    let content_type = content_type_header(&mime, &identity);
 
-    parts.last().copied()
+    Self {
+      path,
+      hash,
+      url,
+      integrity,
+      modified,
+      mime,
+      content_type,
+      identity,
+      gzip,
+      brotli,
+    }
+  }
+
+  /// Replaces the media type, recomputing the `Content-Type`.
+  fn set_mime(&mut self, mime: Mime) {
+    self.content_type = content_type_header(&mime, &self.identity);
+    self.mime = mime;
+  }
+
+  /// Returns the stored bytes for `encoding`, if that variant exists.
+  pub fn variant(&self, encoding: Encoding) -> Option<&Bytes> {
+    match encoding {
+      Encoding::Identity => Some(&self.identity),
+      Encoding::Gzip => self.gzip.as_ref(),
+      Encoding::Brotli => self.brotli.as_ref(),
+    }
+  }
+
+  /// Returns the strong entity tag of the `encoding` variant. Each variant is
+  /// a distinct representation, so the encoding is part of the tag.
+  pub fn etag(&self, encoding: Encoding) -> ETag {
+    let tag = match encoding {
+      Encoding::Identity => format!("\"{}\"", self.hash),
+      _ => format!("\"{}-{}\"", self.hash, encoding.as_str()),
+    };
+    tag.parse().expect("hex hashes are valid entity tags")
+  }
+
+  /// Picks the variant to send for a request's `Accept-Encoding` header.
+  pub fn negotiate(&self, accept_encoding: Option<&str>) -> (Encoding, &Bytes) {
+    Encoding::negotiate(accept_encoding, |encoding| self.variant(encoding).is_some())
+      .and_then(|encoding| Some((encoding, self.variant(encoding)?)))
+      .unwrap_or((Encoding::Identity, &self.identity))
   }
 }
 
-async fn compress_data(bytes: &[u8]) -> Result<Bytes, std::io::Error> {
-  let mut encoder = BrotliEncoder::with_quality(Vec::new(), async_compression::Level::Precise(11));
+/// Builds the `Content-Type` header value for an asset of type `mime` with
+/// contents `bytes`, adding a charset when the contents are UTF-8 text.
+fn content_type_header(mime: &Mime, bytes: &[u8]) -> HeaderValue {
+  let value = match content_type::charset(mime, bytes) {
+    Some(charset) => format!("{}; charset={charset}", mime.essence_str()),
+    None => mime.essence_str().to_string(),
+  };
+  HeaderValue::from_str(&value).expect("media types are valid header values")
+}
 
-  encoder.write_all(bytes).await?;
-  encoder.shutdown().await?;
+/// Hashes asset contents for use in URLs, using the first 16 hex characters of
+/// a SHA3-256 digest.
+fn content_hash(bytes: &[u8]) -> String {
+  let digest = Sha3_256::digest(bytes);
+  format!("{:x}", digest)[..16].to_string()
+}
+
+/// Whether a URL segment has the shape of a [`content_hash`].
+fn is_content_hash(segment: &str) -> bool {
+  segment.len() == 16
+    && segment
+      .bytes()
+      .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Computes the `integrity` attribute value for `bytes`. Browsers check it
+/// against the decoded body, so one digest covers every encoded variant.
+fn integrity_digest(bytes: &[u8]) -> String {
+  format!("sha384-{}", BASE64.encode(Sha384::digest(bytes)))
+}
+
+/// Splits a hashed path such as `js/main.<hash>.js` or `robots.<hash>` into
+/// the cache key it was built from and the hash, undoing [`hashed_url`].
+fn split_content_hash(path: &str) -> Option<(String, &str)> {
+  let (directory, filename) = match path.rsplit_once('/') {
+    Some((directory, filename)) => (Some(directory), filename),
+    None => (None, path),
+  };
+  let (rest, last) = filename.rsplit_once(HASH_SPLIT_CHAR)?;
+  let (name, hash) = match rest.rsplit_once(HASH_SPLIT_CHAR) {
+    Some((stem, hash)) => (format!("{stem}.{last}"), hash),
+    // Names without an extension carry the hash last.
+    None => (rest.to_string(), last),
+  };
+  if !is_content_hash(hash) {
+    return None;
+  }
+
+  let key = match directory {
+    Some(directory) => format!("{directory}/{name}"),
+    None => name,
+  };
+  Some((key, hash))
+}
+
+/// Builds the URL for a cache key with `hash` inserted before the extension,
+/// e.g. `js/main.js` becomes `/static/js/main.<hash>.js`.
+fn hashed_url(key: &str, hash: &str) -> String {
+  match key.rsplit_once('.') {
+    Some((stem, ext)) => format!("{STATIC_PREFIX}/{stem}{HASH_SPLIT_CHAR}{hash}.{ext}"),
+    None => format!("{STATIC_PREFIX}/{key}{HASH_SPLIT_CHAR}{hash}"),
+  }
+}
 
-  Ok(Bytes::from(encoder.into_inner()))
+/// A content coding static assets can be stored and served in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+  Identity,
+  Gzip,
+  Brotli,
+}
+
+impl Encoding {
+  /// Candidates in order of preference when the client weighs them equally.
+  const PREFERENCE: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+
+  /// The `Content-Encoding` token for this coding.
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Encoding::Identity => "identity",
+      Encoding::Gzip => "gzip",
+      Encoding::Brotli => "br",
+    }
+  }
+
+  /// Chooses the acceptable encoding with the highest q-value among those
+  /// `available`, per RFC 9110 §12.5.3. Clients that send no header get
+  /// `identity`, which stays acceptable unless explicitly refused. Returns
+  /// `None` when the client refuses every available encoding.
+  pub fn negotiate(
+    accept_encoding: Option<&str>,
+    available: impl Fn(Encoding) -> bool,
+  ) -> Option<Encoding> {
+    let Some(header) = accept_encoding else {
+      return Some(Encoding::Identity).filter(|e| available(*e));
+    };
+
+    let mut wildcard = None;
+    let mut weights: Vec<(&str, f32)> = Vec::new();
+    for item in header.split(',') {
+      let mut params = item.split(';');
+      let coding = params.next().unwrap_or_default().trim();
+      if coding.is_empty() {
+        continue;
+      }
+      let q = params
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+      if coding == "*" {
+        wildcard = Some(q);
+      } else {
+        weights.push((coding, q));
+      }
+    }
+
+    let weight = |encoding: Encoding| {
+      let explicit = weights
+        .iter()
+        .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.as_str()))
+        .map(|(_, q)| *q);
+      match (explicit, encoding) {
+        (Some(q), _) => q,
+        (None, Encoding::Identity) => wildcard.unwrap_or(1.0),
+        (None, _) => wildcard.unwrap_or(0.0),
+      }
+    };
+
+    Self::PREFERENCE
+      .into_iter()
+      .filter(|encoding| available(*encoding))
+      .map(|encoding| (encoding, weight(encoding)))
+      .filter(|(_, q)| *q > 0.0)
+      // `max_by` keeps the last maximum, so walk preferences in reverse.
+      .rev()
+      .max_by(|(_, a), (_, b)| a.total_cmp(b))
+      .map(|(encoding, _)| encoding)
+  }
+}
+
+/// Compresses `bytes` with `encoding`, keeping the result only if it is
+/// actually smaller than the input.
+async fn compress_smaller(encoding: Encoding, bytes: &[u8]) -> Option<Bytes> {
+  compress_data(encoding, bytes)
+    .await
+    .ok()
+    .filter(|compressed| compressed.len() < bytes.len())
+}
+
+async fn compress_data(encoding: Encoding, bytes: &[u8]) -> Result<Bytes, std::io::Error> {
+  match encoding {
+    Encoding::Identity => Ok(Bytes::copy_from_slice(bytes)),
+    Encoding::Gzip => {
+      let mut encoder = GzipEncoder::with_quality(Vec::new(), async_compression::Level::Best);
+      encoder.write_all(bytes).await?;
+      encoder.shutdown().await?;
+      Ok(Bytes::from(encoder.into_inner()))
+    }
+    Encoding::Brotli => {
+      let mut encoder =
+        BrotliEncoder::with_quality(Vec::new(), async_compression::Level::Precise(11));
+      encoder.write_all(bytes).await?;
+      encoder.shutdown().await?;
+      Ok(Bytes::from(encoder.into_inner()))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{env, fs, path::PathBuf};
+  use uuid::Uuid;
+
+  fn write_assets(files: &[(&str, &[u8])]) -> PathBuf {
+    let dir = env::temp_dir().join(format!("assets-test-{}", Uuid::now_v7()));
+    for (name, contents) in files {
+      let path = dir.join(name);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, contents).unwrap();
+    }
+    dir
+  }
+
+  #[tokio::test]
+  async fn urls_carry_content_hash() {
+    let dir = write_assets(&[("js/main.js", b"console.log(1)")]);
+    let cache = AssetCache::load_files(Some(&dir), &[]).await;
+
+    let asset = cache.get("js/main.js").unwrap();
+    assert_eq!(asset.hash, content_hash(b"console.log(1)"));
+    assert_eq!(
+      cache.url("js/main.js"),
+      Some(format!("/static/js/main.{}.js", asset.hash).as_str())
+    );
+    assert_eq!(cache.url("js/missing.js"), None);
+  }
+
+  #[test]
+  fn integrity_is_sha384_of_identity() {
+    // `printf abc | openssl dgst -sha384 -binary | base64`
+    assert_eq!(
+      integrity_digest(b"abc"),
+      "sha384-ywB1P0WjXou1oD1pmsZQBycsMqsO3tFjGotgWkP/W+2AhgcroefMI1i67KE0yCWn"
+    );
+  }
+
+  #[tokio::test]
+  async fn resolve_classifies_requested_version() {
+    let dir = write_assets(&[("css/main.css", b"body{}")]);
+    let cache = AssetCache::load_files(Some(&dir), &[]).await;
+    let hash = content_hash(b"body{}");
+
+    let version = |path: &str| cache.resolve(path).map(|(_, version)| version);
+    assert_eq!(
+      version(&format!("css/main.{hash}.css")),
+      Some(AssetVersion::Current)
+    );
+    assert_eq!(version("css/main.css"), Some(AssetVersion::Unversioned));
+    assert_eq!(
+      version("css/main.0123456789abcdef.css"),
+      Some(AssetVersion::Stale)
+    );
+    assert_eq!(version("css/other.css"), None);
+  }
+
+  #[tokio::test]
+  async fn dotted_names_are_assets_of_their_own() {
+    let dir = write_assets(&[("js/foo.js", b"foo"), ("js/foo.min.js", b"foo()")]);
+    let cache = AssetCache::load_files(Some(&dir), &[]).await;
+    let hash = content_hash(b"foo()");
+
+    assert_eq!(
+      cache.url("js/foo.min.js"),
+      Some(format!("/static/js/foo.min.{hash}.js").as_str())
+    );
+    assert_eq!(cache.get("js/foo.js").unwrap().identity, "foo");
+
+    let version = |path: &str| {
+      cache
+        .resolve(path)
+        .map(|(asset, version)| (asset.url.clone(), version))
+    };
+    let min_url = cache.url("js/foo.min.js").unwrap().to_string();
+    assert_eq!(
+      version("js/foo.min.js"),
+      Some((min_url.clone(), AssetVersion::Unversioned))
+    );
+    assert_eq!(
+      version(&format!("js/foo.min.{hash}.js")),
+      Some((min_url.clone(), AssetVersion::Current))
+    );
+    assert_eq!(
+      version("js/foo.min.0123456789abcdef.js"),
+      Some((min_url, AssetVersion::Stale))
+    );
+    // Only a content hash is stripped, so other dotted names are unknown.
+    assert_eq!(version("js/foo.bar.js"), None);
+    assert_eq!(version("js/foo.0123456789abcdeg.js"), None);
+  }
+
+  #[test]
+  fn negotiate_honors_q_values() {
+    let all = |_| true;
+    let negotiate = |header| Encoding::negotiate(header, all);
+
+    assert_eq!(negotiate(None), Some(Encoding::Identity));
+    assert_eq!(negotiate(Some("gzip, deflate, br")), Some(Encoding::Brotli));
+    assert_eq!(negotiate(Some("gzip, br;q=0.5")), Some(Encoding::Gzip));
+    assert_eq!(negotiate(Some("deflate")), Some(Encoding::Identity));
+    assert_eq!(negotiate(Some("*")), Some(Encoding::Brotli));
+    assert_eq!(negotiate(Some("br;q=0, *;q=0.5")), Some(Encoding::Gzip));
+    assert_eq!(negotiate(Some("identity;q=0")), None);
+    assert_eq!(negotiate(Some("*;q=0")), None);
+    assert_eq!(
+      Encoding::negotiate(Some("br, gzip"), |e| e != Encoding::Brotli),
+      Some(Encoding::Gzip)
+    );
+  }
+
+  #[cfg(debug_assertions)]
+  #[tokio::test]
+  async fn reload_paths_refreshes_changed_entries() {
+    let dir = write_assets(&[("js/main.js", b"one"), ("css/main.css", b"body{}")]);
+    let cache = AssetCache::load_files(Some(&dir), &[]).await;
+    let old_url = cache.url("js/main.js").unwrap().to_string();
+
+    fs::write(dir.join("js/main.js"), b"two").unwrap();
+    let cache = cache.reload_paths(&[&dir], &[dir.join("js/main.js")]).await;
+
+    let asset = cache.get("js/main.js").unwrap();
+    assert_eq!(asset.identity.as_ref(), b"two");
+    assert_ne!(asset.url, old_url);
+    assert!(cache.get("css/main.css").is_some());
+
+    fs::remove_file(dir.join("css/main.css")).unwrap();
+    let cache = cache
+      .reload_paths(&[&dir], &[dir.join("css/main.css")])
+      .await;
+    assert!(cache.get("css/main.css").is_none());
+  }
+
+  #[tokio::test]
+  async fn assets_carry_content_type_and_compression() {
+    let css = "body{color:red}".repeat(100);
+    let dir = write_assets(&[
+      ("css/main.css", css.as_bytes()),
+      ("css/tiny.css", b"a{}"),
+      (
+        "img/logo.svg",
+        format!("<svg>{}</svg>", "<g/>".repeat(300)).as_bytes(),
+      ),
+      ("apple-app-site-association", br#"{"applinks":{}}"#),
+      (".DS_Store", b"\0\0\0\x01Bud1"),
+    ]);
+    let cache = AssetCache::load_files(Some(&dir), &[]).await;
+
+    let main = cache.get("css/main.css").unwrap();
+    assert_eq!(main.content_type, "text/css; charset=utf-8");
+    assert!(main.gzip.is_some() && main.brotli.is_some());
+
+    let tiny = cache.get("css/tiny.css").unwrap();
+    assert!(tiny.gzip.is_none() && tiny.brotli.is_none());
+
+    let logo = cache.get("img/logo.svg").unwrap();
+    assert_eq!(logo.content_type, "image/svg+xml; charset=utf-8");
+    assert!(logo.brotli.is_some());
+
+    let aasa = cache.get("apple-app-site-association").unwrap();
+    assert_eq!(aasa.content_type, "application/json; charset=utf-8");
+    let (_, version) = cache
+      .resolve(&format!("apple-app-site-association.{}", aasa.hash))
+      .unwrap();
+    assert_eq!(version, AssetVersion::Current);
+    assert_eq!(
+      cache.resolve("apple-app-site-association").unwrap().1,
+      AssetVersion::Unversioned
+    );
+    assert!(cache.get(".DS_Store").is_none());
+  }
+
+  #[cfg(debug_assertions)]
+  #[tokio::test]
+  async fn configured_content_types_survive_reload() {
+    let dir = write_assets(&[("robots", b"User-agent: *")]);
+    let cache = AssetCache::load_files(Some(&dir), &[])
+      .await
+      .with_content_types([("robots".to_string(), "text/x-robots".parse().unwrap())].into());
+    assert_eq!(
+      cache.get("robots").unwrap().content_type,
+      "text/x-robots; charset=utf-8"
+    );
+
+    fs::write(dir.join("robots"), b"User-agent: *\nDisallow: /").unwrap();
+    let cache = cache.reload_paths(&[&dir], &[dir.join("robots")]).await;
+    assert_eq!(
+      cache.get("robots").unwrap().mime.essence_str(),
+      "text/x-robots"
+    );
+  }
 }
@@ -0,0 +1,333 @@
+//! Mutual TLS: verifying client certificates and handing them to handlers.
+//!
+//! With `server.client_auth` set, the HTTPS listener asks clients for a
+//! certificate chaining to `server.client_ca`, checked against any
+//! `server.client_crls`. [`ClientCertAcceptor`] reads the verified leaf
+//! certificate once per connection and every request on that connection
+//! carries it, for the [`ClientCert`] extractor.
+//!
+//! Browsers present client certificates without being asked by the page, so
+//! a certificate is an ambient credential like a cookie: cert-authenticated
+//! requests still go through the CSRF checks.
+
+use std::{
+  convert::Infallible,
+  io,
+  net::IpAddr,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use axum::{
+  extract::{FromRequestParts, OptionalFromRequestParts},
+  middleware::AddExtension,
+  response::{IntoResponse, Response},
+};
+use axum_server::{
+  accept::Accept,
+  tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use futures::future::BoxFuture;
+use http::{StatusCode, request::Parts};
+use rustls::{
+  RootCertStore,
+  pki_types::{CertificateDer, CertificateRevocationListDer, pem::PemObject as _},
+  server::{WebPkiClientVerifier, danger::ClientCertVerifier},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_layer::Layer as _;
+use x509_parser::extensions::GeneralName;
+
+use crate::{config::ClientAuth, tls};
+
+/// Builds the verifier for `mode`, trusting the CAs in the PEM bundle at
+/// `ca_path` and rejecting certificates revoked by the PEM CRLs in
+/// `crl_paths`. Returns `None` when clients aren't asked for certificates.
+pub fn client_verifier(
+  mode: ClientAuth,
+  ca_path: Option<&Path>,
+  crl_paths: &[PathBuf],
+) -> eyre::Result<Option<Arc<dyn ClientCertVerifier>>> {
+  if mode == ClientAuth::Off {
+    return Ok(None);
+  }
+  let ca_path = ca_path.ok_or_else(|| eyre::eyre!("client_auth needs a client_ca"))?;
+
+  let mut roots = RootCertStore::empty();
+  let cas = CertificateDer::pem_file_iter(ca_path)
+    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| eyre::eyre!("failed to read {}: {e}", ca_path.display()))?;
+  let (added, _) = roots.add_parsable_certificates(cas);
+  if added == 0 {
+    eyre::bail!("no usable CA certificates in {}", ca_path.display());
+  }
+
+  let mut crls = Vec::new();
+  for path in crl_paths {
+    let file = CertificateRevocationListDer::pem_file_iter(path)
+      .and_then(|crls| crls.collect::<Result<Vec<_>, _>>())
+      .map_err(|e| eyre::eyre!("failed to read {}: {e}", path.display()))?;
+    crls.extend(file);
+  }
+
+  let mut builder =
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), tls::crypto_provider());
+  if !crls.is_empty() {
+    // Only the issuing CAs' CRLs are needed, not the whole chain's.
+    builder = builder.with_crls(crls).only_check_end_entity_revocation();
+  }
+  if mode == ClientAuth::Optional {
+    builder = builder.allow_unauthenticated();
+  }
+  Ok(Some(builder.build()?))
+}
+
+/// A name from a client certificate's subject alternative names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+  Dns(String),
+  Email(String),
+  /// Such as a SPIFFE ID, `spiffe://example.org/service`.
+  Uri(String),
+  Ip(IpAddr),
+}
+
+/// The verified certificate the client presented during the TLS handshake.
+///
+/// Rejects requests without one with `401 Unauthorized`; extract
+/// `Option<ClientCert>` where a certificate is optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+  /// The subject's distinguished name, e.g. `CN=billing,O=Example`.
+  pub subject: String,
+  /// The DNS names, email addresses, URIs and IP addresses the certificate
+  /// is for. Other kinds of name are left out.
+  pub sans: Vec<SubjectAltName>,
+}
+
+impl ClientCert {
+  /// Reads the leaf certificate of a chain rustls has verified.
+  pub fn from_der(cert: &CertificateDer<'_>) -> eyre::Result<Self> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+      .map_err(|e| eyre::eyre!("invalid certificate: {e}"))?;
+    let sans = cert
+      .subject_alternative_name()
+      .map_err(|e| eyre::eyre!("invalid subject alternative names: {e}"))?
+      .map(|ext| {
+        ext
+          .value
+          .general_names
+          .iter()
+          .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(SubjectAltName::Dns(name.to_string())),
+            GeneralName::RFC822Name(email) => Some(SubjectAltName::Email(email.to_string())),
+            GeneralName::URI(uri) => Some(SubjectAltName::Uri(uri.to_string())),
+            GeneralName::IPAddress(bytes) => ip_from_bytes(bytes).map(SubjectAltName::Ip),
+            _ => None,
+          })
+          .collect()
+      })
+      .unwrap_or_default();
+
+    Ok(Self {
+      subject: cert.subject().to_string(),
+      sans,
+    })
+  }
+
+  /// The name callers are known by: the first URI or DNS name, or the
+  /// subject when the certificate has neither.
+  pub fn identity(&self) -> &str {
+    let uri = self.sans.iter().find_map(|san| match san {
+      SubjectAltName::Uri(uri) => Some(uri),
+      _ => None,
+    });
+    let dns = self.sans.iter().find_map(|san| match san {
+      SubjectAltName::Dns(name) => Some(name),
+      _ => None,
+    });
+    uri.or(dns).unwrap_or(&self.subject)
+  }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+  match bytes.len() {
+    4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+    16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+    _ => None,
+  }
+}
+
+impl<S> FromRequestParts<S> for ClientCert
+where
+  S: Send + Sync,
+{
+  type Rejection = Response;
+
+  #[allow(clippy::manual_async_fn)]
+  fn from_request_parts(
+    parts: &mut Parts,
+    state: &S,
+  ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+    async move {
+      <Self as OptionalFromRequestParts<S>>::from_request_parts(parts, state)
+        .await
+        .unwrap_or_else(|never| match never {})
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "client certificate required").into_response())
+    }
+  }
+}
+
+impl<S> OptionalFromRequestParts<S> for ClientCert
+where
+  S: Send + Sync,
+{
+  type Rejection = Infallible;
+
+  #[allow(clippy::manual_async_fn)]
+  fn from_request_parts(
+    parts: &mut Parts,
+    _state: &S,
+  ) -> impl Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+    async move {
+      // `ClientCertAcceptor` adds this to every request on a connection.
+      Ok(parts.extensions.get::<Option<Self>>().cloned().flatten())
+    }
+  }
+}
+
+/// Completes TLS handshakes like [`RustlsAcceptor`] and adds the client's
+/// certificate, if it presented one, to each request on the connection.
+#[derive(Debug, Clone)]
+pub struct ClientCertAcceptor {
+  inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+  pub fn new(config: RustlsConfig) -> Self {
+    Self {
+      inner: RustlsAcceptor::new(config),
+    }
+  }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+  I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  S: Send + 'static,
+{
+  type Stream = TlsStream<I>;
+  type Service = AddExtension<S, Option<ClientCert>>;
+  type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+  fn accept(&self, stream: I, service: S) -> Self::Future {
+    let handshake = self.inner.accept(stream, service);
+    Box::pin(async move {
+      let (stream, service) = handshake.await?;
+      // rustls has already verified the chain, so a leaf that doesn't parse
+      // is a bug rather than a bad client.
+      let cert = match stream.get_ref().1.peer_certificates() {
+        Some([leaf, ..]) => Some(ClientCert::from_der(leaf).map_err(io::Error::other)?),
+        _ => None,
+      };
+      Ok((stream, axum::Extension(cert).layer(service)))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ca() -> (rcgen::Certificate, rcgen::KeyPair) {
+    let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+      rcgen::KeyUsagePurpose::KeyCertSign,
+      rcgen::KeyUsagePurpose::CrlSign,
+    ];
+    (params.self_signed(&key).unwrap(), key)
+  }
+
+  fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mtls-{}", uuid::Uuid::now_v7().simple()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+  }
+
+  #[test]
+  fn client_cert_names() {
+    let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let mut params = rcgen::CertificateParams::new(vec!["billing.internal".into()]).unwrap();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+      .distinguished_name
+      .push(rcgen::DnType::CommonName, "billing");
+    params.subject_alt_names.extend([
+      rcgen::SanType::URI("spiffe://example.org/billing".try_into().unwrap()),
+      rcgen::SanType::IpAddress("10.0.0.7".parse().unwrap()),
+      rcgen::SanType::Rfc822Name("ops@example.org".try_into().unwrap()),
+    ]);
+    let cert = params.self_signed(&key).unwrap();
+
+    let client = ClientCert::from_der(cert.der()).unwrap();
+    assert_eq!(client.subject, "CN=billing");
+    assert_eq!(
+      client.sans,
+      [
+        SubjectAltName::Dns("billing.internal".into()),
+        SubjectAltName::Uri("spiffe://example.org/billing".into()),
+        SubjectAltName::Ip("10.0.0.7".parse().unwrap()),
+        SubjectAltName::Email("ops@example.org".into()),
+      ]
+    );
+    assert_eq!(client.identity(), "spiffe://example.org/billing");
+
+    let subject_only = ClientCert {
+      subject: "CN=billing".into(),
+      sans: Vec::new(),
+    };
+    assert_eq!(subject_only.identity(), "CN=billing");
+  }
+
+  #[test]
+  fn verifiers_need_a_ca_and_read_crls() {
+    assert!(
+      client_verifier(ClientAuth::Off, None, &[])
+        .unwrap()
+        .is_none()
+    );
+    assert!(client_verifier(ClientAuth::Required, None, &[]).is_err());
+
+    let (ca, ca_key) = ca();
+    let ca_path = temp_file("ca.pem", &ca.pem());
+    let required = client_verifier(ClientAuth::Required, Some(&ca_path), &[])
+      .unwrap()
+      .unwrap();
+    assert!(required.client_auth_mandatory());
+    let optional = client_verifier(ClientAuth::Optional, Some(&ca_path), &[])
+      .unwrap()
+      .unwrap();
+    assert!(!optional.client_auth_mandatory());
+
+    let crl = rcgen::CertificateRevocationListParams {
+      this_update: rcgen::date_time_ymd(2020, 1, 1),
+      next_update: rcgen::date_time_ymd(2100, 1, 1),
+      crl_number: rcgen::SerialNumber::from(1u64),
+      issuing_distribution_point: None,
+      revoked_certs: Vec::new(),
+      key_identifier_method: rcgen::KeyIdMethod::Sha256,
+    }
+    .signed_by(&ca, &ca_key)
+    .unwrap();
+    let crl_path = temp_file("crl.pem", &crl.pem().unwrap());
+    assert!(client_verifier(ClientAuth::Required, Some(&ca_path), &[crl_path]).is_ok());
+
+    let not_pem = temp_file("ca.pem", "not a certificate");
+    assert!(client_verifier(ClientAuth::Required, Some(&not_pem), &[]).is_err());
+  }
+}
@@ -0,0 +1,478 @@
+//! The client's address, scheme and host as seen through reverse proxies.
+//!
+//! Behind a load balancer the socket peer is the balancer, and the request's
+//! authority is whatever it forwarded to. [`client_info_layer`] works out the
+//! real client from the `X-Forwarded-For/Proto/Host` headers, or `Forwarded`
+//! with `server.forwarded_headers = "forwarded"`, but only believes them when
+//! the peer is one of the configured `server.trusted_proxies`; anyone else
+//! could send them to spoof an address. Only the family the proxies set is
+//! read, as they pass the other one on from the client untouched.
+//! Handlers read the result with the [`ClientInfo`] extractor.
+
+use std::{
+  convert::Infallible,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+};
+
+use axum::{
+  extract::{ConnectInfo, FromRequestParts, Request, State},
+  middleware::Next,
+  response::Response,
+};
+use http::{
+  HeaderMap, Uri,
+  header::{FORWARDED, HOST},
+  request::Parts,
+  uri::{Authority, Scheme},
+};
+use ipnet::IpNet;
+
+use crate::config::ForwardedHeaders;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Who sent a request, and how they addressed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+  /// The client's address. `None` when the connection has no socket address
+  /// to start from.
+  pub ip: Option<IpAddr>,
+  /// The scheme the client used, `http` or `https`.
+  pub scheme: Scheme,
+  /// The host and port the client asked for, if it said.
+  pub host: Option<Authority>,
+}
+
+impl<S> FromRequestParts<S> for ClientInfo
+where
+  S: Send + Sync,
+{
+  type Rejection = Infallible;
+
+  #[allow(clippy::manual_async_fn)]
+  fn from_request_parts(
+    parts: &mut Parts,
+    _state: &S,
+  ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+    async move {
+      // Without the layer no proxy is trusted.
+      let info = parts.extensions.get::<ClientInfo>().cloned();
+      Ok(info.unwrap_or_else(|| {
+        TrustedProxies::new(Vec::new(), Scheme::HTTP).client_info(
+          peer_ip(&parts.extensions),
+          &parts.headers,
+          &parts.uri,
+        )
+      }))
+    }
+  }
+}
+
+/// Connect info for requests over a Unix domain socket, which have no peer
+/// address. They count as coming from `127.0.0.1`, so listing that in
+/// `server.trusted_proxies` trusts a proxy on the same host.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+pub struct UnixPeer;
+
+#[cfg(unix)]
+impl
+  axum::extract::connect_info::Connected<axum::serve::IncomingStream<'_, tokio::net::UnixListener>>
+  for UnixPeer
+{
+  fn connect_info(_stream: axum::serve::IncomingStream<'_, tokio::net::UnixListener>) -> Self {
+    Self
+  }
+}
+
+/// Parses a `server.trusted_proxies` entry: a CIDR range or a single
+/// address.
+pub(crate) fn parse_network(network: &str) -> Result<IpNet, String> {
+  network
+    .parse::<IpNet>()
+    .map(|net| net.trunc())
+    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+    .map_err(|_| format!("'{network}' is not an IP address or CIDR range"))
+}
+
+/// The proxies whose forwarding headers are believed, which headers those
+/// are, and the scheme of the listener requests arrive on.
+#[derive(Debug, Clone)]
+pub struct TrustedProxies {
+  networks: Vec<IpNet>,
+  headers: ForwardedHeaders,
+  scheme: Scheme,
+}
+
+/// One proxy hop from a forwarding header.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Hop {
+  /// The address the proxy received the request from, unless it was
+  /// obfuscated or unparseable.
+  addr: Option<IpAddr>,
+  proto: Option<Scheme>,
+  host: Option<Authority>,
+}
+
+impl TrustedProxies {
+  /// Trusts `networks` to set `X-Forwarded-*` headers.
+  pub fn new(networks: Vec<IpNet>, scheme: Scheme) -> Self {
+    Self {
+      networks,
+      headers: ForwardedHeaders::default(),
+      scheme,
+    }
+  }
+
+  /// Reads `headers` instead of `X-Forwarded-*`.
+  pub fn with_forwarded_headers(mut self, headers: ForwardedHeaders) -> Self {
+    self.headers = headers;
+    self
+  }
+
+  fn is_trusted(&self, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    self.networks.iter().any(|net| net.contains(&ip))
+  }
+
+  /// Works out who sent a request that arrived from `peer`.
+  ///
+  /// The client is the nearest address in the forwarding chain that isn't a
+  /// trusted proxy, walking back from the peer. The scheme and host come from
+  /// the hop the peer itself added, as earlier hops may have been written by
+  /// the client.
+  pub fn client_info(&self, peer: Option<IpAddr>, headers: &HeaderMap, uri: &Uri) -> ClientInfo {
+    let direct = ClientInfo {
+      ip: peer.map(|ip| ip.to_canonical()),
+      scheme: uri.scheme().cloned().unwrap_or_else(|| self.scheme.clone()),
+      host: uri.authority().cloned().or_else(|| {
+        headers
+          .get(HOST)
+          .and_then(|value| Authority::try_from(value.as_bytes()).ok())
+      }),
+    };
+
+    let Some(peer) = peer.filter(|peer| self.is_trusted(*peer)) else {
+      return direct;
+    };
+    let hops = match self.headers {
+      ForwardedHeaders::XForwarded => x_forwarded_hops(headers),
+      ForwardedHeaders::Forwarded => forwarded_hops(headers),
+    };
+    let Some(nearest) = hops.last() else {
+      return direct;
+    };
+
+    let mut ip = peer.to_canonical();
+    for hop in hops.iter().rev() {
+      if !self.is_trusted(ip) {
+        break;
+      }
+      match hop.addr {
+        Some(addr) => ip = addr.to_canonical(),
+        // The client is hidden behind this proxy, which is as far as we know.
+        None => break,
+      }
+    }
+
+    ClientInfo {
+      ip: Some(ip),
+      scheme: nearest.proto.clone().unwrap_or(direct.scheme),
+      host: nearest.host.clone().or(direct.host),
+    }
+  }
+}
+
+/// Middleware storing the request's [`ClientInfo`] in its extensions and
+/// recording the client address on the request span.
+pub async fn client_info_layer(
+  State(proxies): State<Arc<TrustedProxies>>,
+  mut request: Request,
+  next: Next,
+) -> Response {
+  let info = proxies.client_info(
+    peer_ip(request.extensions()),
+    request.headers(),
+    request.uri(),
+  );
+  if let Some(ip) = info.ip {
+    tracing::Span::current().record("http.client.address", ip.to_string());
+  }
+  request.extensions_mut().insert(info);
+  next.run(request).await
+}
+
+fn peer_ip(extensions: &http::Extensions) -> Option<IpAddr> {
+  if let Some(ConnectInfo(addr)) = extensions.get::<ConnectInfo<SocketAddr>>() {
+    return Some(addr.ip());
+  }
+  #[cfg(unix)]
+  if extensions.get::<ConnectInfo<UnixPeer>>().is_some() {
+    return Some(std::net::Ipv4Addr::LOCALHOST.into());
+  }
+  None
+}
+
+/// Reads the `Forwarded` chain, oldest hop first.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+  joined(headers, FORWARDED.as_str())
+    .split(',')
+    .filter(|element| !element.trim().is_empty())
+    .map(parse_forwarded_element)
+    .collect()
+}
+
+/// Reads the `X-Forwarded-For` chain, oldest hop first, with the scheme and
+/// host on the nearest hop.
+fn x_forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+  let mut hops: Vec<Hop> = joined(headers, X_FORWARDED_FOR)
+    .split(',')
+    .filter(|node| !node.trim().is_empty())
+    .map(|node| Hop {
+      addr: parse_node(node),
+      ..Hop::default()
+    })
+    .collect();
+
+  let proto = last_value(headers, X_FORWARDED_PROTO).and_then(parse_proto);
+  let host = last_value(headers, X_FORWARDED_HOST).and_then(|host| host.parse().ok());
+  if proto.is_some() || host.is_some() {
+    if hops.is_empty() {
+      hops.push(Hop::default());
+    }
+    let nearest = hops.last_mut().expect("not empty");
+    nearest.proto = proto;
+    nearest.host = host;
+  }
+  hops
+}
+
+/// All values of a list header, joined as if sent in one line.
+fn joined(headers: &HeaderMap, name: &str) -> String {
+  headers
+    .get_all(name)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+fn last_value(headers: &HeaderMap, name: &str) -> Option<String> {
+  joined(headers, name)
+    .rsplit(',')
+    .next()
+    .map(|value| value.trim().to_string())
+    .filter(|value| !value.is_empty())
+}
+
+/// Parses one `Forwarded` element, e.g. `for=192.0.2.60;proto=https`.
+fn parse_forwarded_element(element: &str) -> Hop {
+  let mut hop = Hop::default();
+  for pair in element.split(';') {
+    let Some((key, value)) = pair.split_once('=') else {
+      continue;
+    };
+    let value = value.trim().trim_matches('"');
+    match key.trim().to_ascii_lowercase().as_str() {
+      "for" => hop.addr = parse_node(value),
+      "proto" => hop.proto = parse_proto(value),
+      "host" => hop.host = value.parse().ok(),
+      _ => {}
+    }
+  }
+  hop
+}
+
+/// Parses a node: an address, optionally with a port and, for IPv6, in
+/// brackets. Obfuscated identifiers and `unknown` give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+  let node = node.trim().trim_matches('"');
+  if let Some(rest) = node.strip_prefix('[') {
+    return rest.split_once(']')?.0.parse().ok();
+  }
+  node
+    .parse::<IpAddr>()
+    .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+    .ok()
+}
+
+fn parse_proto(proto: impl AsRef<str>) -> Option<Scheme> {
+  match proto.as_ref().trim().to_ascii_lowercase().as_str() {
+    "http" => Some(Scheme::HTTP),
+    "https" => Some(Scheme::HTTPS),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use http::HeaderValue;
+
+  fn proxies(networks: &[&str]) -> TrustedProxies {
+    TrustedProxies::new(
+      networks.iter().map(|n| parse_network(n).unwrap()).collect(),
+      Scheme::HTTP,
+    )
+  }
+
+  fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+      headers.append(*name, HeaderValue::from_static(value));
+    }
+    headers
+  }
+
+  fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  fn resolve(
+    proxies: &TrustedProxies,
+    peer: &str,
+    pairs: &[(&'static str, &'static str)],
+  ) -> ClientInfo {
+    proxies.client_info(Some(ip(peer)), &headers(pairs), &Uri::from_static("/"))
+  }
+
+  #[test]
+  fn untrusted_peers_cannot_spoof_headers() {
+    let proxies = proxies(&["10.0.0.0/8"]);
+    let info = resolve(
+      &proxies,
+      "203.0.113.9",
+      &[
+        ("host", "app.example"),
+        ("x-forwarded-for", "198.51.100.1"),
+        ("x-forwarded-proto", "https"),
+        ("x-forwarded-host", "evil.example"),
+      ],
+    );
+    assert_eq!(info.ip, Some(ip("203.0.113.9")));
+    assert_eq!(info.scheme, Scheme::HTTP);
+    assert_eq!(info.host.unwrap(), "app.example");
+  }
+
+  #[test]
+  fn x_forwarded_headers_from_trusted_peers() {
+    let proxies = proxies(&["10.0.0.0/8", "192.0.2.1"]);
+    let info = resolve(
+      &proxies,
+      "10.0.0.5",
+      &[
+        ("host", "backend:8080"),
+        // The client made up the first entry; 192.0.2.1 is our CDN.
+        ("x-forwarded-for", "127.0.0.1, 198.51.100.7"),
+        ("x-forwarded-for", "192.0.2.1"),
+        ("x-forwarded-proto", "http, https"),
+        ("x-forwarded-host", "app.example"),
+      ],
+    );
+    assert_eq!(info.ip, Some(ip("198.51.100.7")));
+    assert_eq!(info.scheme, Scheme::HTTPS);
+    assert_eq!(info.host.unwrap(), "app.example");
+
+    // Only proxies in the chain means the oldest one is the client.
+    let info = resolve(&proxies, "10.0.0.5", &[("x-forwarded-for", "10.1.2.3")]);
+    assert_eq!(info.ip, Some(ip("10.1.2.3")));
+  }
+
+  #[test]
+  fn forwarded_header_when_configured() {
+    let proxies = proxies(&["10.0.0.0/8"]).with_forwarded_headers(ForwardedHeaders::Forwarded);
+    let info = resolve(
+      &proxies,
+      "10.0.0.5",
+      &[
+        ("x-forwarded-for", "198.51.100.1"),
+        (
+          "forwarded",
+          r#"for="[2001:db8:cafe::17]:4711";proto=https;host=app.example, for=10.0.0.9"#,
+        ),
+      ],
+    );
+    assert_eq!(info.ip, Some(ip("2001:db8:cafe::17")));
+    // Only the hop our peer added is believed about the scheme.
+    assert_eq!(info.scheme, Scheme::HTTP);
+
+    let info = resolve(
+      &proxies,
+      "10.0.0.5",
+      &[(
+        "forwarded",
+        r#"for="[2001:db8:cafe::17]:4711", for=10.0.0.9;proto=https;host="app.example:8443""#,
+      )],
+    );
+    assert_eq!(info.ip, Some(ip("2001:db8:cafe::17")));
+    assert_eq!(info.scheme, Scheme::HTTPS);
+    assert_eq!(info.host.unwrap(), "app.example:8443");
+
+    // A proxy that sets `Forwarded` passes on whatever `X-Forwarded-*` the
+    // client sent.
+    let info = resolve(
+      &proxies,
+      "10.0.0.5",
+      &[
+        ("x-forwarded-for", "192.0.2.66"),
+        ("x-forwarded-proto", "https"),
+        ("forwarded", "for=198.51.100.1"),
+      ],
+    );
+    assert_eq!(info.ip, Some(ip("198.51.100.1")));
+    assert_eq!(info.scheme, Scheme::HTTP);
+  }
+
+  #[test]
+  fn x_forwarded_proxies_ignore_a_spoofed_forwarded_header() {
+    // nginx appends to X-Forwarded-For but leaves the client's `Forwarded`
+    // header alone.
+    let proxies = proxies(&["10.0.0.0/8"]);
+    let info = resolve(
+      &proxies,
+      "10.0.0.5",
+      &[
+        ("host", "app.example"),
+        (
+          "forwarded",
+          "for=192.0.2.66;proto=https;host=evil.example, for=10.0.0.9",
+        ),
+        ("x-forwarded-for", "198.51.100.7"),
+      ],
+    );
+    assert_eq!(info.ip, Some(ip("198.51.100.7")));
+    assert_eq!(info.scheme, Scheme::HTTP);
+    assert_eq!(info.host.unwrap(), "app.example");
+  }
+
+  #[test]
+  fn hidden_clients_stop_at_the_proxy() {
+    let proxies = proxies(&["10.0.0.0/8"]).with_forwarded_headers(ForwardedHeaders::Forwarded);
+    let info = resolve(
+      &proxies,
+      "10.0.0.5",
+      &[("forwarded", "for=_hidden, for=10.0.0.9")],
+    );
+    assert_eq!(info.ip, Some(ip("10.0.0.9")));
+    let info = resolve(&proxies, "10.0.0.5", &[("forwarded", "for=unknown")]);
+    assert_eq!(info.ip, Some(ip("10.0.0.5")));
+  }
+
+  #[test]
+  fn networks_parse_and_match_mapped_addresses() {
+    assert_eq!(
+      parse_network("10.1.2.3/8").unwrap().to_string(),
+      "10.0.0.0/8"
+    );
+    assert_eq!(parse_network("::1").unwrap().to_string(), "::1/128");
+    assert!(parse_network("10.0.0.0/33").is_err());
+    assert!(parse_network("localhost").is_err());
+
+    let proxies = proxies(&["127.0.0.1"]);
+    assert!(proxies.is_trusted(ip("::ffff:127.0.0.1")));
+    assert!(!proxies.is_trusted(ip("127.0.0.2")));
+  }
+}
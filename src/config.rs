@@ -1,6 +1,17 @@
 use clap::{Args, Parser};
 use confique::Config as _;
-use std::path::PathBuf;
+use jiff::{SignedDuration, Timestamp};
+use mime_guess::Mime;
+use serde::Deserialize;
+use std::{
+  collections::{BTreeMap, HashMap},
+  net::{Ipv4Addr, SocketAddr},
+  path::PathBuf,
+};
+
+use crate::{
+  client_info, csp, listen, rate_limit, redirect, tls, tokio_postgres_sessions::is_valid_identifier,
+};
 
 #[derive(confique::Config, Debug, Clone)]
 pub struct AppConfig {
@@ -8,9 +19,24 @@ pub struct AppConfig {
   pub server: Server,
   #[config(nested)]
   pub postgres: Postgres,
+  #[config(nested)]
+  pub session: Session,
+  #[config(nested)]
+  pub assets: Assets,
+  #[config(nested)]
+  pub security: Security,
+  #[config(nested)]
+  pub rate_limit: RateLimit,
+  #[config(nested)]
+  pub acme: Acme,
+  #[config(nested)]
+  pub shutdown: Shutdown,
+  #[config(nested)]
+  pub jobs: Jobs,
 }
 
 #[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
 pub struct Server {
   // Networking
   #[config(default = 8080, env = "{{project-name | shouty_snake_case}}_HTTP_PORT")]
@@ -26,12 +52,34 @@ pub struct Server {
   )]
   pub monitoring_port: u16,
 
+  /// Addresses the HTTP listener binds, e.g. `["127.0.0.1:8080",
+  /// "[::1]:8080"]`. Empty binds all IPv4 interfaces on `http_port`.
+  #[config(default = [])]
+  pub http_bind: Vec<SocketAddr>,
+  /// Addresses the HTTPS listener binds. Empty binds all IPv4 interfaces on
+  /// `https_port`.
+  #[config(default = [])]
+  pub https_bind: Vec<SocketAddr>,
+  /// Addresses the monitoring listener binds. Empty binds all IPv4
+  /// interfaces on `monitoring_port`.
+  #[config(default = [])]
+  pub monitoring_bind: Vec<SocketAddr>,
+  /// A Unix domain socket the app is also served on, over plain HTTP, for a
+  /// reverse proxy on the same host.
+  pub unix_socket: Option<PathBuf>,
+  /// A Unix domain socket the monitoring endpoints are also served on.
+  pub monitoring_unix_socket: Option<PathBuf>,
+
   // TLS toggle
   #[config(
     default = false,
     env = "{{project-name | shouty_snake_case}}_TLS_ENABLED"
   )]
   pub tls_enabled: bool,
+  /// Whether to also serve HTTP/3 over QUIC, on UDP at the HTTPS addresses,
+  /// when TLS is enabled.
+  #[config(default = false, env = "{{project-name | shouty_snake_case}}_HTTP3")]
+  pub http3: bool,
 
   // TLS/ACME configuration (optional)
   #[config(default = [])]
@@ -44,6 +92,152 @@ pub struct Server {
   pub production: bool,
   pub tls_key: Option<PathBuf>,
   pub tls_cert: Option<PathBuf>,
+  /// Certificates for particular hosts, chosen by the name clients ask for
+  /// (SNI). Other hosts get `tls_cert`, or the ACME certificate for
+  /// `domains`, or else the first of these.
+  #[config(default = [])]
+  pub certificates: Vec<HostCertificate>,
+
+  // Client certificates
+  /// Whether HTTPS clients are asked for a certificate: `off`, `optional`
+  /// or `required`.
+  #[config(default = "off")]
+  pub client_auth: ClientAuth,
+  /// A PEM bundle of the CAs client certificates must chain to.
+  pub client_ca: Option<PathBuf>,
+  /// PEM files of certificate revocation lists from those CAs.
+  #[config(default = [])]
+  pub client_crls: Vec<PathBuf>,
+
+  // Reverse proxies
+  /// Addresses or CIDR ranges of reverse proxies whose `Forwarded` and
+  /// `X-Forwarded-*` headers are believed, e.g. `["10.0.0.0/8", "::1"]`.
+  #[config(default = [])]
+  pub trusted_proxies: Vec<String>,
+  /// Which forwarding headers the trusted proxies set: `x-forwarded` for
+  /// `X-Forwarded-For/Proto/Host`, as nginx and most load balancers do, or
+  /// `forwarded` for the standard `Forwarded` header. The other family is
+  /// ignored, since proxies pass it on from the client untouched.
+  #[config(default = "x-forwarded")]
+  pub forwarded_headers: ForwardedHeaders,
+
+  // HTTP to HTTPS redirects
+  /// The host plain HTTP requests are redirected to, instead of the one they
+  /// asked for.
+  pub canonical_host: Option<String>,
+  /// A directory of ACME HTTP-01 challenge responses to serve over plain
+  /// HTTP, for certificates issued by an external client such as certbot.
+  pub acme_challenge_dir: Option<PathBuf>,
+}
+
+impl Server {
+  fn validate(&self) -> Result<(), String> {
+    let mut addrs: Vec<_> = self.http_addrs().into_iter().map(|a| ("http", a)).collect();
+    if self.tls_enabled {
+      addrs.extend(self.https_addrs().into_iter().map(|a| ("https", a)));
+    }
+    addrs.extend(
+      self
+        .monitoring_addrs()
+        .into_iter()
+        .map(|a| ("monitoring", a)),
+    );
+    listen::check_collisions(&addrs).map_err(|e| format!("server: {e}"))?;
+
+    if cfg!(not(unix)) && (self.unix_socket.is_some() || self.monitoring_unix_socket.is_some()) {
+      return Err("server: Unix sockets aren't supported on this platform".into());
+    }
+    if self.unix_socket.is_some() && self.unix_socket == self.monitoring_unix_socket {
+      return Err("server: unix_socket and monitoring_unix_socket must differ".into());
+    }
+
+    for network in &self.trusted_proxies {
+      client_info::parse_network(network).map_err(|e| format!("server.trusted_proxies: {e}"))?;
+    }
+    if let Some(host) = &self.canonical_host {
+      redirect::validate_canonical_host(host).map_err(|e| format!("server.canonical_host: {e}"))?;
+    }
+    let mut hosts: Vec<&str> = self.domains.iter().map(String::as_str).collect();
+    for certificate in &self.certificates {
+      if certificate.domains.is_empty() {
+        return Err(format!(
+          "server.certificates: {} lists no domains",
+          certificate.cert.display()
+        ));
+      }
+      for domain in &certificate.domains {
+        tls::validate_host_pattern(domain).map_err(|e| format!("server.certificates: {e}"))?;
+        if hosts.iter().any(|host| host.eq_ignore_ascii_case(domain)) {
+          return Err(format!("server.certificates: {domain} is listed twice"));
+        }
+        hosts.push(domain);
+      }
+    }
+    if self.client_auth != ClientAuth::Off && self.client_ca.is_none() {
+      return Err("server: client_auth needs a client_ca".into());
+    }
+    Ok(())
+  }
+
+  /// The addresses the HTTP listener binds.
+  pub fn http_addrs(&self) -> Vec<SocketAddr> {
+    bind_addrs(&self.http_bind, self.http_port)
+  }
+
+  /// The addresses the HTTPS listener binds.
+  pub fn https_addrs(&self) -> Vec<SocketAddr> {
+    bind_addrs(&self.https_bind, self.https_port)
+  }
+
+  /// The addresses the monitoring listener binds.
+  pub fn monitoring_addrs(&self) -> Vec<SocketAddr> {
+    bind_addrs(&self.monitoring_bind, self.monitoring_port)
+  }
+
+  /// The port HTTP requests are redirected to: that of the first HTTPS
+  /// address.
+  pub fn public_https_port(&self) -> u16 {
+    self.https_addrs()[0].port()
+  }
+
+  /// The configured trusted proxy networks, parsed.
+  pub fn trusted_proxies(&self) -> Vec<ipnet::IpNet> {
+    self
+      .trusted_proxies
+      .iter()
+      .map(|network| client_info::parse_network(network).expect("validated at load time"))
+      .collect()
+  }
+}
+
+/// The forwarding headers reverse proxies set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeaders {
+  /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`.
+  #[default]
+  XForwarded,
+  /// `Forwarded`, from RFC 7239.
+  Forwarded,
+}
+
+/// A certificate and key served for some host names.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostCertificate {
+  /// Host names, or wildcards such as `*.example.com` covering one more
+  /// label.
+  pub domains: Vec<String>,
+  pub cert: PathBuf,
+  pub key: PathBuf,
+}
+
+fn bind_addrs(bind: &[SocketAddr], port: u16) -> Vec<SocketAddr> {
+  if bind.is_empty() {
+    vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))]
+  } else {
+    bind.to_vec()
+  }
 }
 
 #[derive(Debug, Parser, Default, Clone)]
@@ -125,6 +319,8 @@ where
   if let Some(v) = cli.server.tls_cert {
     cfg.server.tls_cert = Some(v);
   }
+  // The overlay can introduce port collisions.
+  cfg.server.validate().map_err(|e| eyre::eyre!(e))?;
 
   Ok(cfg)
 }
@@ -135,6 +331,694 @@ pub struct Postgres {
   pub url: String,
 }
 
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct Assets {
+  /// Media types for assets whose detected type is wrong or missing, keyed by
+  /// asset name, e.g. `".well-known/apple-app-site-association" =
+  /// "application/json"`.
+  #[config(default = {})]
+  pub content_types: HashMap<String, String>,
+}
+
+impl Assets {
+  fn validate(&self) -> Result<(), String> {
+    for (name, mime) in &self.content_types {
+      if mime.parse::<Mime>().is_err() {
+        return Err(format!(
+          "assets.content_types '{name}' has invalid media type '{mime}'"
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  /// The configured media type overrides, parsed.
+  pub fn content_types(&self) -> HashMap<String, Mime> {
+    self
+      .content_types
+      .iter()
+      .map(|(name, mime)| (name.clone(), mime.parse().expect("validated at load time")))
+      .collect()
+  }
+}
+
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct Session {
+  // Cookie
+  #[config(default = "id")]
+  pub cookie_name: String,
+  pub domain: Option<String>,
+  #[config(default = "/")]
+  pub path: String,
+  #[config(
+    default = true,
+    env = "{{project-name | shouty_snake_case}}_SESSION_SECURE"
+  )]
+  pub secure: bool,
+  #[config(default = "lax")]
+  pub same_site: SameSite,
+
+  // Expiry
+  #[config(default = "browser-session")]
+  pub expiry: SessionExpiry,
+  /// How long a session lives without being modified, for `on-inactivity`.
+  #[config(default = "24h")]
+  pub duration: SignedDuration,
+  /// The fixed point in time sessions expire at, for `at-date`.
+  pub expires_at: Option<Timestamp>,
+
+  // Storage
+  #[config(default = "60s")]
+  pub deletion_interval: SignedDuration,
+  #[config(default = "tower_sessions")]
+  pub schema_name: String,
+  #[config(default = "session")]
+  pub table_name: String,
+}
+
+/// The `SameSite` attribute of the session cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+  Strict,
+  Lax,
+  None,
+}
+
+impl From<SameSite> for tower_sessions::cookie::SameSite {
+  fn from(value: SameSite) -> Self {
+    match value {
+      SameSite::Strict => Self::Strict,
+      SameSite::Lax => Self::Lax,
+      SameSite::None => Self::None,
+    }
+  }
+}
+
+/// When a session expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionExpiry {
+  /// `duration` after the session was last modified.
+  OnInactivity,
+  /// At the fixed `expires_at` timestamp.
+  AtDate,
+  /// When the browser ends its session.
+  BrowserSession,
+}
+
+impl Session {
+  fn validate(&self) -> Result<(), String> {
+    if self.cookie_name.is_empty()
+      || !self
+        .cookie_name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+    {
+      return Err(format!(
+        "session.cookie_name '{}' is not a valid cookie name",
+        self.cookie_name
+      ));
+    }
+    if !self.path.starts_with('/') {
+      return Err(format!("session.path '{}' must start with '/'", self.path));
+    }
+    if self.same_site == SameSite::None && !self.secure {
+      return Err("session.same_site = \"none\" requires session.secure = true".to_string());
+    }
+    match self.expiry {
+      SessionExpiry::OnInactivity if !self.duration.is_positive() => {
+        return Err("session.duration must be positive".to_string());
+      }
+      SessionExpiry::AtDate => match self.expires_at {
+        None => {
+          return Err(
+            "session.expires_at is required when session.expiry = \"at-date\"".to_string(),
+          );
+        }
+        Some(expires_at) if expires_at <= Timestamp::now() => {
+          return Err(format!("session.expires_at {expires_at} is in the past"));
+        }
+        Some(_) => {}
+      },
+      _ => {}
+    }
+    if !self.deletion_interval.is_positive() {
+      return Err("session.deletion_interval must be positive".to_string());
+    }
+    if !is_valid_identifier(&self.schema_name) {
+      return Err(format!(
+        "session.schema_name '{}' is not a valid Postgres identifier",
+        self.schema_name
+      ));
+    }
+    if !is_valid_identifier(&self.table_name) {
+      return Err(format!(
+        "session.table_name '{}' is not a valid Postgres identifier",
+        self.table_name
+      ));
+    }
+    Ok(())
+  }
+
+  /// The session expiry to configure the session layer with.
+  pub fn tower_expiry(&self) -> tower_sessions::Expiry {
+    match self.expiry {
+      SessionExpiry::OnInactivity => tower_sessions::Expiry::OnInactivity(time::Duration::new(
+        self.duration.as_secs(),
+        self.duration.subsec_nanos(),
+      )),
+      SessionExpiry::AtDate => {
+        let expires_at = self.expires_at.expect("validated at load time");
+        tower_sessions::Expiry::AtDateTime(
+          time::OffsetDateTime::from_unix_timestamp_nanos(expires_at.as_nanosecond())
+            .expect("jiff timestamps fit in OffsetDateTime"),
+        )
+      }
+      SessionExpiry::BrowserSession => tower_sessions::Expiry::OnSessionEnd,
+    }
+  }
+
+  /// How often expired sessions are purged from the store.
+  pub fn deletion_interval(&self) -> std::time::Duration {
+    self.deletion_interval.unsigned_abs()
+  }
+}
+
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct Security {
+  // Strict-Transport-Security
+  /// How many seconds browsers should only reach this host over HTTPS.
+  #[config(default = 15552000)]
+  pub hsts_max_age: u32,
+  #[config(default = true)]
+  pub hsts_include_subdomains: bool,
+  #[config(default = false)]
+  pub hsts_preload: bool,
+
+  // Framing, referrers and cross-origin isolation
+  #[config(default = "same-origin")]
+  pub frame_options: FrameOptions,
+  #[config(default = "no-referrer")]
+  pub referrer_policy: ReferrerPolicy,
+  #[config(default = "same-origin")]
+  pub cross_origin_opener_policy: CrossOriginOpenerPolicy,
+  #[config(default = "same-origin")]
+  pub cross_origin_resource_policy: CrossOriginResourcePolicy,
+
+  #[config(nested)]
+  pub csp: Csp,
+  /// CSP directives replaced for requests under a path prefix, keyed by
+  /// prefix and then directive name, e.g. `[security.routes."/embed"]` with
+  /// `frame-ancestors = ["https://partner.example"]`. The longest matching
+  /// prefix wins.
+  #[config(default = {})]
+  pub routes: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+/// Content Security Policy sources. Empty directives are left out, so they
+/// fall back to `default_src`. Inline scripts are allowed through a
+/// per-request nonce, which is always added to `script_src`.
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct Csp {
+  #[config(default = ["'self'"])]
+  pub default_src: Vec<String>,
+  #[config(default = ["'self'"])]
+  pub base_uri: Vec<String>,
+  #[config(default = [])]
+  pub connect_src: Vec<String>,
+  #[config(default = ["'self'", "https:", "data:"])]
+  pub font_src: Vec<String>,
+  #[config(default = ["'self'"])]
+  pub form_action: Vec<String>,
+  #[config(default = ["'self'"])]
+  pub frame_ancestors: Vec<String>,
+  #[config(default = [])]
+  pub frame_src: Vec<String>,
+  #[config(default = ["'self'", "data:"])]
+  pub img_src: Vec<String>,
+  #[config(default = [])]
+  pub manifest_src: Vec<String>,
+  #[config(default = [])]
+  pub media_src: Vec<String>,
+  #[config(default = ["'none'"])]
+  pub object_src: Vec<String>,
+  #[config(default = ["'self'"])]
+  pub script_src: Vec<String>,
+  #[config(default = ["'none'"])]
+  pub script_src_attr: Vec<String>,
+  #[config(default = ["'self'", "https:", "'unsafe-inline'"])]
+  pub style_src: Vec<String>,
+  #[config(default = [])]
+  pub worker_src: Vec<String>,
+  #[config(default = true)]
+  pub upgrade_insecure_requests: bool,
+  /// Send `Content-Security-Policy-Report-Only` instead, to trial a policy
+  /// without enforcing it.
+  #[config(default = false)]
+  pub report_only: bool,
+}
+
+/// The `X-Frame-Options` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrameOptions {
+  Deny,
+  SameOrigin,
+}
+
+/// The `Referrer-Policy` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReferrerPolicy {
+  NoReferrer,
+  NoReferrerWhenDowngrade,
+  Origin,
+  OriginWhenCrossOrigin,
+  SameOrigin,
+  StrictOrigin,
+  StrictOriginWhenCrossOrigin,
+  UnsafeUrl,
+}
+
+/// The `Cross-Origin-Opener-Policy` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrossOriginOpenerPolicy {
+  SameOrigin,
+  SameOriginAllowPopups,
+  UnsafeNone,
+}
+
+/// The `Cross-Origin-Resource-Policy` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrossOriginResourcePolicy {
+  SameOrigin,
+  SameSite,
+  CrossOrigin,
+}
+
+impl Security {
+  fn validate(&self) -> Result<(), String> {
+    if self.hsts_preload && !(self.hsts_include_subdomains && self.hsts_max_age >= 31536000) {
+      return Err(
+        "security.hsts_preload requires hsts_include_subdomains and an hsts_max_age of at least \
+         one year"
+          .to_string(),
+      );
+    }
+    for (prefix, directives) in &self.routes {
+      if !prefix.starts_with('/') {
+        return Err(format!("security.routes '{prefix}' must start with '/'"));
+      }
+      for (directive, sources) in directives {
+        csp::validate_directive(directive, sources)
+          .map_err(|e| format!("security.routes '{prefix}': {e}"))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// The non-CSP security headers sent with every response. The CSP carries
+  /// a per-request nonce, so `crate::csp` sends it instead.
+  pub fn helmet(&self) -> axum_helmet::Helmet {
+    use axum_helmet as h;
+
+    let mut hsts = h::StrictTransportSecurity::new().max_age(self.hsts_max_age);
+    if self.hsts_include_subdomains {
+      hsts = hsts.include_sub_domains();
+    }
+    if self.hsts_preload {
+      hsts = hsts.preload();
+    }
+
+    let frame_options = match self.frame_options {
+      FrameOptions::Deny => h::XFrameOptions::deny(),
+      FrameOptions::SameOrigin => h::XFrameOptions::same_origin(),
+    };
+    let referrer_policy = match self.referrer_policy {
+      ReferrerPolicy::NoReferrer => h::ReferrerPolicy::no_referrer(),
+      ReferrerPolicy::NoReferrerWhenDowngrade => h::ReferrerPolicy::no_referrer_when_downgrade(),
+      ReferrerPolicy::Origin => h::ReferrerPolicy::origin(),
+      ReferrerPolicy::OriginWhenCrossOrigin => h::ReferrerPolicy::origin_when_cross_origin(),
+      ReferrerPolicy::SameOrigin => h::ReferrerPolicy::same_origin(),
+      ReferrerPolicy::StrictOrigin => h::ReferrerPolicy::strict_origin(),
+      ReferrerPolicy::StrictOriginWhenCrossOrigin => {
+        h::ReferrerPolicy::strict_origin_when_cross_origin()
+      }
+      ReferrerPolicy::UnsafeUrl => h::ReferrerPolicy::unsafe_url(),
+    };
+    let opener_policy = match self.cross_origin_opener_policy {
+      CrossOriginOpenerPolicy::SameOrigin => h::CrossOriginOpenerPolicy::same_origin(),
+      CrossOriginOpenerPolicy::SameOriginAllowPopups => {
+        h::CrossOriginOpenerPolicy::same_origin_allow_popups()
+      }
+      CrossOriginOpenerPolicy::UnsafeNone => h::CrossOriginOpenerPolicy::unsafe_none(),
+    };
+    let resource_policy = match self.cross_origin_resource_policy {
+      CrossOriginResourcePolicy::SameOrigin => h::CrossOriginResourcePolicy::same_origin(),
+      CrossOriginResourcePolicy::SameSite => h::CrossOriginResourcePolicy::same_site(),
+      CrossOriginResourcePolicy::CrossOrigin => h::CrossOriginResourcePolicy::cross_origin(),
+    };
+
+    h::Helmet::new()
+      .add(opener_policy)
+      .add(resource_policy)
+      .add(h::OriginAgentCluster::new(true))
+      .add(referrer_policy)
+      .add(hsts)
+      .add(h::XContentTypeOptions::nosniff())
+      .add(h::XDNSPrefetchControl::off())
+      .add(h::XDownloadOptions::noopen())
+      .add(frame_options)
+      .add(h::XPermittedCrossDomainPolicies::none())
+      .add(h::XXSSProtection::off())
+  }
+}
+
+impl Csp {
+  fn validate(&self) -> Result<(), String> {
+    for (directive, sources) in self.directives() {
+      csp::validate_directive(directive, sources).map_err(|e| format!("security.csp: {e}"))?;
+    }
+    Ok(())
+  }
+
+  /// The configured directives by name, in policy order, including empty
+  /// ones.
+  pub fn directives(&self) -> [(&'static str, &Vec<String>); 15] {
+    [
+      ("default-src", &self.default_src),
+      ("base-uri", &self.base_uri),
+      ("connect-src", &self.connect_src),
+      ("font-src", &self.font_src),
+      ("form-action", &self.form_action),
+      ("frame-ancestors", &self.frame_ancestors),
+      ("frame-src", &self.frame_src),
+      ("img-src", &self.img_src),
+      ("manifest-src", &self.manifest_src),
+      ("media-src", &self.media_src),
+      ("object-src", &self.object_src),
+      ("script-src", &self.script_src),
+      ("script-src-attr", &self.script_src_attr),
+      ("style-src", &self.style_src),
+      ("worker-src", &self.worker_src),
+    ]
+  }
+}
+
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct RateLimit {
+  /// Where token buckets are kept. `postgres` shares them between replicas.
+  #[config(default = "memory")]
+  pub backend: RateLimitBackend,
+  /// Token-bucket policies keyed by path prefix, e.g. `[rate_limit.routes."/login"]`
+  /// with `limit = 5`, `period = "15m"` and `methods = ["POST"]`. The longest
+  /// matching prefix whose methods include the request's wins; requests no
+  /// policy matches are not limited.
+  #[config(default = { "/api": { "limit": 120, "period": 60 } })]
+  pub routes: BTreeMap<String, RateLimitPolicy>,
+  /// How often buckets that have refilled are forgotten.
+  #[config(default = "60s")]
+  pub prune_interval: SignedDuration,
+  #[config(default = "rate_limit")]
+  pub schema_name: String,
+  #[config(default = "bucket")]
+  pub table_name: String,
+}
+
+/// Where rate limit buckets are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+  /// In process memory, so every replica limits on its own.
+  Memory,
+  /// In a Postgres table shared by all replicas.
+  Postgres,
+}
+
+/// A token bucket holding `limit` requests that refills completely over
+/// `period`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitPolicy {
+  pub limit: u32,
+  #[serde(deserialize_with = "duration_or_seconds")]
+  pub period: SignedDuration,
+  #[serde(default)]
+  pub key: RateLimitKey,
+  /// The methods the policy applies to. Empty means all of them.
+  #[serde(default)]
+  pub methods: Vec<String>,
+}
+
+/// What requests are counted by. Every request is counted against the
+/// client's network; the other keys add a bucket of their own on top.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitKey {
+  /// The client IP address, or its /64 for IPv6. Requests over the Unix
+  /// socket count as `127.0.0.1` and share a bucket unless a trusted proxy
+  /// forwards the client address.
+  #[default]
+  Ip,
+  /// The `X-API-Key` header, wherever it's sent from.
+  ApiKey,
+  /// The user's session, once the session store knows it.
+  User,
+}
+
+/// Accepts a duration like `"15m"` or a whole number of seconds. Config
+/// defaults can only nest values of one type, so the built-in policies give
+/// their periods in seconds.
+fn duration_or_seconds<'de, D>(deserializer: D) -> Result<SignedDuration, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Period {
+    Seconds(i64),
+    Duration(SignedDuration),
+  }
+
+  Ok(match Period::deserialize(deserializer)? {
+    Period::Seconds(seconds) => SignedDuration::from_secs(seconds),
+    Period::Duration(duration) => duration,
+  })
+}
+
+impl RateLimit {
+  fn validate(&self) -> Result<(), String> {
+    for (prefix, policy) in &self.routes {
+      rate_limit::validate_policy(prefix, policy)
+        .map_err(|e| format!("rate_limit.routes '{prefix}': {e}"))?;
+    }
+    if !self.prune_interval.is_positive() {
+      return Err("rate_limit.prune_interval must be positive".to_string());
+    }
+    if !is_valid_identifier(&self.schema_name) {
+      return Err(format!(
+        "rate_limit.schema_name '{}' is not a valid Postgres identifier",
+        self.schema_name
+      ));
+    }
+    if !is_valid_identifier(&self.table_name) {
+      return Err(format!(
+        "rate_limit.table_name '{}' is not a valid Postgres identifier",
+        self.table_name
+      ));
+    }
+    Ok(())
+  }
+
+  /// How often buckets that have refilled are pruned from the store.
+  pub fn prune_interval(&self) -> std::time::Duration {
+    self.prune_interval.unsigned_abs()
+  }
+}
+
+/// Certificate ordering when TLS is on and no `server.tls_cert` is given.
+/// The domains and contacts are `server.domains` and `server.email`.
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct Acme {
+  /// Where ACME accounts and certificates are kept: `dir`, in `server.cache`,
+  /// or `postgres`, shared by every replica.
+  #[config(default = "dir")]
+  pub backend: AcmeBackend,
+  /// The ACME directory URL. Defaults to Let's Encrypt, staging unless
+  /// `server.production` is set; point it at Pebble to test locally.
+  pub directory: Option<String>,
+  /// PEM CA certificates trusted for the directory instead of the Web PKI
+  /// roots, e.g. Pebble's `pebble.minica.pem`.
+  pub ca_cert: Option<PathBuf>,
+  #[config(default = "acme")]
+  pub schema_name: String,
+  #[config(default = "cache")]
+  pub table_name: String,
+}
+
+/// Where ACME accounts and certificates are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AcmeBackend {
+  /// In `server.cache`, or nowhere when that is unset.
+  Dir,
+  /// In a Postgres table shared by all replicas.
+  Postgres,
+}
+
+/// Whether TLS clients are asked for a certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+  /// Clients aren't asked.
+  #[default]
+  Off,
+  /// Clients may present a certificate, which must then verify; those that
+  /// don't are let through without one.
+  Optional,
+  /// Clients must present a certificate that verifies.
+  Required,
+}
+
+impl Acme {
+  fn validate(&self) -> Result<(), String> {
+    if let Some(directory) = &self.directory {
+      let uri = directory
+        .parse::<http::Uri>()
+        .map_err(|e| format!("acme.directory '{directory}': {e}"))?;
+      if uri.scheme() != Some(&http::uri::Scheme::HTTPS) {
+        return Err(format!("acme.directory '{directory}' must be an https URL"));
+      }
+    }
+    if !is_valid_identifier(&self.schema_name) {
+      return Err(format!(
+        "acme.schema_name '{}' is not a valid Postgres identifier",
+        self.schema_name
+      ));
+    }
+    if !is_valid_identifier(&self.table_name) {
+      return Err(format!(
+        "acme.table_name '{}' is not a valid Postgres identifier",
+        self.table_name
+      ));
+    }
+    Ok(())
+  }
+}
+
+/// How the server shuts down on Ctrl+C or SIGTERM.
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct Shutdown {
+  /// How long open connections get to finish before they are closed.
+  #[config(default = "10s")]
+  pub drain_timeout: SignedDuration,
+  /// How long the whole shutdown may take before the process exits anyway.
+  #[config(default = "30s")]
+  pub exit_timeout: SignedDuration,
+}
+
+impl Shutdown {
+  fn validate(&self) -> Result<(), String> {
+    if !self.drain_timeout.is_positive() {
+      return Err("shutdown.drain_timeout must be positive".to_string());
+    }
+    if self.exit_timeout <= self.drain_timeout {
+      return Err("shutdown.exit_timeout must be longer than drain_timeout".to_string());
+    }
+    Ok(())
+  }
+
+  pub fn drain_timeout(&self) -> std::time::Duration {
+    self.drain_timeout.unsigned_abs()
+  }
+
+  pub fn exit_timeout(&self) -> std::time::Duration {
+    self.exit_timeout.unsigned_abs()
+  }
+}
+
+/// The background job queue, see `crate::jobs`.
+#[derive(confique::Config, Debug, Clone)]
+#[config(validate = Self::validate)]
+pub struct Jobs {
+  /// How many jobs each replica runs at once.
+  #[config(default = 4)]
+  pub concurrency: usize,
+  /// How often the queue is checked between notifications, which is what
+  /// picks up jobs due later and retries.
+  #[config(default = "5s")]
+  pub poll_interval: SignedDuration,
+  /// How long a job may run before it's considered abandoned and run again.
+  #[config(default = "5m")]
+  pub lease: SignedDuration,
+  /// How many times a job runs before it's moved to the dead letters.
+  #[config(default = 5)]
+  pub max_attempts: u32,
+  /// The delay before the first retry, doubled for every one after it.
+  #[config(default = "10s")]
+  pub retry_backoff: SignedDuration,
+  #[config(default = "1h")]
+  pub max_retry_backoff: SignedDuration,
+  #[config(default = "jobs")]
+  pub schema_name: String,
+}
+
+impl Jobs {
+  fn validate(&self) -> Result<(), String> {
+    if self.concurrency == 0 {
+      return Err("jobs.concurrency must be at least 1".to_string());
+    }
+    if self.max_attempts == 0 {
+      return Err("jobs.max_attempts must be at least 1".to_string());
+    }
+    for (name, duration) in [
+      ("poll_interval", self.poll_interval),
+      ("lease", self.lease),
+      ("retry_backoff", self.retry_backoff),
+    ] {
+      if !duration.is_positive() {
+        return Err(format!("jobs.{name} must be positive"));
+      }
+    }
+    if self.max_retry_backoff < self.retry_backoff {
+      return Err("jobs.max_retry_backoff must be at least retry_backoff".to_string());
+    }
+    if !is_valid_identifier(&self.schema_name) {
+      return Err(format!(
+        "jobs.schema_name '{}' is not a valid Postgres identifier",
+        self.schema_name
+      ));
+    }
+    Ok(())
+  }
+
+  pub fn poll_interval(&self) -> std::time::Duration {
+    self.poll_interval.unsigned_abs()
+  }
+
+  pub fn lease(&self) -> std::time::Duration {
+    self.lease.unsigned_abs()
+  }
+
+  pub fn retry_backoff(&self) -> std::time::Duration {
+    self.retry_backoff.unsigned_abs()
+  }
+
+  pub fn max_retry_backoff(&self) -> std::time::Duration {
+    self.max_retry_backoff.unsigned_abs()
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -186,6 +1070,17 @@ mod tests {
     assert_eq!(cfg.server.https_port, 8443);
     assert_eq!(cfg.server.monitoring_port, 9090);
     assert!(!cfg.server.tls_enabled);
+    assert_eq!(cfg.session.cookie_name, "id");
+    assert!(cfg.session.secure);
+    assert_eq!(cfg.session.same_site, SameSite::Lax);
+    assert_eq!(cfg.session.expiry, SessionExpiry::BrowserSession);
+    assert_eq!(cfg.session.duration, SignedDuration::from_hours(24));
+    assert_eq!(
+      cfg.session.deletion_interval(),
+      std::time::Duration::from_secs(60)
+    );
+    assert_eq!(cfg.session.schema_name, "tower_sessions");
+    assert_eq!(cfg.session.table_name, "session");
   }
 
   #[test]
@@ -208,6 +1103,205 @@ tls_enabled = true
     assert!(cfg.server.tls_enabled);
   }
 
+  #[test]
+  fn trusted_proxies_from_file() {
+    let _g = env_lock();
+    let path = write_temp_toml(
+      r#"[server]
+trusted_proxies = ["10.0.0.0/8", "::1"]
+"#,
+    );
+
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(cfg.server.forwarded_headers, ForwardedHeaders::XForwarded);
+    assert_eq!(
+      cfg.server.trusted_proxies(),
+      [
+        "10.0.0.0/8".parse::<ipnet::IpNet>().unwrap(),
+        "::1/128".parse().unwrap()
+      ]
+    );
+
+    let path = write_temp_toml("[server]\nforwarded_headers = \"forwarded\"\n");
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(cfg.server.forwarded_headers, ForwardedHeaders::Forwarded);
+
+    let path = write_temp_toml("[server]\ntrusted_proxies = [\"10.0.0.0/40\"]\n");
+    let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn bind_addresses_default_to_ports_and_must_not_collide() {
+    let _g = env_lock();
+    let cfg = with_test_env(|| AppConfig::builder().env().load().unwrap());
+    assert_eq!(cfg.server.http_addrs(), ["0.0.0.0:8080".parse().unwrap()]);
+    assert_eq!(cfg.server.public_https_port(), 8443);
+
+    let path = write_temp_toml(
+      r#"[server]
+http_bind = ["0.0.0.0:8080", "[::]:8080"]
+monitoring_bind = ["127.0.0.1:9090"]
+unix_socket = "/run/app/http.sock"
+"#,
+    );
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(cfg.server.http_addrs().len(), 2);
+    assert_eq!(
+      cfg.server.monitoring_addrs(),
+      ["127.0.0.1:9090".parse().unwrap()]
+    );
+
+    // HTTPS only binds when TLS is on.
+    let path = write_temp_toml("[server]\nhttps_port = 8080\n");
+    assert!(with_test_env(|| AppConfig::builder().env().file(&path).load()).is_ok());
+    let path = write_temp_toml("[server]\nhttps_port = 8080\ntls_enabled = true\n");
+    assert!(with_test_env(|| AppConfig::builder().env().file(&path).load()).is_err());
+
+    let path = write_temp_toml("[server]\nmonitoring_bind = [\"127.0.0.1:8080\"]\n");
+    assert!(with_test_env(|| AppConfig::builder().env().file(&path).load()).is_err());
+  }
+
+  #[test]
+  fn acme_directory_must_be_https() {
+    let _g = env_lock();
+    let path = write_temp_toml(
+      r#"[acme]
+backend = "postgres"
+directory = "https://localhost:14000/dir"
+"#,
+    );
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(cfg.acme.backend, AcmeBackend::Postgres);
+    assert_eq!(cfg.acme.schema_name, "acme");
+
+    let path = write_temp_toml("[acme]\ndirectory = \"http://localhost:14000/dir\"\n");
+    assert!(with_test_env(|| AppConfig::builder().env().file(&path).load()).is_err());
+  }
+
+  #[test]
+  fn shutdown_timeouts() {
+    let _g = env_lock();
+    let cfg = with_test_env(|| AppConfig::builder().env().load().unwrap());
+    assert_eq!(
+      cfg.shutdown.drain_timeout(),
+      std::time::Duration::from_secs(10)
+    );
+    assert_eq!(
+      cfg.shutdown.exit_timeout(),
+      std::time::Duration::from_secs(30)
+    );
+
+    for section in [
+      "drain_timeout = \"0s\"",
+      "drain_timeout = \"1m\"\nexit_timeout = \"30s\"",
+    ] {
+      let path = write_temp_toml(&format!("[shutdown]\n{section}\n"));
+      let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+      assert!(result.is_err(), "{section}");
+    }
+  }
+
+  #[test]
+  fn job_queue_settings() {
+    let _g = env_lock();
+    let cfg = with_test_env(|| AppConfig::builder().env().load().unwrap());
+    assert_eq!(cfg.jobs.concurrency, 4);
+    assert_eq!(cfg.jobs.max_attempts, 5);
+    assert_eq!(cfg.jobs.lease(), std::time::Duration::from_secs(300));
+    assert_eq!(
+      cfg.jobs.max_retry_backoff(),
+      std::time::Duration::from_secs(3600)
+    );
+    assert_eq!(cfg.jobs.schema_name, "jobs");
+
+    for section in [
+      "concurrency = 0",
+      "max_attempts = 0",
+      "poll_interval = \"0s\"",
+      "retry_backoff = \"1m\"\nmax_retry_backoff = \"30s\"",
+      "schema_name = \"bad name\"",
+    ] {
+      let path = write_temp_toml(&format!("[jobs]\n{section}\n"));
+      let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+      assert!(result.is_err(), "{section}");
+    }
+  }
+
+  #[test]
+  fn host_certificates_from_file() {
+    let _g = env_lock();
+    let path = write_temp_toml(
+      r#"[server]
+domains = ["example.com"]
+
+[[server.certificates]]
+domains = ["example.org", "*.example.org"]
+cert = "/etc/ssl/example.org.pem"
+key = "/etc/ssl/example.org.key"
+"#,
+    );
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(
+      cfg.server.certificates,
+      [HostCertificate {
+        domains: vec!["example.org".into(), "*.example.org".into()],
+        cert: "/etc/ssl/example.org.pem".into(),
+        key: "/etc/ssl/example.org.key".into(),
+      }]
+    );
+
+    for entries in [
+      // Already ordered through ACME.
+      r#"domains = ["example.com"]"#,
+      r#"domains = ["a.example.net", "A.example.net"]"#,
+      r#"domains = ["*.*.example.net"]"#,
+      r#"domains = []"#,
+    ] {
+      let path = write_temp_toml(&format!(
+        "[server]\ndomains = [\"example.com\"]\n\n[[server.certificates]]\n{entries}\ncert = \"c.pem\"\nkey = \"k.pem\"\n"
+      ));
+      let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+      assert!(result.is_err(), "{entries}");
+    }
+  }
+
+  #[test]
+  fn client_auth_needs_a_ca() {
+    let _g = env_lock();
+    let cfg = with_test_env(|| AppConfig::builder().env().load().unwrap());
+    assert_eq!(cfg.server.client_auth, ClientAuth::Off);
+
+    let path = write_temp_toml(
+      r#"[server]
+client_auth = "required"
+client_ca = "/etc/ssl/clients.pem"
+client_crls = ["/etc/ssl/clients.crl"]
+"#,
+    );
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(cfg.server.client_auth, ClientAuth::Required);
+    assert_eq!(cfg.server.client_crls.len(), 1);
+
+    let path = write_temp_toml("[server]\nclient_auth = \"optional\"\n");
+    assert!(with_test_env(|| AppConfig::builder().env().file(&path).load()).is_err());
+  }
+
+  #[test]
+  fn canonical_host_is_validated() {
+    let _g = env_lock();
+    let path = write_temp_toml("[server]\ncanonical_host = \"www.example.com\"\n");
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(
+      cfg.server.canonical_host.as_deref(),
+      Some("www.example.com")
+    );
+
+    let path = write_temp_toml("[server]\ncanonical_host = \"www.example.com:443\"\n");
+    let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+    assert!(result.is_err());
+  }
+
   #[test]
   fn env_overrides_files() {
     let _g = env_lock();
@@ -247,4 +1341,185 @@ http_port = 18080
       env::remove_var("{{project-name | shouty_snake_case}}_HTTP_PORT");
     }
   }
+
+  #[test]
+  fn session_section_from_file() {
+    let _g = env_lock();
+    let path = write_temp_toml(
+      r#"[session]
+cookie_name = "sid"
+domain = "example.com"
+same_site = "strict"
+expiry = "on-inactivity"
+duration = "2h"
+deletion_interval = "5m"
+schema_name = "app"
+table_name = "sessions"
+"#,
+    );
+
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+
+    assert_eq!(cfg.session.cookie_name, "sid");
+    assert_eq!(cfg.session.domain.as_deref(), Some("example.com"));
+    assert_eq!(cfg.session.same_site, SameSite::Strict);
+    assert_eq!(cfg.session.expiry, SessionExpiry::OnInactivity);
+    assert_eq!(cfg.session.duration, SignedDuration::from_hours(2));
+    assert_eq!(
+      cfg.session.deletion_interval(),
+      std::time::Duration::from_secs(300)
+    );
+    assert_eq!(cfg.session.schema_name, "app");
+    assert_eq!(cfg.session.table_name, "sessions");
+  }
+
+  #[test]
+  fn invalid_session_section_is_rejected() {
+    let _g = env_lock();
+    for section in [
+      r#"cookie_name = "bad name""#,
+      r#"path = "relative""#,
+      "same_site = \"none\"\nsecure = false",
+      "expiry = \"on-inactivity\"\nduration = \"0s\"",
+      r#"expiry = "at-date""#,
+      "expiry = \"at-date\"\nexpires_at = \"2020-01-01T00:00:00Z\"",
+      r#"deletion_interval = "0s""#,
+      r#"schema_name = "1bad""#,
+      r#"table_name = "bad-name""#,
+    ] {
+      let path = write_temp_toml(&format!("[session]\n{section}\n"));
+      let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+      assert!(result.is_err(), "expected {section:?} to be rejected");
+    }
+  }
+
+  #[test]
+  fn asset_content_types_from_file() {
+    let _g = env_lock();
+    let path = write_temp_toml(
+      r#"[assets.content_types]
+".well-known/apple-app-site-association" = "application/json"
+"#,
+    );
+
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+    assert_eq!(
+      cfg.assets.content_types()[".well-known/apple-app-site-association"],
+      mime_guess::mime::APPLICATION_JSON
+    );
+
+    let path = write_temp_toml(
+      "[assets.content_types]
+robots = \"not a type\"\n",
+    );
+    let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn security_section_from_file() {
+    let _g = env_lock();
+    let path = write_temp_toml(
+      r#"[security]
+hsts_max_age = 63072000
+hsts_preload = true
+frame_options = "deny"
+referrer_policy = "strict-origin-when-cross-origin"
+
+[security.csp]
+img_src = ["'self'", "https://cdn.example.com"]
+
+[security.routes."/embed"]
+frame-ancestors = ["https://partner.example"]
+"#,
+    );
+
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+
+    assert_eq!(cfg.security.hsts_max_age, 63072000);
+    assert_eq!(cfg.security.frame_options, FrameOptions::Deny);
+    assert_eq!(
+      cfg.security.referrer_policy,
+      ReferrerPolicy::StrictOriginWhenCrossOrigin
+    );
+    assert_eq!(
+      cfg.security.csp.img_src,
+      ["'self'", "https://cdn.example.com"]
+    );
+    // Directives not mentioned keep their defaults.
+    assert_eq!(cfg.security.csp.object_src, ["'none'"]);
+    assert_eq!(
+      cfg.security.routes["/embed"]["frame-ancestors"],
+      ["https://partner.example"]
+    );
+  }
+
+  #[test]
+  fn invalid_security_section_is_rejected() {
+    let _g = env_lock();
+    for section in [
+      "[security]\nhsts_preload = true\nhsts_max_age = 600",
+      "[security]\nframe_options = \"allow\"",
+      "[security.csp]\nimg_src = [\"self\"]",
+      "[security.csp]\nscript_src = [\"'self'; object-src *\"]",
+      "[security.csp]\nobject_src = [\"'none'\", \"'self'\"]",
+      "[security.routes.\"/embed\"]\nframe-ancestor = [\"'self'\"]",
+      "[security.routes.\"embed\"]\nframe-ancestors = [\"'self'\"]",
+    ] {
+      let path = write_temp_toml(&format!("{section}\n"));
+      let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+      assert!(result.is_err(), "expected {section:?} to be rejected");
+    }
+  }
+
+  #[test]
+  fn rate_limit_section_from_file() {
+    let _g = env_lock();
+    let cfg = with_test_env(|| AppConfig::builder().env().load().unwrap());
+    assert_eq!(cfg.rate_limit.backend, RateLimitBackend::Memory);
+    assert_eq!(
+      cfg.rate_limit.routes["/api"].period,
+      SignedDuration::from_mins(1)
+    );
+
+    let path = write_temp_toml(
+      r#"[rate_limit]
+backend = "postgres"
+
+[rate_limit.routes."/login"]
+limit = 5
+period = "15m"
+methods = ["POST"]
+"#,
+    );
+
+    let cfg = with_test_env(|| AppConfig::builder().env().file(&path).load().unwrap());
+
+    assert_eq!(cfg.rate_limit.backend, RateLimitBackend::Postgres);
+    assert_eq!(cfg.rate_limit.routes.keys().collect::<Vec<_>>(), ["/login"]);
+    let login = &cfg.rate_limit.routes["/login"];
+    assert_eq!(login.limit, 5);
+    assert_eq!(login.period, SignedDuration::from_mins(15));
+    assert_eq!(login.key, RateLimitKey::Ip);
+    assert_eq!(login.methods, ["POST"]);
+  }
+
+  #[test]
+  fn invalid_rate_limit_section_is_rejected() {
+    let _g = env_lock();
+    for section in [
+      "[rate_limit]\nbackend = \"redis\"",
+      "[rate_limit]\nprune_interval = \"0s\"",
+      "[rate_limit]\nschema_name = \"1bad\"",
+      "[rate_limit.routes.\"login\"]\nlimit = 5\nperiod = \"1m\"",
+      "[rate_limit.routes.\"/login\"]\nlimit = 0\nperiod = \"1m\"",
+      "[rate_limit.routes.\"/login\"]\nlimit = 5\nperiod = \"0s\"",
+      "[rate_limit.routes.\"/login\"]\nlimit = 5\nperiod = \"1m\"\nmethods = [\"P OST\"]",
+      "[rate_limit.routes.\"/login\"]\nlimit = 5\nperiod = \"1m\"\nkey = \"cookie\"",
+    ] {
+      let path = write_temp_toml(&format!("{section}\n"));
+      let result = with_test_env(|| AppConfig::builder().env().file(&path).load());
+      assert!(result.is_err(), "expected {section:?} to be rejected");
+    }
+  }
 }
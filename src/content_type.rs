@@ -0,0 +1,136 @@
+//! Media type detection for static assets.
+//!
+//! `build.rs` compiles this module too, so embedded assets are classified and
+//! compressed exactly like the ones loaded from disk at runtime.
+
+use mime_guess::{Mime, mime};
+
+/// Assets smaller than this many bytes are not worth compressing: the savings
+/// are lost in framing overhead.
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Determines the media type of an asset from its file extension, falling back
+/// to sniffing its contents when the extension is missing or unknown.
+pub fn detect(filename: &str, bytes: &[u8]) -> Mime {
+  let has_extension = filename
+    .rsplit('/')
+    .next()
+    .is_some_and(|name| name.contains('.'));
+
+  has_extension
+    .then(|| mime_guess::from_path(filename).first())
+    .flatten()
+    .unwrap_or_else(|| sniff(bytes))
+}
+
+/// Guesses a media type from file contents: magic numbers for binary formats,
+/// then a few textual heuristics. Anything else that isn't UTF-8 text is an
+/// opaque `application/octet-stream`.
+pub fn sniff(bytes: &[u8]) -> Mime {
+  if let Some(mime) = infer::get(bytes).and_then(|kind| kind.mime_type().parse().ok()) {
+    return mime;
+  }
+
+  let Ok(text) = std::str::from_utf8(bytes) else {
+    return mime::APPLICATION_OCTET_STREAM;
+  };
+  if text.contains('\0') {
+    return mime::APPLICATION_OCTET_STREAM;
+  }
+
+  let text = text.trim_start_matches('\u{feff}').trim_start();
+  if text.starts_with('{') || text.starts_with('[') {
+    mime::APPLICATION_JSON
+  } else if text.starts_with("<svg") {
+    mime::IMAGE_SVG
+  } else {
+    mime::TEXT_PLAIN
+  }
+}
+
+/// Returns the charset to advertise for `mime`. Only textual types get one,
+/// and only when the contents really are UTF-8.
+pub fn charset(mime: &Mime, bytes: &[u8]) -> Option<&'static str> {
+  let textual = mime.type_() == mime::TEXT
+    || matches!(mime.suffix(), Some(suffix) if suffix == mime::JSON || suffix == mime::XML)
+    || matches!(
+      (mime.type_(), mime.subtype().as_str()),
+      (mime::APPLICATION, "javascript" | "json" | "xml")
+    );
+
+  (textual && std::str::from_utf8(bytes).is_ok()).then_some("utf-8")
+}
+
+/// Whether compressing `mime` is likely to pay off. Formats that are already
+/// compressed (most images, WOFF fonts, archives, audio and video) are not.
+pub fn is_compressible(mime: &Mime) -> bool {
+  if mime.type_() == mime::TEXT
+    || matches!(mime.suffix(), Some(suffix) if suffix == mime::JSON || suffix == mime::XML)
+  {
+    return true;
+  }
+
+  matches!(
+    (mime.type_().as_str(), mime.subtype().as_str()),
+    (
+      "application",
+      "javascript" | "json" | "xml" | "wasm" | "x-font-ttf" | "x-font-otf" | "vnd.ms-fontobject"
+    ) | ("image", "svg" | "bmp" | "x-icon" | "vnd.microsoft.icon")
+      | ("font", "ttf" | "otf" | "sfnt" | "collection")
+  )
+}
+
+/// Whether an asset of type `mime` and `len` bytes should get precompressed
+/// variants.
+pub fn should_compress(mime: &Mime, len: usize) -> bool {
+  len >= MIN_COMPRESS_SIZE && is_compressible(mime)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn detect_prefers_extension_then_sniffs() {
+    assert_eq!(detect("css/main.css", b"body{}"), mime::TEXT_CSS);
+    assert_eq!(detect("robots", b"User-agent: *"), mime::TEXT_PLAIN);
+    assert_eq!(
+      detect(
+        ".well-known/apple-app-site-association",
+        br#" {"applinks":{}}"#
+      ),
+      mime::APPLICATION_JSON
+    );
+    assert_eq!(
+      detect("data.unknownext", b"\0asm\x01\0\0\0").as_ref(),
+      "application/wasm"
+    );
+    assert_eq!(
+      detect("blob", &[0xff, 0xfe, 0x00, 0x01]),
+      mime::APPLICATION_OCTET_STREAM
+    );
+  }
+
+  #[test]
+  fn charset_only_for_utf8_text() {
+    assert_eq!(charset(&mime::TEXT_CSS, b"body{}"), Some("utf-8"));
+    assert_eq!(charset(&mime::IMAGE_SVG, b"<svg/>"), Some("utf-8"));
+    assert_eq!(
+      charset(&"application/manifest+json".parse().unwrap(), b"{}"),
+      Some("utf-8")
+    );
+    assert_eq!(charset(&mime::TEXT_PLAIN, &[0xff, 0xfe]), None);
+    assert_eq!(charset(&mime::IMAGE_PNG, b"png"), None);
+  }
+
+  #[test]
+  fn compression_skips_small_and_compressed_formats() {
+    let wasm: Mime = "application/wasm".parse().unwrap();
+    assert!(should_compress(&wasm, MIN_COMPRESS_SIZE));
+    assert!(should_compress(&mime::IMAGE_SVG, 4096));
+    assert!(should_compress(&"font/ttf".parse().unwrap(), 4096));
+    assert!(!should_compress(&mime::TEXT_CSS, MIN_COMPRESS_SIZE - 1));
+    assert!(!should_compress(&mime::IMAGE_PNG, 4096));
+    assert!(!should_compress(&mime::FONT_WOFF2, 4096));
+  }
+}
@@ -0,0 +1,490 @@
+//! Content Security Policy with per-request script nonces.
+//!
+//! [`csp_layer`] generates a fresh nonce for every request, makes it available
+//! to components rendered while handling that request through [`csp_nonce`],
+//! and sends the configured policy with the nonce added to `script-src`.
+//! Browsers report violations to [`REPORT_PATH`], where they are logged.
+
+use std::sync::Arc;
+
+use axum::{
+  Router,
+  body::Bytes,
+  extract::{DefaultBodyLimit, Request, State},
+  middleware::Next,
+  response::Response,
+  routing::post,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use http::{
+  HeaderName, HeaderValue, StatusCode,
+  header::{CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY_REPORT_ONLY, X_FRAME_OPTIONS},
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::config::Security;
+
+/// Where browsers send violation reports.
+pub const REPORT_PATH: &str = "/csp-report";
+
+/// The Reporting API endpoint name used by the `report-to` directive.
+const REPORT_GROUP: &str = "csp-endpoint";
+
+/// Reports are small; anything larger is not a genuine browser report.
+const MAX_REPORT_SIZE: usize = 64 * 1024;
+
+/// The hash of the script `tower-livereload` injects in debug builds, which
+/// carries no nonce. Recompute it when upgrading that crate:
+/// `openssl dgst -sha256 -binary assets/polling.js | base64`.
+#[cfg(debug_assertions)]
+const LIVERELOAD_SCRIPT_HASH: &str = "'sha256-L/4du8mXhXqvOm9Re02dTBSI4mWBbsqtG8F+xh3jiJc='";
+
+static REPORTING_ENDPOINTS: HeaderName = HeaderName::from_static("reporting-endpoints");
+
+/// Stands in for the nonce in prebuilt policies. It can't occur in a policy
+/// otherwise, since sources never contain spaces.
+const NONCE_PLACEHOLDER: &str = "{ nonce }";
+const NONCE_SOURCE: &str = "'nonce-{ nonce }'";
+
+/// The directives that can be set per route.
+const DIRECTIVES: &[&str] = &[
+  "base-uri",
+  "child-src",
+  "connect-src",
+  "default-src",
+  "font-src",
+  "form-action",
+  "frame-ancestors",
+  "frame-src",
+  "img-src",
+  "manifest-src",
+  "media-src",
+  "object-src",
+  "script-src",
+  "script-src-attr",
+  "script-src-elem",
+  "style-src",
+  "style-src-attr",
+  "style-src-elem",
+  "worker-src",
+];
+
+/// Keyword sources, which must be single-quoted.
+const KEYWORDS: &[&str] = &[
+  "'self'",
+  "'none'",
+  "'unsafe-inline'",
+  "'unsafe-eval'",
+  "'unsafe-hashes'",
+  "'wasm-unsafe-eval'",
+  "'strict-dynamic'",
+  "'report-sample'",
+];
+
+tokio::task_local! {
+  static NONCE: CspNonce;
+}
+
+/// A random, single-use token that marks inline scripts as trusted for one
+/// response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(Arc<str>);
+
+impl CspNonce {
+  fn generate() -> Self {
+    Self(BASE64.encode(rand::random::<[u8; 16]>()).into())
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+/// Returns the nonce for the response being rendered, for use as the `nonce`
+/// attribute of inline `<script>` tags. Outside a request handled by
+/// [`csp_layer`] there is none, and the attribute should be omitted.
+pub fn csp_nonce() -> Option<String> {
+  NONCE.try_with(|nonce| nonce.as_str().to_string()).ok()
+}
+
+/// Checks that `sources` form a well-formed value for `directive`, so a typo
+/// fails at startup instead of silently weakening or breaking the policy.
+pub(crate) fn validate_directive(directive: &str, sources: &[String]) -> Result<(), String> {
+  if !DIRECTIVES.contains(&directive) {
+    return Err(format!("unknown directive '{directive}'"));
+  }
+
+  for source in sources {
+    if source.is_empty()
+      || !source
+        .bytes()
+        .all(|b| b.is_ascii_graphic() && b != b';' && b != b',')
+    {
+      return Err(format!("{directive} has invalid source '{source}'"));
+    }
+    let quoted = source.starts_with('\'');
+    let known = KEYWORDS.contains(&source.as_str())
+      || ["'nonce-", "'sha256-", "'sha384-", "'sha512-"]
+        .iter()
+        .any(|prefix| source.starts_with(prefix) && source.ends_with('\''));
+    if quoted && !known {
+      return Err(format!("{directive} has unknown keyword {source}"));
+    }
+    if KEYWORDS.contains(&format!("'{source}'").as_str()) {
+      return Err(format!(
+        "{directive} keyword '{source}' must be single-quoted"
+      ));
+    }
+  }
+
+  if sources.len() > 1 && sources.iter().any(|source| source == "'none'") {
+    return Err(format!(
+      "{directive} can't combine 'none' with other sources"
+    ));
+  }
+  Ok(())
+}
+
+/// The policies sent with responses, prebuilt from config with a placeholder
+/// for the nonce.
+#[derive(Debug)]
+pub struct CspPolicies {
+  header: HeaderName,
+  default: Policy,
+  /// Route overrides, longest prefix first.
+  routes: Vec<(String, Policy)>,
+}
+
+#[derive(Debug)]
+struct Policy {
+  template: String,
+  /// Whether the route sets its own `frame-ancestors`, which the global
+  /// `X-Frame-Options` would otherwise contradict.
+  frames_overridden: bool,
+}
+
+impl CspPolicies {
+  pub fn from_config(security: &Security) -> Self {
+    let csp = &security.csp;
+    let directives: Vec<(&str, &[String])> = csp
+      .directives()
+      .into_iter()
+      .map(|(name, sources)| (name, sources.as_slice()))
+      .collect();
+
+    let mut routes: Vec<(String, Policy)> = security
+      .routes
+      .iter()
+      .map(|(prefix, overrides)| {
+        let mut directives = directives.clone();
+        for (name, sources) in overrides {
+          match directives.iter_mut().find(|(existing, _)| existing == name) {
+            Some((_, existing)) => *existing = sources,
+            None => directives.push((name, sources)),
+          }
+        }
+        let policy = Policy {
+          template: render(&directives, csp.upgrade_insecure_requests),
+          frames_overridden: overrides.contains_key("frame-ancestors"),
+        };
+        (prefix.trim_end_matches('/').to_string(), policy)
+      })
+      .collect();
+    routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+    Self {
+      header: if csp.report_only {
+        CONTENT_SECURITY_POLICY_REPORT_ONLY
+      } else {
+        CONTENT_SECURITY_POLICY
+      },
+      default: Policy {
+        template: render(&directives, csp.upgrade_insecure_requests),
+        frames_overridden: false,
+      },
+      routes,
+    }
+  }
+
+  fn for_path(&self, path: &str) -> &Policy {
+    self
+      .routes
+      .iter()
+      .find(|(prefix, _)| {
+        path
+          .strip_prefix(prefix.as_str())
+          .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+      })
+      .map_or(&self.default, |(_, policy)| policy)
+  }
+}
+
+/// Middleware that assigns each request a nonce and sends the matching
+/// `Content-Security-Policy`. The nonce is also stored in the request
+/// extensions for handlers that need it directly.
+pub async fn csp_layer(
+  State(policies): State<Arc<CspPolicies>>,
+  mut request: Request,
+  next: Next,
+) -> Response {
+  let nonce = CspNonce::generate();
+  request.extensions_mut().insert(nonce.clone());
+  let policy = policies.for_path(request.uri().path());
+
+  let mut response = NONCE.scope(nonce.clone(), next.run(request)).await;
+
+  let headers = response.headers_mut();
+  headers.insert(policies.header.clone(), policy.header_value(&nonce));
+  if policy.frames_overridden {
+    headers.remove(X_FRAME_OPTIONS);
+  }
+  headers.insert(
+    REPORTING_ENDPOINTS.clone(),
+    HeaderValue::from_str(&format!("{REPORT_GROUP}=\"{REPORT_PATH}\"")).expect("constant is valid"),
+  );
+  response
+}
+
+impl Policy {
+  fn header_value(&self, nonce: &CspNonce) -> HeaderValue {
+    let policy = self.template.replace(NONCE_PLACEHOLDER, nonce.as_str());
+    HeaderValue::from_str(&policy).expect("sources are validated and nonces are base64")
+  }
+}
+
+/// Renders `directives` into a policy, leaving out empty ones. `script-src`
+/// always gets the nonce, and in debug builds the live-reload script's hash.
+fn render(directives: &[(&str, &[String])], upgrade_insecure_requests: bool) -> String {
+  let mut parts = Vec::new();
+  for (name, sources) in directives {
+    let mut sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+    if *name == "script-src" {
+      sources.retain(|source| *source != "'none'");
+      sources.push(NONCE_SOURCE);
+      #[cfg(debug_assertions)]
+      sources.push(LIVERELOAD_SCRIPT_HASH);
+    }
+    if !sources.is_empty() {
+      parts.push(format!("{name} {}", sources.join(" ")));
+    }
+  }
+  if upgrade_insecure_requests {
+    parts.push("upgrade-insecure-requests".to_string());
+  }
+  parts.push(format!("report-uri {REPORT_PATH}"));
+  parts.push(format!("report-to {REPORT_GROUP}"));
+  parts.join("; ")
+}
+
+/// The endpoint browsers post violation reports to.
+pub fn report_router<S>() -> Router<S>
+where
+  S: Clone + Send + Sync + 'static,
+{
+  Router::new().route(
+    REPORT_PATH,
+    post(report).layer(DefaultBodyLimit::max(MAX_REPORT_SIZE)),
+  )
+}
+
+async fn report(body: Bytes) -> StatusCode {
+  let Some(violations) = parse_reports(&body) else {
+    return StatusCode::BAD_REQUEST;
+  };
+
+  for violation in violations {
+    warn!(
+      document_uri = violation.document_uri.as_deref(),
+      directive = violation
+        .effective_directive
+        .as_deref()
+        .or(violation.violated_directive.as_deref()),
+      blocked_uri = violation.blocked_uri.as_deref(),
+      source_file = violation.source_file.as_deref(),
+      line_number = violation.line_number,
+      disposition = violation.disposition.as_deref(),
+      "content security policy violation"
+    );
+  }
+  StatusCode::NO_CONTENT
+}
+
+/// A violation, in either the `report-uri` (kebab-case) or Reporting API
+/// (camelCase) vocabulary.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Violation {
+  #[serde(alias = "document-uri", alias = "documentURL")]
+  document_uri: Option<String>,
+  #[serde(alias = "effective-directive", alias = "effectiveDirective")]
+  effective_directive: Option<String>,
+  #[serde(alias = "violated-directive")]
+  violated_directive: Option<String>,
+  #[serde(alias = "blocked-uri", alias = "blockedURL")]
+  blocked_uri: Option<String>,
+  #[serde(alias = "source-file", alias = "sourceFile")]
+  source_file: Option<String>,
+  #[serde(alias = "line-number", alias = "lineNumber")]
+  line_number: Option<u64>,
+  disposition: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Reports {
+  /// `application/csp-report`, sent for `report-uri`.
+  Legacy {
+    #[serde(rename = "csp-report")]
+    csp_report: Violation,
+  },
+  /// `application/reports+json`, sent for `report-to`.
+  Reporting(Vec<Report>),
+}
+
+#[derive(Deserialize)]
+struct Report {
+  #[serde(rename = "type")]
+  kind: String,
+  #[serde(default)]
+  body: Violation,
+}
+
+/// Parses a report body, keeping only CSP violations. Returns `None` for
+/// bodies in neither format.
+fn parse_reports(body: &[u8]) -> Option<Vec<Violation>> {
+  match serde_json::from_slice(body).ok()? {
+    Reports::Legacy { csp_report } => Some(vec![csp_report]),
+    Reports::Reporting(reports) => Some(
+      reports
+        .into_iter()
+        .filter(|report| report.kind == "csp-violation")
+        .map(|report| report.body)
+        .collect(),
+    ),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use confique::Config as _;
+
+  fn default_security() -> Security {
+    Security::builder().load().unwrap()
+  }
+
+  fn strings(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn nonces_are_unique_and_in_policy() {
+    let (a, b) = (CspNonce::generate(), CspNonce::generate());
+    assert_ne!(a, b);
+    assert_eq!(BASE64.decode(a.as_str()).unwrap().len(), 16);
+
+    let policies = CspPolicies::from_config(&default_security());
+    let policy = policies.for_path("/").header_value(&a);
+    let policy = policy.to_str().unwrap();
+    assert!(policy.contains(&format!("script-src 'self' 'nonce-{}'", a.as_str())));
+    assert!(policy.contains(&format!("report-uri {REPORT_PATH}")));
+    assert!(!policy.contains("'unsafe-eval'"));
+    assert!(!policy.contains("script-src 'self' 'unsafe-inline'"));
+    // Empty directives fall back to default-src.
+    assert!(!policy.contains("connect-src"));
+  }
+
+  #[test]
+  fn routes_override_directives_by_prefix() {
+    let mut security = default_security();
+    security.csp.img_src = strings(&["'self'", "https://cdn.example.com"]);
+    for (prefix, ancestors) in [
+      ("/embed", "https://partner.example"),
+      ("/embed/admin", "'none'"),
+    ] {
+      let overrides = [("frame-ancestors".to_string(), strings(&[ancestors]))].into();
+      security.routes.insert(prefix.to_string(), overrides);
+    }
+    let policies = CspPolicies::from_config(&security);
+    let nonce = CspNonce::generate();
+    let policy = |path| {
+      let policy = policies.for_path(path);
+      let value = policy.header_value(&nonce);
+      (
+        value.to_str().unwrap().to_string(),
+        policy.frames_overridden,
+      )
+    };
+
+    let (global, frames_overridden) = policy("/embedded");
+    assert!(global.contains("frame-ancestors 'self'"));
+    assert!(global.contains("img-src 'self' https://cdn.example.com"));
+    assert!(!frames_overridden);
+
+    let (embed, frames_overridden) = policy("/embed/widget");
+    assert!(embed.contains("frame-ancestors https://partner.example"));
+    assert!(embed.contains("img-src 'self' https://cdn.example.com"));
+    assert!(frames_overridden);
+
+    assert!(policy("/embed/admin").0.contains("frame-ancestors 'none'"));
+  }
+
+  #[test]
+  fn validate_directive_rejects_malformed_sources() {
+    let sources = strings;
+
+    assert!(validate_directive("img-src", &sources(&["'self'", "https:", "data:"])).is_ok());
+    assert!(validate_directive("script-src", &sources(&["'sha256-abc='"])).is_ok());
+    assert!(validate_directive("img-src", &sources(&["self"])).is_err());
+    assert!(validate_directive("img-src", &sources(&["'selfish'"])).is_err());
+    assert!(validate_directive("img-src", &sources(&["a.com;"])).is_err());
+    assert!(validate_directive("img-src", &sources(&["a .com"])).is_err());
+    assert!(validate_directive("img-src", &sources(&["'none'", "'self'"])).is_err());
+    assert!(validate_directive("image-src", &sources(&["'self'"])).is_err());
+  }
+
+  #[tokio::test]
+  async fn nonce_is_scoped_to_request() {
+    assert_eq!(csp_nonce(), None);
+
+    let nonce = CspNonce::generate();
+    let seen = NONCE.scope(nonce.clone(), async { csp_nonce() }).await;
+    assert_eq!(seen.as_deref(), Some(nonce.as_str()));
+  }
+
+  #[test]
+  fn parses_both_report_formats() {
+    let legacy = br#"{"csp-report": {
+      "document-uri": "https://example.com/",
+      "violated-directive": "script-src-elem",
+      "effective-directive": "script-src-elem",
+      "blocked-uri": "inline",
+      "line-number": 12,
+      "disposition": "enforce"
+    }}"#;
+    let violations = parse_reports(legacy).unwrap();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].blocked_uri.as_deref(), Some("inline"));
+    assert_eq!(violations[0].line_number, Some(12));
+
+    let reporting = br#"[
+      {"type": "csp-violation", "url": "https://example.com/", "body": {
+        "documentURL": "https://example.com/",
+        "effectiveDirective": "script-src-elem",
+        "blockedURL": "https://evil.example/x.js",
+        "disposition": "enforce"
+      }},
+      {"type": "deprecation", "body": {"id": "x"}}
+    ]"#;
+    let violations = parse_reports(reporting).unwrap();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(
+      violations[0].effective_directive.as_deref(),
+      Some("script-src-elem")
+    );
+
+    assert!(parse_reports(b"not json").is_none());
+  }
+}
@@ -0,0 +1,341 @@
+//! Cross-site request forgery protection for session-authenticated requests.
+//!
+//! [`csrf_layer`] keeps a synchronizer token in the session. Pages get it
+//! through [`csrf_token`]: `document` puts it in an `hx-headers` attribute so
+//! every htmx request carries it, and [`Form`] adds it to plain forms.
+//! Unsafe requests must come from this origin and present the token, either
+//! in the [`HEADER_NAME`] header or the [`FIELD_NAME`] form field.
+//!
+//! Requests with an `Authorization` or `X-API-Key` header are exempt: browsers
+//! never attach those on their own, so a forged cross-site request can't carry
+//! them.
+//!
+//! [`Form`]: crate::routes::components::Form
+
+use axum::{
+  body::{Body, Bytes, to_bytes},
+  extract::{FromRequest as _, Multipart, Request},
+  middleware::Next,
+  response::{IntoResponse as _, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL};
+use http::{HeaderMap, StatusCode, header};
+use subtle::ConstantTimeEq as _;
+use tower_sessions::Session;
+use tracing::{debug, error};
+
+/// The request header carrying the token, used by htmx and `fetch`.
+pub const HEADER_NAME: &str = "x-csrf-token";
+/// The form field carrying the token, used by plain HTML forms.
+pub const FIELD_NAME: &str = "_csrf";
+
+/// The session key the token is stored under.
+const SESSION_KEY: &str = "csrf_token";
+
+/// The largest form body buffered to look for the token.
+const MAX_FORM_SIZE: usize = 2 * 1024 * 1024;
+
+tokio::task_local! {
+  static TOKEN: String;
+}
+
+/// Returns the CSRF token of the session behind the request being rendered.
+/// Outside a request handled by [`csrf_layer`] there is none.
+pub fn csrf_token() -> Option<String> {
+  TOKEN.try_with(Clone::clone).ok()
+}
+
+/// Middleware enforcing CSRF checks on unsafe methods, and making the
+/// session's token available to components on every other request.
+pub async fn csrf_layer(session: Session, request: Request, next: Next) -> Response {
+  if is_exempt(request.headers()) {
+    return next.run(request).await;
+  }
+
+  let token = match session_token(&session).await {
+    Ok(token) => token,
+    Err(e) => {
+      error!("failed to load CSRF token: {e}");
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let request = if request.method().is_safe() {
+    request
+  } else {
+    if !is_same_origin(request.headers(), request.uri()) {
+      debug!(
+        "rejected cross-origin {} {}",
+        request.method(),
+        request.uri()
+      );
+      return forbidden();
+    }
+
+    let (request, presented) = match presented_token(request).await {
+      Ok(found) => found,
+      Err(response) => return response,
+    };
+    let valid =
+      presented.is_some_and(|presented| bool::from(presented.as_bytes().ct_eq(token.as_bytes())));
+    if !valid {
+      debug!(
+        "rejected {} {} without a valid CSRF token",
+        request.method(),
+        request.uri()
+      );
+      return forbidden();
+    }
+    request
+  };
+
+  TOKEN.scope(token, next.run(request)).await
+}
+
+fn forbidden() -> Response {
+  (StatusCode::FORBIDDEN, "invalid CSRF token").into_response()
+}
+
+/// Requests authenticated by a header the browser doesn't add by itself.
+fn is_exempt(headers: &HeaderMap) -> bool {
+  headers
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| value.starts_with("Bearer "))
+    || headers.contains_key("x-api-key")
+}
+
+/// Returns the session's token, creating one on first use.
+async fn session_token(session: &Session) -> Result<String, tower_sessions::session::Error> {
+  if let Some(token) = session.get::<String>(SESSION_KEY).await? {
+    return Ok(token);
+  }
+
+  let token = BASE64_URL.encode(rand::random::<[u8; 32]>());
+  session.insert(SESSION_KEY, &token).await?;
+  Ok(token)
+}
+
+/// Checks the fetch metadata and `Origin` headers browsers attach to
+/// cross-site requests. Requests with neither, such as from older browsers,
+/// fall through to the token check alone.
+fn is_same_origin(headers: &HeaderMap, uri: &http::Uri) -> bool {
+  if let Some(site) = headers.get("sec-fetch-site") {
+    return matches!(site.as_bytes(), b"same-origin" | b"none");
+  }
+
+  let Some(origin) = headers.get(header::ORIGIN) else {
+    return true;
+  };
+  // HTTP/2 requests carry the authority in the URI rather than `Host`.
+  let host = uri
+    .authority()
+    .map(|authority| authority.as_str())
+    .or_else(|| headers.get(header::HOST)?.to_str().ok());
+
+  origin
+    .to_str()
+    .ok()
+    .and_then(|origin| origin.parse::<http::Uri>().ok())
+    .and_then(|origin| {
+      origin
+        .authority()
+        .map(|authority| authority.as_str().to_string())
+    })
+    .is_some_and(|origin| host.is_some_and(|host| host.eq_ignore_ascii_case(&origin)))
+}
+
+/// Finds the token in the request header or, for URL-encoded and multipart
+/// forms, the body. The body is buffered and put back so handlers can still
+/// read it, so forms larger than [`MAX_FORM_SIZE`], such as big uploads, have
+/// to send the header instead.
+async fn presented_token(request: Request) -> Result<(Request, Option<String>), Response> {
+  if let Some(token) = request
+    .headers()
+    .get(HEADER_NAME)
+    .and_then(|value| value.to_str().ok())
+  {
+    let token = token.to_string();
+    return Ok((request, Some(token)));
+  }
+
+  let content_type = request
+    .headers()
+    .get(header::CONTENT_TYPE)
+    .and_then(|value| value.to_str().ok())
+    .unwrap_or_default();
+  let is_multipart = content_type.starts_with("multipart/form-data");
+  if !is_multipart && !content_type.starts_with("application/x-www-form-urlencoded") {
+    return Ok((request, None));
+  }
+
+  let (parts, body) = request.into_parts();
+  let bytes = to_bytes(body, MAX_FORM_SIZE)
+    .await
+    .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+  let token = if is_multipart {
+    multipart_field(&parts.headers, bytes.clone(), FIELD_NAME).await
+  } else {
+    form_field(&bytes, FIELD_NAME)
+  };
+  Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+/// Reads the first field called `name` out of a buffered multipart body.
+async fn multipart_field(headers: &HeaderMap, body: Bytes, name: &str) -> Option<String> {
+  let mut request = Request::new(Body::from(body));
+  *request.headers_mut() = headers.clone();
+  let mut multipart = Multipart::from_request(request, &()).await.ok()?;
+  while let Some(field) = multipart.next_field().await.ok()? {
+    if field.name() == Some(name) {
+      return field.text().await.ok();
+    }
+  }
+  None
+}
+
+fn form_field(body: &[u8], name: &str) -> Option<String> {
+  form_urlencoded::parse(body)
+    .find(|(key, _)| key == name)
+    .map(|(_, value)| value.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use http::HeaderValue;
+
+  fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+    pairs
+      .iter()
+      .map(|(name, value)| {
+        (
+          http::HeaderName::from_static(name),
+          HeaderValue::from_static(value),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn origin_checks_prefer_fetch_metadata() {
+    let uri: http::Uri = "/api/layout".parse().unwrap();
+    let same_origin = |pairs| is_same_origin(&headers(pairs), &uri);
+
+    assert!(same_origin(&[("sec-fetch-site", "same-origin")]));
+    assert!(!same_origin(&[("sec-fetch-site", "cross-site")]));
+    assert!(!same_origin(&[("sec-fetch-site", "same-site")]));
+    assert!(same_origin(&[
+      ("host", "example.com:8080"),
+      ("origin", "https://example.com:8080"),
+    ]));
+    assert!(!same_origin(&[
+      ("host", "example.com"),
+      ("origin", "https://evil.example"),
+    ]));
+    assert!(!same_origin(&[("host", "example.com"), ("origin", "null")]));
+    assert!(same_origin(&[("host", "example.com")]));
+  }
+
+  #[test]
+  fn header_authenticated_requests_are_exempt() {
+    assert!(is_exempt(&headers(&[("x-api-key", "key")])));
+    assert!(is_exempt(&headers(&[("authorization", "Bearer abc")])));
+    assert!(!is_exempt(&headers(&[("authorization", "Basic abc")])));
+    assert!(!is_exempt(&headers(&[])));
+  }
+
+  #[tokio::test]
+  async fn unsafe_requests_need_the_session_token() {
+    use axum::{Router, middleware::from_fn, routing::get};
+    use tower::ServiceExt as _;
+    use tower_sessions::{MemoryStore, SessionManagerLayer};
+
+    let app = Router::new()
+      .route(
+        "/",
+        get(|| async { csrf_token().unwrap_or_default() }).post(|| async { "saved" }),
+      )
+      .layer(from_fn(csrf_layer))
+      .layer(SessionManagerLayer::new(MemoryStore::default()).with_secure(false));
+
+    let response = app
+      .clone()
+      .oneshot(Request::get("/").body(Body::empty()).unwrap())
+      .await
+      .unwrap();
+    let cookie = response.headers()[header::SET_COOKIE]
+      .to_str()
+      .unwrap()
+      .split(';')
+      .next()
+      .unwrap()
+      .to_string();
+    let token = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let token = std::str::from_utf8(&token).unwrap().to_string();
+    assert!(!token.is_empty());
+
+    let post = |extra: &[(&str, &str)]| {
+      let mut request = Request::post("/").header(header::COOKIE, &cookie);
+      for (name, value) in extra {
+        request = request.header(*name, *value);
+      }
+      app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let status = |response: Result<Response, _>| response.unwrap().status();
+    assert_eq!(status(post(&[]).await), StatusCode::FORBIDDEN);
+    assert_eq!(
+      status(post(&[(HEADER_NAME, "wrong")]).await),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      status(post(&[(HEADER_NAME, &token), ("sec-fetch-site", "cross-site")]).await),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(status(post(&[(HEADER_NAME, &token)]).await), StatusCode::OK);
+    assert_eq!(status(post(&[("x-api-key", "key")]).await), StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn token_is_read_from_header_or_form() {
+    let request = Request::builder()
+      .header(HEADER_NAME, "from-header")
+      .body(Body::empty())
+      .unwrap();
+    let (_, token) = presented_token(request).await.unwrap();
+    assert_eq!(token.as_deref(), Some("from-header"));
+
+    let request = Request::builder()
+      .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+      .body(Body::from("name=Ada&_csrf=from%2Fform"))
+      .unwrap();
+    let (request, token) = presented_token(request).await.unwrap();
+    assert_eq!(token.as_deref(), Some("from/form"));
+    // The body is still there for the handler.
+    let body = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"name=Ada&_csrf=from%2Fform");
+
+    let multipart = "--b\r\n\
+      Content-Disposition: form-data; name=\"_csrf\"\r\n\r\n\
+      from-multipart\r\n\
+      --b\r\n\
+      Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\n\
+      contents\r\n\
+      --b--\r\n";
+    let request = Request::builder()
+      .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+      .body(Body::from(multipart))
+      .unwrap();
+    let (request, token) = presented_token(request).await.unwrap();
+    assert_eq!(token.as_deref(), Some("from-multipart"));
+    let body = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], multipart.as_bytes());
+
+    let request = Request::builder()
+      .header(header::CONTENT_TYPE, "application/json")
+      .body(Body::from(r#"{"_csrf":"ignored"}"#))
+      .unwrap();
+    assert_eq!(presented_token(request).await.unwrap().1, None);
+  }
+}
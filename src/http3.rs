@@ -0,0 +1,321 @@
+//! HTTP/3 over QUIC, next to the HTTPS listener.
+//!
+//! With `server.http3` on, every HTTPS address is also bound over UDP and
+//! served with the same rustls config and `Router`. Responses over TCP carry
+//! an `Alt-Svc` header so browsers switch to HTTP/3 for later requests.
+//! Requests get the same `ConnectInfo` and client certificate extensions as
+//! over TCP, so extractors can't tell the difference.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+  Router,
+  body::Body,
+  extract::{ConnectInfo, Request, State},
+  middleware::Next,
+  response::Response,
+};
+use bytes::{Buf as _, Bytes};
+use futures::StreamExt as _;
+use h3::server::RequestResolver;
+use http::{HeaderValue, Version, header::ALT_SVC};
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::{ServerConfig, pki_types::CertificateDer};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt as _;
+use tracing::{debug, info, warn};
+
+use crate::{client_cert::ClientCert, listen};
+
+/// How long clients are told they can remember the HTTP/3 endpoint.
+const ALT_SVC_MAX_AGE: u32 = 86400;
+
+/// The `Alt-Svc` value advertising HTTP/3 on `port`.
+pub(crate) fn alt_svc(port: u16) -> HeaderValue {
+  HeaderValue::try_from(format!("h3=\":{port}\"; ma={ALT_SVC_MAX_AGE}"))
+    .expect("ports make valid headers")
+}
+
+/// Adds `Alt-Svc` to responses over HTTP/1.1 and HTTP/2.
+pub(crate) async fn alt_svc_layer(
+  State(alt_svc): State<HeaderValue>,
+  request: Request,
+  next: Next,
+) -> Response {
+  let version = request.version();
+  let mut response = next.run(request).await;
+  if version != Version::HTTP_3 {
+    response.headers_mut().insert(ALT_SVC, alt_svc);
+  }
+  response
+}
+
+/// A QUIC endpoint on `addr` using the certificates and client
+/// authentication of `tls`.
+pub(crate) fn endpoint(addr: SocketAddr, tls: &ServerConfig) -> eyre::Result<quinn::Endpoint> {
+  let mut tls = tls.clone();
+  tls.alpn_protocols = vec![b"h3".to_vec()];
+  let crypto = QuicServerConfig::try_from(tls)
+    .map_err(|e| eyre::eyre!("TLS config can't be used for QUIC: {e}"))?;
+  let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+  let socket =
+    listen::bind_udp(addr).map_err(|e| eyre::eyre!("failed to bind HTTP/3 to {addr}: {e}"))?;
+  let runtime = quinn::default_runtime().ok_or_else(|| eyre::eyre!("no async runtime"))?;
+  Ok(quinn::Endpoint::new(
+    quinn::EndpointConfig::default(),
+    Some(server_config),
+    socket,
+    runtime,
+  )?)
+}
+
+/// Serves `app` on `endpoint` until `stopping` is cancelled, then tells
+/// clients to go away and gives open requests up to `grace` to finish.
+pub(crate) async fn serve(
+  endpoint: quinn::Endpoint,
+  app: Router,
+  stopping: CancellationToken,
+  grace: Duration,
+) {
+  debug!(addr = ?endpoint.local_addr(), "starting HTTP/3 server");
+  loop {
+    let incoming = tokio::select! {
+      incoming = endpoint.accept() => incoming,
+      () = stopping.cancelled() => break,
+    };
+    let Some(incoming) = incoming else { break };
+
+    let app = app.clone();
+    let stopping = stopping.clone();
+    tokio::spawn(async move {
+      let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+          debug!("QUIC handshake failed: {e}");
+          return;
+        }
+      };
+      if let Err(e) = serve_connection(connection, app, stopping).await {
+        debug!("HTTP/3 connection failed: {e}");
+      }
+    });
+  }
+
+  endpoint.set_server_config(None);
+  if tokio::time::timeout(grace, endpoint.wait_idle())
+    .await
+    .is_err()
+  {
+    warn!("closing HTTP/3 connections that outlived the shutdown grace period");
+  }
+  endpoint.close(no_error(), b"");
+  info!("HTTP/3 server stopped");
+}
+
+async fn serve_connection(
+  connection: quinn::Connection,
+  app: Router,
+  stopping: CancellationToken,
+) -> Result<(), h3::error::ConnectionError> {
+  let peer = connection.remote_address();
+  let client_cert = client_cert(&connection);
+  let mut h3 = h3::server::Connection::new(h3_quinn::Connection::new(connection.clone())).await?;
+
+  // Dropped with the connection, which cancels whatever is left.
+  let mut requests = JoinSet::new();
+  loop {
+    let accepted = tokio::select! {
+      accepted = h3.accept() => accepted,
+      Some(_) = requests.join_next() => continue,
+      () = stopping.cancelled() => break,
+    };
+    match accepted {
+      Ok(Some(resolver)) => {
+        let app = app.clone();
+        let client_cert = client_cert.clone();
+        requests.spawn(async move {
+          if let Err(e) = serve_request(resolver, app, peer, client_cert).await {
+            debug!("HTTP/3 request failed: {e}");
+          }
+        });
+      }
+      Ok(None) => return Ok(()),
+      Err(e) if e.is_h3_no_error() => return Ok(()),
+      Err(e) => return Err(e),
+    }
+  }
+
+  // Requests already accepted run to completion, and then the connection is
+  // closed rather than left for the client to give up on.
+  h3.shutdown(0).await?;
+  while requests.join_next().await.is_some() {}
+  connection.close(no_error(), b"");
+  Ok(())
+}
+
+fn no_error() -> quinn::VarInt {
+  quinn::VarInt::from_u64(h3::error::Code::H3_NO_ERROR.value())
+    .expect("HTTP/3 error codes are varints")
+}
+
+/// The client's verified certificate, as [`crate::client_cert`] reads it
+/// over TCP.
+fn client_cert(connection: &quinn::Connection) -> Option<ClientCert> {
+  let chain = connection
+    .peer_identity()?
+    .downcast::<Vec<CertificateDer<'static>>>()
+    .ok()?;
+  match ClientCert::from_der(chain.first()?) {
+    Ok(cert) => Some(cert),
+    Err(e) => {
+      warn!("unreadable client certificate: {e:#}");
+      None
+    }
+  }
+}
+
+async fn serve_request(
+  resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+  app: Router,
+  peer: SocketAddr,
+  client_cert: Option<ClientCert>,
+) -> Result<(), axum::BoxError> {
+  let (request, stream) = resolver.resolve_request().await?;
+  let (mut send, recv) = stream.split();
+
+  let body = futures::stream::unfold(Some(recv), |recv| async move {
+    let mut recv = recv?;
+    match recv.recv_data().await {
+      Ok(Some(mut data)) => Some((Ok(data.copy_to_bytes(data.remaining())), Some(recv))),
+      Ok(None) => None,
+      Err(e) => Some((Err(e), None)),
+    }
+  });
+  let mut request = request.map(|()| Body::from_stream(body));
+  request.extensions_mut().insert(ConnectInfo(peer));
+  request.extensions_mut().insert(client_cert);
+
+  let response = app.oneshot(request).await?;
+  let (parts, body) = response.into_parts();
+  send
+    .send_response(http::Response::from_parts(parts, ()))
+    .await?;
+  let mut body = body.into_data_stream();
+  while let Some(chunk) = body.next().await {
+    send.send_data(chunk?).await?;
+  }
+  send.finish().await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::{middleware, routing::get};
+
+  #[tokio::test]
+  async fn alt_svc_is_only_advertised_over_tcp() {
+    let router = Router::new()
+      .route("/", get(|| async { "ok" }))
+      .layer(middleware::from_fn_with_state(alt_svc(8443), alt_svc_layer));
+    let send = |version| {
+      let request = http::Request::builder()
+        .version(version)
+        .uri("/")
+        .body(Body::empty())
+        .unwrap();
+      router.clone().oneshot(request)
+    };
+
+    let response = send(Version::HTTP_11).await.unwrap();
+    assert_eq!(response.headers()[ALT_SVC], "h3=\":8443\"; ma=86400");
+    let response = send(Version::HTTP_2).await.unwrap();
+    assert!(response.headers().contains_key(ALT_SVC));
+    let response = send(Version::HTTP_3).await.unwrap();
+    assert!(!response.headers().contains_key(ALT_SVC));
+  }
+
+  #[tokio::test]
+  async fn serves_the_router_until_stopped() {
+    let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let cert = rcgen::CertificateParams::new(vec!["localhost".into()])
+      .unwrap()
+      .self_signed(&key)
+      .unwrap();
+    let provider = crate::tls::crypto_provider();
+    let tls = ServerConfig::builder_with_provider(provider.clone())
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_no_client_auth()
+      .with_single_cert(
+        vec![cert.der().clone()],
+        rustls::pki_types::PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+      )
+      .unwrap();
+
+    let app = Router::new().route(
+      "/echo",
+      axum::routing::post(
+        |ConnectInfo(peer): ConnectInfo<SocketAddr>, body: String| async move {
+          format!("{} {body}", peer.ip())
+        },
+      ),
+    );
+    let endpoint = endpoint("127.0.0.1:0".parse().unwrap(), &tls).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+    let stopping = CancellationToken::new();
+    let server = tokio::spawn(serve(
+      endpoint,
+      app,
+      stopping.clone(),
+      Duration::from_secs(1),
+    ));
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert.der().clone()).unwrap();
+    let mut client_tls = rustls::ClientConfig::builder_with_provider(provider)
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    client_tls.alpn_protocols = vec![b"h3".to_vec()];
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+      quinn::crypto::rustls::QuicClientConfig::try_from(client_tls).unwrap(),
+    )));
+    let connection = client.connect(addr, "localhost").unwrap().await.unwrap();
+    let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+      .await
+      .unwrap();
+    let driver =
+      tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let request = http::Request::post(format!("https://localhost:{}/echo", addr.port()))
+      .body(())
+      .unwrap();
+    let mut stream = send_request.send_request(request).await.unwrap();
+    stream
+      .send_data(Bytes::from_static(b"hello"))
+      .await
+      .unwrap();
+    stream.finish().await.unwrap();
+    let response = stream.recv_response().await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+      body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    assert_eq!(body, b"127.0.0.1 hello");
+
+    // Stopping sends GOAWAY, which ends the client's connection cleanly.
+    stopping.cancel();
+    let closed = driver.await.unwrap();
+    assert!(closed.is_h3_no_error(), "{closed:?}");
+    tokio::time::timeout(Duration::from_secs(5), server)
+      .await
+      .unwrap()
+      .unwrap();
+  }
+}
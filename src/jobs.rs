@@ -0,0 +1,1050 @@
+//! Durable background jobs in Postgres.
+//!
+//! Jobs are rows in `"{schema}"."job"`. A [`Worker`] claims due jobs with
+//! `FOR UPDATE SKIP LOCKED`, so every replica can run one against the same
+//! queue, and `LISTEN`s for the `NOTIFY` sent on enqueue so new jobs start
+//! right away instead of at the next poll. A claimed job is leased; if its
+//! replica dies the lease runs out and another one picks the job up, so jobs
+//! run at least once and should be safe to repeat.
+//!
+//! A job that fails is retried with exponential backoff. After its last
+//! attempt, or once that attempt's lease runs out, it's moved to
+//! `"{schema}"."dead_job"`, where it stays until it is retried from the
+//! monitoring port. A worker whose lease ran out can't record an outcome
+//! over the worker that claimed the job after it.
+//!
+//! Schedules enqueue a job every interval or on a cron pattern. Their next
+//! run is kept in `"{schema}"."schedule"`, and only the replica that advances
+//! it enqueues the job. With an [`Election`], only the leader looks at them
+//! at all.
+
+use std::{
+  collections::HashMap, panic::AssertUnwindSafe, str::FromStr as _, sync::Arc, time::Duration,
+};
+
+use axum::{
+  Json, Router,
+  extract::{Path, Query, State},
+  response::{IntoResponse, Response},
+  routing::{get, post},
+};
+use croner::Cron;
+use deadpool_postgres::{GenericClient, Pool};
+use futures::{FutureExt as _, StreamExt as _, future::BoxFuture};
+use http::StatusCode;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::{
+  sync::{Notify, Semaphore},
+  task::JoinSet,
+};
+use tokio_postgres::{AsyncMessage, NoTls, Row, error::SqlState};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument as _, debug, error, info, info_span, warn};
+use uuid::Uuid;
+
+use crate::{
+  config, error::AppError, leader::Election, tokio_postgres_sessions::is_valid_identifier,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum JobError {
+  /// A variant to map `tokio_postgres` errors.
+  #[error(transparent)]
+  Postgres(#[from] tokio_postgres::Error),
+
+  /// A variant to map `deadpool_postgres` errors.
+  #[error(transparent)]
+  Pool(#[from] deadpool_postgres::PoolError),
+
+  /// A variant to map job payload (de)serialization errors.
+  #[error(transparent)]
+  Payload(#[from] serde_json::Error),
+
+  /// A variant to map cron pattern errors.
+  #[error(transparent)]
+  Cron(#[from] croner::errors::CronError),
+}
+
+/// A kind of background job. The value is its payload, stored as JSON.
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+  /// Names the job's rows. Queued jobs of a kind no worker handles wait
+  /// until one does, so renaming a kind strands them.
+  const KIND: &'static str;
+}
+
+/// The job table, shared by everything that enqueues and runs jobs.
+#[derive(Clone, Debug)]
+pub struct JobQueue {
+  pool: Pool,
+  schema_name: String,
+  max_attempts: i32,
+}
+
+/// A job claimed by a worker.
+struct Claimed {
+  id: Uuid,
+  kind: String,
+  payload: Value,
+  attempts: i32,
+  max_attempts: i32,
+}
+
+/// How many jobs of a kind are in each state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KindStats {
+  pub kind: String,
+  /// Due and waiting for a worker.
+  pub ready: i64,
+  /// Leased to a worker.
+  pub running: i64,
+  /// Due later, including retries waiting out their backoff.
+  pub waiting: i64,
+  /// Out of attempts.
+  pub dead: i64,
+}
+
+/// A schedule and when it next enqueues its job.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleInfo {
+  pub name: String,
+  pub pattern: String,
+  pub next_run_at: Timestamp,
+}
+
+/// A job that ran out of attempts.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadJob {
+  pub id: Uuid,
+  pub kind: String,
+  pub payload: Value,
+  pub attempts: i32,
+  pub last_error: String,
+  pub created_at: Timestamp,
+  pub failed_at: Timestamp,
+}
+
+impl JobQueue {
+  /// Create a new job queue with the provided connection pool.
+  pub fn new(pool: Pool) -> Self {
+    Self {
+      pool,
+      schema_name: "jobs".to_string(),
+      max_attempts: 5,
+    }
+  }
+
+  pub fn from_config(config: &config::Jobs, pool: Pool) -> Result<Self, String> {
+    Ok(
+      Self::new(pool)
+        .with_schema_name(&config.schema_name)?
+        .with_max_attempts(config.max_attempts),
+    )
+  }
+
+  /// Set the schema holding the job tables with the provided name.
+  pub fn with_schema_name(mut self, schema_name: impl AsRef<str>) -> Result<Self, String> {
+    let schema_name = schema_name.as_ref();
+    if !is_valid_identifier(schema_name) {
+      return Err(format!("Invalid schema name '{schema_name}'"));
+    }
+
+    schema_name.clone_into(&mut self.schema_name);
+    Ok(self)
+  }
+
+  /// How many times jobs enqueued from now on run before they're given up.
+  pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+    self.max_attempts = i32::try_from(max_attempts.max(1)).unwrap_or(i32::MAX);
+    self
+  }
+
+  /// The channel notified when a job is enqueued.
+  fn channel(&self) -> &str {
+    &self.schema_name
+  }
+
+  /// Migrate the job schema.
+  pub async fn migrate(&self) -> Result<(), JobError> {
+    let mut client = self.pool.get().await?;
+    let tx = client.transaction().await?;
+
+    let create_schema_query = format!(
+      r#"create schema if not exists "{schema_name}""#,
+      schema_name = self.schema_name,
+    );
+
+    if let Err(err) = tx.batch_execute(&create_schema_query).await {
+      let duplicate = matches!(
+        err.code(),
+        Some(code) if code == &SqlState::DUPLICATE_SCHEMA || code == &SqlState::UNIQUE_VIOLATION
+      );
+
+      if !duplicate {
+        return Err(err.into());
+      }
+    }
+
+    let create_tables_query = format!(
+      r#"
+            create table if not exists "{schema_name}"."job"
+            (
+                id uuid primary key not null,
+                kind text not null,
+                payload jsonb not null,
+                attempts integer not null default 0,
+                max_attempts integer not null,
+                run_at timestamptz not null,
+                locked_until timestamptz,
+                last_error text,
+                created_at timestamptz not null default now()
+            );
+            create index if not exists job_run_at on "{schema_name}"."job" (run_at);
+
+            create table if not exists "{schema_name}"."dead_job"
+            (
+                id uuid primary key not null,
+                kind text not null,
+                payload jsonb not null,
+                attempts integer not null,
+                max_attempts integer not null,
+                last_error text not null,
+                created_at timestamptz not null,
+                failed_at timestamptz not null default now()
+            );
+
+            create table if not exists "{schema_name}"."schedule"
+            (
+                name text primary key not null,
+                pattern text not null,
+                next_run_at timestamptz not null
+            );
+            "#,
+      schema_name = self.schema_name,
+    );
+    tx.batch_execute(&create_tables_query).await?;
+
+    tx.commit().await?;
+    Ok(())
+  }
+
+  /// Queues `job` to run as soon as a worker is free.
+  pub async fn enqueue<J: Job>(&self, job: &J) -> Result<Uuid, JobError> {
+    self.enqueue_at(job, Timestamp::now()).await
+  }
+
+  /// Queues `job` to run at `run_at`.
+  pub async fn enqueue_at<J: Job>(&self, job: &J, run_at: Timestamp) -> Result<Uuid, JobError> {
+    let client = self.pool.get().await?;
+    self.enqueue_with(&client, job, run_at).await
+  }
+
+  /// Queues `job` through `client`, so it's only queued if the caller's
+  /// transaction commits.
+  pub async fn enqueue_with<J: Job>(
+    &self,
+    client: &impl GenericClient,
+    job: &J,
+    run_at: Timestamp,
+  ) -> Result<Uuid, JobError> {
+    let payload = serde_json::to_value(job)?;
+    self.insert(client, J::KIND, &payload, run_at).await
+  }
+
+  async fn insert(
+    &self,
+    client: &impl GenericClient,
+    kind: &str,
+    payload: &Value,
+    run_at: Timestamp,
+  ) -> Result<Uuid, JobError> {
+    let query = format!(
+      r#"
+            with job as (
+                insert into "{schema_name}"."job" (id, kind, payload, max_attempts, run_at)
+                values ($1, $2, $3, $4, $5)
+                returning id
+            )
+            select pg_notify($6, '') from job
+            "#,
+      schema_name = self.schema_name,
+    );
+    let id = Uuid::now_v7();
+    client
+      .execute(
+        &query,
+        &[
+          &id,
+          &kind,
+          payload,
+          &self.max_attempts,
+          &run_at,
+          &self.channel(),
+        ],
+      )
+      .await?;
+    Ok(id)
+  }
+
+  /// Leases the longest-due job of one of `kinds` for `lease`. Jobs whose
+  /// last attempt ran out its lease, say because it kept killing its worker,
+  /// are moved to the dead letters instead.
+  async fn claim(&self, kinds: &[&str], lease: Duration) -> Result<Option<Claimed>, JobError> {
+    let query = format!(
+      r#"
+            with abandoned as (
+                delete from "{schema_name}"."job"
+                where id in (
+                    select id from "{schema_name}"."job"
+                    where run_at <= now()
+                      and (locked_until is null or locked_until < now())
+                      and kind = any($1)
+                      and attempts >= max_attempts
+                    for update skip locked
+                )
+                returning id, kind, payload, attempts, max_attempts, created_at
+            ), buried as (
+                insert into "{schema_name}"."dead_job"
+                    (id, kind, payload, attempts, max_attempts, last_error, created_at)
+                select id, kind, payload, attempts, max_attempts,
+                    'lease ran out on the last attempt', created_at
+                from abandoned
+            )
+            update "{schema_name}"."job"
+            set attempts = attempts + 1,
+                locked_until = now() + make_interval(secs => $2)
+            where id = (
+                select id from "{schema_name}"."job"
+                where run_at <= now()
+                  and (locked_until is null or locked_until < now())
+                  and kind = any($1)
+                  and attempts < max_attempts
+                order by run_at
+                for update skip locked
+                limit 1
+            )
+            returning id, kind, payload, attempts, max_attempts
+            "#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    let row = client
+      .query_opt(&query, &[&kinds, &lease.as_secs_f64()])
+      .await?;
+    Ok(row.map(|row| Claimed {
+      id: row.get("id"),
+      kind: row.get("kind"),
+      payload: row.get("payload"),
+      attempts: row.get("attempts"),
+      max_attempts: row.get("max_attempts"),
+    }))
+  }
+
+  // The outcome of an attempt is only recorded while its lease is current:
+  // once it runs out, another worker may have claimed the job and bumped
+  // `attempts`. Each returns whether the attempt still held the job.
+
+  async fn complete(&self, id: Uuid, attempt: i32) -> Result<bool, JobError> {
+    let query = format!(
+      r#"delete from "{schema_name}"."job" where id = $1 and attempts = $2"#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    Ok(client.execute(&query, &[&id, &attempt]).await? > 0)
+  }
+
+  /// Releases a failed job to run again after `delay`.
+  async fn retry(
+    &self,
+    id: Uuid,
+    attempt: i32,
+    delay: Duration,
+    error: &str,
+  ) -> Result<bool, JobError> {
+    let query = format!(
+      r#"
+            update "{schema_name}"."job"
+            set run_at = now() + make_interval(secs => $3),
+                locked_until = null,
+                last_error = $4
+            where id = $1 and attempts = $2
+            "#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    let updated = client
+      .execute(&query, &[&id, &attempt, &delay.as_secs_f64(), &error])
+      .await?;
+    Ok(updated > 0)
+  }
+
+  /// Moves a failed job to the dead letters.
+  async fn bury(&self, id: Uuid, attempt: i32, error: &str) -> Result<bool, JobError> {
+    let query = format!(
+      r#"
+            with moved as (
+                delete from "{schema_name}"."job" where id = $1 and attempts = $2
+                returning id, kind, payload, attempts, max_attempts, created_at
+            )
+            insert into "{schema_name}"."dead_job"
+                (id, kind, payload, attempts, max_attempts, last_error, created_at)
+            select id, kind, payload, attempts, max_attempts, $3, created_at from moved
+            "#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    Ok(client.execute(&query, &[&id, &attempt, &error]).await? > 0)
+  }
+
+  /// Counts the queued and dead jobs of every kind.
+  pub async fn stats(&self) -> Result<Vec<KindStats>, JobError> {
+    let query = format!(
+      r#"
+            with queued as (
+                select kind,
+                    count(*) filter (where locked_until > now()) as running,
+                    count(*) filter (
+                        where run_at <= now() and not coalesce(locked_until > now(), false)
+                    ) as ready,
+                    count(*) filter (
+                        where run_at > now() and not coalesce(locked_until > now(), false)
+                    ) as waiting
+                from "{schema_name}"."job"
+                group by kind
+            ), dead as (
+                select kind, count(*) as dead from "{schema_name}"."dead_job" group by kind
+            )
+            select coalesce(queued.kind, dead.kind) as kind,
+                coalesce(ready, 0) as ready,
+                coalesce(running, 0) as running,
+                coalesce(waiting, 0) as waiting,
+                coalesce(dead, 0) as dead
+            from queued full join dead on queued.kind = dead.kind
+            order by 1
+            "#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    let rows = client.query(&query, &[]).await?;
+    Ok(
+      rows
+        .iter()
+        .map(|row| KindStats {
+          kind: row.get("kind"),
+          ready: row.get("ready"),
+          running: row.get("running"),
+          waiting: row.get("waiting"),
+          dead: row.get("dead"),
+        })
+        .collect(),
+    )
+  }
+
+  /// Every schedule any replica has registered.
+  pub async fn schedules(&self) -> Result<Vec<ScheduleInfo>, JobError> {
+    let query = format!(
+      r#"select name, pattern, next_run_at from "{schema_name}"."schedule" order by name"#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    let rows = client.query(&query, &[]).await?;
+    Ok(
+      rows
+        .iter()
+        .map(|row| ScheduleInfo {
+          name: row.get("name"),
+          pattern: row.get("pattern"),
+          next_run_at: row.get("next_run_at"),
+        })
+        .collect(),
+    )
+  }
+
+  /// The `limit` jobs that ran out of attempts most recently.
+  pub async fn dead_jobs(&self, limit: i64) -> Result<Vec<DeadJob>, JobError> {
+    let query = format!(
+      r#"
+            select id, kind, payload, attempts, last_error, created_at, failed_at
+            from "{schema_name}"."dead_job"
+            order by failed_at desc
+            limit $1
+            "#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    let rows = client.query(&query, &[&limit]).await?;
+    Ok(rows.iter().map(dead_job).collect())
+  }
+
+  /// Queues a dead job again with its attempts reset. Returns whether there
+  /// was such a job.
+  pub async fn retry_dead(&self, id: Uuid) -> Result<bool, JobError> {
+    let query = format!(
+      r#"
+            with moved as (
+                delete from "{schema_name}"."dead_job" where id = $1
+                returning id, kind, payload, max_attempts, last_error, created_at
+            ), job as (
+                insert into "{schema_name}"."job"
+                    (id, kind, payload, max_attempts, run_at, last_error, created_at)
+                select id, kind, payload, max_attempts, now(), last_error, created_at from moved
+                returning id
+            )
+            select pg_notify($2, '') from job
+            "#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    let retried = client.execute(&query, &[&id, &self.channel()]).await?;
+    Ok(retried > 0)
+  }
+
+  /// Records `schedule`, or replaces it if its pattern changed.
+  async fn register_schedule(&self, schedule: &Scheduled) -> Result<(), JobError> {
+    let query = format!(
+      r#"
+            insert into "{schema_name}"."schedule" (name, pattern, next_run_at)
+            values ($1, $2, $3)
+            on conflict (name) do update
+            set pattern = excluded.pattern, next_run_at = excluded.next_run_at
+            where "schedule".pattern <> excluded.pattern
+            "#,
+      schema_name = self.schema_name,
+    );
+    let next_run_at = schedule.every.next_after(Timestamp::now())?;
+    let client = self.pool.get().await?;
+    client
+      .execute(
+        &query,
+        &[&schedule.name, &schedule.every.pattern(), &next_run_at],
+      )
+      .await?;
+    Ok(())
+  }
+
+  /// Enqueues the job of `schedule` if it's due, advancing it in the same
+  /// transaction so no other replica enqueues it too.
+  async fn fire_if_due(&self, schedule: &Scheduled) -> Result<bool, JobError> {
+    let query = format!(
+      r#"
+            update "{schema_name}"."schedule"
+            set next_run_at = $2
+            where name = $1 and next_run_at <= now()
+            "#,
+      schema_name = self.schema_name,
+    );
+    let next_run_at = schedule.every.next_after(Timestamp::now())?;
+    let mut client = self.pool.get().await?;
+    let tx = client.transaction().await?;
+    if tx.execute(&query, &[&schedule.name, &next_run_at]).await? == 0 {
+      return Ok(false);
+    }
+    self
+      .insert(&tx, schedule.kind, &schedule.payload, Timestamp::now())
+      .await?;
+    tx.commit().await?;
+    Ok(true)
+  }
+
+  /// How long until the first of `names` is due.
+  async fn until_next_schedule(&self, names: &[&str]) -> Result<Option<Duration>, JobError> {
+    let query = format!(
+      r#"
+            select extract(epoch from min(next_run_at) - now())::float8 as seconds
+            from "{schema_name}"."schedule"
+            where name = any($1)
+            "#,
+      schema_name = self.schema_name,
+    );
+    let client = self.pool.get().await?;
+    let row = client.query_one(&query, &[&names]).await?;
+    let seconds: Option<f64> = row.get("seconds");
+    Ok(seconds.map(|seconds| Duration::from_secs_f64(seconds.max(0.0))))
+  }
+}
+
+fn dead_job(row: &Row) -> DeadJob {
+  DeadJob {
+    id: row.get("id"),
+    kind: row.get("kind"),
+    payload: row.get("payload"),
+    attempts: row.get("attempts"),
+    last_error: row.get("last_error"),
+    created_at: row.get("created_at"),
+    failed_at: row.get("failed_at"),
+  }
+}
+
+/// When a schedule enqueues its job. Cron patterns are evaluated in UTC.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+  Every(Duration),
+  Cron(Box<Cron>),
+}
+
+impl Schedule {
+  /// A schedule on a cron pattern, like `"0 3 * * *"` for 03:00 UTC daily.
+  pub fn cron(pattern: &str) -> Result<Self, JobError> {
+    Ok(Self::Cron(Box::new(Cron::from_str(pattern)?)))
+  }
+
+  /// Identifies the schedule, so a changed one replaces the stored run time.
+  fn pattern(&self) -> String {
+    match self {
+      Self::Every(interval) => format!("every {interval:?}"),
+      Self::Cron(cron) => cron.pattern.to_string(),
+    }
+  }
+
+  fn next_after(&self, after: Timestamp) -> Result<Timestamp, JobError> {
+    match self {
+      Self::Every(interval) => Ok(after.saturating_add(*interval).unwrap_or(Timestamp::MAX)),
+      Self::Cron(cron) => {
+        let after =
+          chrono::DateTime::from_timestamp(after.as_second(), after.subsec_nanosecond() as u32)
+            .unwrap_or_default();
+        let next = cron.find_next_occurrence(&after, false)?;
+        Ok(
+          Timestamp::new(next.timestamp(), next.timestamp_subsec_nanos() as i32)
+            .unwrap_or(Timestamp::MAX),
+        )
+      }
+    }
+  }
+}
+
+struct Scheduled {
+  name: String,
+  every: Schedule,
+  kind: &'static str,
+  payload: Value,
+}
+
+/// Runs a job's handler, or fails to decode its payload.
+type Handler<S> = Arc<
+  dyn Fn(S, Value) -> Result<BoxFuture<'static, eyre::Result<()>>, serde_json::Error> + Send + Sync,
+>;
+
+/// Runs the jobs it has handlers for, and enqueues scheduled jobs.
+pub struct Worker<S> {
+  queue: JobQueue,
+  state: S,
+  handlers: HashMap<&'static str, Handler<S>>,
+  schedules: Vec<Scheduled>,
+  election: Option<Election>,
+  listen: Option<tokio_postgres::Config>,
+  concurrency: usize,
+  poll_interval: Duration,
+  lease: Duration,
+  retry_backoff: Duration,
+  max_retry_backoff: Duration,
+}
+
+impl<S: Clone + Send + Sync + 'static> Worker<S> {
+  /// A worker for `queue` whose handlers are passed `state`.
+  pub fn new(queue: JobQueue, state: S) -> Self {
+    Self {
+      queue,
+      state,
+      handlers: HashMap::new(),
+      schedules: Vec::new(),
+      election: None,
+      listen: None,
+      concurrency: 4,
+      poll_interval: Duration::from_secs(5),
+      lease: Duration::from_secs(300),
+      retry_backoff: Duration::from_secs(10),
+      max_retry_backoff: Duration::from_secs(3600),
+    }
+  }
+
+  pub fn from_config(config: &config::Jobs, queue: JobQueue, state: S) -> Self {
+    Self::new(queue, state)
+      .with_concurrency(config.concurrency)
+      .with_poll_interval(config.poll_interval())
+      .with_lease(config.lease())
+      .with_retry_backoff(config.retry_backoff(), config.max_retry_backoff())
+  }
+
+  /// How many jobs run at once.
+  pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+    self.concurrency = concurrency.max(1);
+    self
+  }
+
+  /// How often the queue is checked when no `NOTIFY` arrives, which is what
+  /// picks up jobs that were due later.
+  pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+    self.poll_interval = poll_interval;
+    self
+  }
+
+  /// How long a job may run before other workers consider it abandoned.
+  pub fn with_lease(mut self, lease: Duration) -> Self {
+    self.lease = lease;
+    self
+  }
+
+  /// The delay before the first retry, doubling with each one up to `max`.
+  pub fn with_retry_backoff(mut self, base: Duration, max: Duration) -> Self {
+    self.retry_backoff = base;
+    self.max_retry_backoff = max;
+    self
+  }
+
+  /// `LISTEN`s on a connection of its own made with `config`, to wake up as
+  /// soon as jobs are enqueued.
+  pub fn listen(mut self, config: tokio_postgres::Config) -> Self {
+    self.listen = Some(config);
+    self
+  }
+
+  /// Runs `handler` for jobs of kind `J`. An error fails the attempt.
+  pub fn register<J, F, Fut>(mut self, handler: F) -> Self
+  where
+    J: Job,
+    F: Fn(S, J) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = eyre::Result<()>> + Send + 'static,
+  {
+    let handler: Handler<S> = Arc::new(move |state, payload| {
+      let job = serde_json::from_value::<J>(payload)?;
+      Ok(handler(state, job).boxed())
+    });
+    self.handlers.insert(J::KIND, handler);
+    self
+  }
+
+  /// Enqueues `job` on `schedule`. `name` identifies the schedule across
+  /// replicas and restarts.
+  pub fn schedule<J: Job>(
+    mut self,
+    name: impl Into<String>,
+    schedule: Schedule,
+    job: &J,
+  ) -> Result<Self, JobError> {
+    self.schedules.push(Scheduled {
+      name: name.into(),
+      every: schedule,
+      kind: J::KIND,
+      payload: serde_json::to_value(job)?,
+    });
+    Ok(self)
+  }
+
+  /// Runs the schedules only while this replica leads `election`.
+  pub fn with_election(mut self, election: Election) -> Self {
+    self.election = Some(election);
+    self
+  }
+
+  /// Runs jobs until `stopping` is cancelled, then gives the running ones up
+  /// to `grace` to finish. Jobs cut off are run again once their lease
+  /// expires.
+  pub async fn run(self, stopping: CancellationToken, grace: Duration) {
+    let wake = Arc::new(Notify::new());
+    let mut background = JoinSet::new();
+    if let Some(config) = self.listen.clone() {
+      background.spawn(listen(
+        config,
+        self.queue.channel().to_string(),
+        wake.clone(),
+        self.poll_interval,
+      ));
+    }
+    if !self.schedules.is_empty() {
+      let queue = self.queue.clone();
+      let schedules = Arc::new(self.schedules);
+      let poll_interval = self.poll_interval;
+      match self.election {
+        Some(election) => background.spawn(
+          election.run(move || run_schedules(queue.clone(), schedules.clone(), poll_interval)),
+        ),
+        None => background.spawn(run_schedules(queue, schedules, poll_interval)),
+      };
+    }
+
+    let kinds: Vec<&str> = self.handlers.keys().copied().collect();
+    debug!(?kinds, "starting job worker");
+    let slots = Arc::new(Semaphore::new(self.concurrency));
+    let mut running = JoinSet::new();
+    loop {
+      while running.try_join_next().is_some() {}
+      let slot = tokio::select! {
+        slot = slots.clone().acquire_owned() => slot.expect("the semaphore is never closed"),
+        () = stopping.cancelled() => break,
+      };
+
+      let claimed = match self.queue.claim(&kinds, self.lease).await {
+        Ok(claimed) => claimed,
+        Err(e) => {
+          warn!("failed to claim a job: {e}");
+          None
+        }
+      };
+      if let Some(job) = claimed {
+        let span = info_span!("job", kind = %job.kind, id = %job.id, attempt = job.attempts);
+        let handler = self.handlers[job.kind.as_str()].clone();
+        let state = self.state.clone();
+        let queue = self.queue.clone();
+        let backoff = (self.retry_backoff, self.max_retry_backoff);
+        running.spawn(
+          async move {
+            run_job(queue, handler, state, job, backoff).await;
+            drop(slot);
+          }
+          .instrument(span),
+        );
+        continue;
+      }
+      drop(slot);
+
+      tokio::select! {
+        () = wake.notified() => {},
+        () = tokio::time::sleep(self.poll_interval) => {},
+        () = stopping.cancelled() => break,
+      }
+    }
+
+    background.abort_all();
+    if tokio::time::timeout(grace, async {
+      while running.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+      warn!("abandoning jobs that outlived the shutdown grace period");
+    }
+    info!("job worker stopped");
+  }
+}
+
+async fn run_job<S>(
+  queue: JobQueue,
+  handler: Handler<S>,
+  state: S,
+  job: Claimed,
+  (base, max): (Duration, Duration),
+) {
+  let outcome = match handler(state, job.payload) {
+    Ok(run) => match AssertUnwindSafe(run).catch_unwind().await {
+      Ok(Ok(())) => Ok(()),
+      Ok(Err(e)) => Err(format!("{e:#}")),
+      Err(_) => Err("job panicked".to_string()),
+    },
+    Err(e) => {
+      // Retrying won't make the payload readable.
+      let error = format!("invalid payload: {e}");
+      error!("{error}");
+      match queue.bury(job.id, job.attempts, &error).await {
+        Ok(true) => {}
+        Ok(false) => warn!("job was claimed again before it could be given up on"),
+        Err(e) => error!("failed to move job to the dead letters: {e}"),
+      }
+      return;
+    }
+  };
+
+  let result = match outcome {
+    Ok(()) => {
+      debug!("job done");
+      queue.complete(job.id, job.attempts).await
+    }
+    Err(error) if job.attempts >= job.max_attempts => {
+      error!(%error, "job failed for the last time");
+      queue.bury(job.id, job.attempts, &error).await
+    }
+    Err(error) => {
+      let delay = backoff(job.attempts, base, max);
+      warn!(%error, "job failed, retrying in {delay:?}");
+      queue.retry(job.id, job.attempts, delay, &error).await
+    }
+  };
+  match result {
+    Ok(true) => {}
+    // Whichever worker holds the job now records its outcome.
+    Ok(false) => warn!("job's lease ran out and it was claimed again, dropping this outcome"),
+    // The lease runs out and the job is picked up again.
+    Err(e) => error!("failed to record the job's outcome: {e}"),
+  }
+}
+
+/// The delay before retrying after `attempts` failures.
+fn backoff(attempts: i32, base: Duration, max: Duration) -> Duration {
+  let exponent = u32::try_from(attempts.saturating_sub(1))
+    .unwrap_or(0)
+    .min(31);
+  base.saturating_mul(1 << exponent).min(max)
+}
+
+/// Wakes the worker on every `NOTIFY`, reconnecting when the connection
+/// drops.
+async fn listen(
+  config: tokio_postgres::Config,
+  channel: String,
+  wake: Arc<Notify>,
+  retry_interval: Duration,
+) {
+  loop {
+    match config.connect(NoTls).await {
+      Ok((client, mut connection)) => {
+        let driver = {
+          let wake = wake.clone();
+          tokio::spawn(async move {
+            let mut messages = futures::stream::poll_fn(|cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+              match message {
+                Ok(AsyncMessage::Notification(_)) => wake.notify_one(),
+                Ok(_) => {}
+                Err(e) => return Err(e),
+              }
+            }
+            Ok(())
+          })
+        };
+        match client
+          .batch_execute(&format!(r#"listen "{channel}""#))
+          .await
+        {
+          Ok(()) => {
+            // Catch up on whatever was enqueued while disconnected.
+            wake.notify_one();
+            match driver.await {
+              Ok(Ok(())) => warn!("job notification connection closed"),
+              Ok(Err(e)) => warn!("job notification connection lost: {e}"),
+              Err(e) => warn!("job notification connection failed: {e}"),
+            }
+          }
+          Err(e) => {
+            warn!("failed to listen for job notifications: {e}");
+            driver.abort();
+          }
+        }
+      }
+      Err(e) => warn!("failed to connect for job notifications: {e}"),
+    }
+    tokio::time::sleep(retry_interval).await;
+  }
+}
+
+/// Registers `schedules` and enqueues their jobs when they're due.
+async fn run_schedules(queue: JobQueue, schedules: Arc<Vec<Scheduled>>, poll_interval: Duration) {
+  for schedule in schedules.iter() {
+    if let Err(e) = queue.register_schedule(schedule).await {
+      error!(schedule = %schedule.name, "failed to register schedule: {e}");
+    }
+  }
+  let names: Vec<&str> = schedules.iter().map(|s| s.name.as_str()).collect();
+
+  loop {
+    for schedule in schedules.iter() {
+      match queue.fire_if_due(schedule).await {
+        Ok(true) => debug!(schedule = %schedule.name, "enqueued scheduled job"),
+        Ok(false) => {}
+        Err(e) => warn!(schedule = %schedule.name, "failed to run schedule: {e}"),
+      }
+    }
+    // Another replica may fire first, so this is only when to look again.
+    let wait = match queue.until_next_schedule(&names).await {
+      Ok(Some(wait)) => wait.min(poll_interval),
+      Ok(None) => poll_interval,
+      Err(e) => {
+        warn!("failed to read schedules: {e}");
+        poll_interval
+      }
+    };
+    tokio::time::sleep(wait).await;
+  }
+}
+
+/// Job stats, schedules and dead letters, for the monitoring port.
+pub(crate) fn admin_router(queue: JobQueue) -> Router {
+  Router::new()
+    .route("/jobs", get(overview))
+    .route("/jobs/dead", get(dead_letters))
+    .route("/jobs/dead/{id}/retry", post(retry_dead))
+    .with_state(queue)
+}
+
+#[derive(Serialize)]
+struct Overview {
+  kinds: Vec<KindStats>,
+  schedules: Vec<ScheduleInfo>,
+}
+
+async fn overview(State(queue): State<JobQueue>) -> Result<Json<Overview>, AppError> {
+  Ok(Json(Overview {
+    kinds: queue.stats().await.map_err(internal)?,
+    schedules: queue.schedules().await.map_err(internal)?,
+  }))
+}
+
+#[derive(Deserialize)]
+struct DeadLettersQuery {
+  #[serde(default = "default_dead_limit")]
+  limit: i64,
+}
+
+fn default_dead_limit() -> i64 {
+  100
+}
+
+async fn dead_letters(
+  State(queue): State<JobQueue>,
+  Query(query): Query<DeadLettersQuery>,
+) -> Result<Json<Vec<DeadJob>>, AppError> {
+  let limit = query.limit.clamp(1, 1000);
+  Ok(Json(queue.dead_jobs(limit).await.map_err(internal)?))
+}
+
+async fn retry_dead(
+  State(queue): State<JobQueue>,
+  Path(id): Path<Uuid>,
+) -> Result<Response, AppError> {
+  if queue.retry_dead(id).await.map_err(internal)? {
+    Ok(StatusCode::NO_CONTENT.into_response())
+  } else {
+    Err(AppError::new("no such dead job").with_status(StatusCode::NOT_FOUND))
+  }
+}
+
+fn internal(e: JobError) -> AppError {
+  AppError::new(&e.to_string()).with_status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff_doubles_up_to_the_maximum() {
+    let base = Duration::from_secs(10);
+    let max = Duration::from_secs(60);
+    assert_eq!(backoff(1, base, max), Duration::from_secs(10));
+    assert_eq!(backoff(2, base, max), Duration::from_secs(20));
+    assert_eq!(backoff(3, base, max), Duration::from_secs(40));
+    assert_eq!(backoff(4, base, max), max);
+    assert_eq!(backoff(1000, base, max), max);
+  }
+
+  #[test]
+  fn schedules_find_their_next_run() {
+    let now: Timestamp = "2026-03-01T10:15:30Z".parse().unwrap();
+
+    let every = Schedule::Every(Duration::from_secs(90));
+    assert_eq!(
+      every.next_after(now).unwrap(),
+      "2026-03-01T10:17:00Z".parse::<Timestamp>().unwrap()
+    );
+    assert_eq!(every.pattern(), "every 90s");
+
+    let nightly = Schedule::cron("0 3 * * *").unwrap();
+    assert_eq!(
+      nightly.next_after(now).unwrap(),
+      "2026-03-02T03:00:00Z".parse::<Timestamp>().unwrap()
+    );
+    assert_eq!(nightly.pattern(), "0 3 * * *");
+
+    assert!(Schedule::cron("not a pattern").is_err());
+  }
+}
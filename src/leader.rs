@@ -0,0 +1,245 @@
+//! Electing one replica to run singleton tasks.
+//!
+//! Leadership is a Postgres session-level advisory lock, held on a
+//! connection taken out of the pool. Closing that connection, whether on
+//! purpose or because it or the database went away, hands leadership to
+//! the next replica to ask. The holder checks every few seconds that it
+//! still has the lock and gives up the task when it doesn't, so two replicas
+//! can overlap for at most that long after a network partition.
+
+use std::{
+  sync::{Arc, LazyLock},
+  time::Duration,
+};
+
+use deadpool_postgres::{ClientWrapper, Pool};
+use opentelemetry::{
+  KeyValue,
+  metrics::{Counter, Gauge},
+};
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// How often a held lock is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a replica that isn't leader asks again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Whether this replica leads each election, 1 if it does.
+static ELECTED: LazyLock<Gauge<i64>> = LazyLock::new(|| {
+  opentelemetry::global::meter("{{crate_name}}")
+    .i64_gauge("leader.elected")
+    .with_description("Whether this replica is the leader of the election")
+    .build()
+});
+
+/// Advisory locks found to be gone while they were held.
+static LOST: LazyLock<Counter<u64>> = LazyLock::new(|| {
+  opentelemetry::global::meter("{{crate_name}}")
+    .u64_counter("leader.lost")
+    .with_description("Advisory locks lost while held")
+    .build()
+});
+
+#[derive(thiserror::Error, Debug)]
+pub enum LeaderError {
+  /// A variant to map `tokio_postgres` errors.
+  #[error(transparent)]
+  Postgres(#[from] tokio_postgres::Error),
+
+  /// A variant to map `deadpool_postgres` errors.
+  #[error(transparent)]
+  Pool(#[from] deadpool_postgres::PoolError),
+}
+
+/// A held advisory lock. Dropping it closes its connection, which releases
+/// the lock.
+pub struct Leadership {
+  client: Arc<ClientWrapper>,
+  key: String,
+  lost: CancellationToken,
+  watcher: AbortHandle,
+}
+
+impl Leadership {
+  /// Takes the advisory lock named `key`, unless another session holds it,
+  /// and checks every `check_interval` that it's still held.
+  pub async fn try_acquire(
+    pool: &Pool,
+    key: impl Into<String>,
+    check_interval: Duration,
+  ) -> Result<Option<Self>, LeaderError> {
+    let key = key.into();
+    let client = deadpool_postgres::Object::take(pool.get().await?);
+    let row = client
+      .query_one(
+        "select pg_try_advisory_lock(hashtextextended($1, 0))",
+        &[&key],
+      )
+      .await?;
+    if !row.get::<_, bool>(0) {
+      return Ok(None);
+    }
+
+    let client = Arc::new(client);
+    let lost = CancellationToken::new();
+    let watcher = tokio::spawn(watch(
+      client.clone(),
+      key.clone(),
+      lost.clone(),
+      check_interval,
+    ))
+    .abort_handle();
+    Ok(Some(Self {
+      client,
+      key,
+      lost,
+      watcher,
+    }))
+  }
+
+  /// Resolves once the lock turns out to be gone.
+  pub async fn lost(&self) {
+    self.lost.cancelled().await;
+  }
+
+  pub fn is_lost(&self) -> bool {
+    self.lost.is_cancelled()
+  }
+
+  /// Releases the lock.
+  pub async fn release(self) -> Result<(), LeaderError> {
+    self.watcher.abort();
+    self
+      .client
+      .execute(
+        "select pg_advisory_unlock(hashtextextended($1, 0))",
+        &[&self.key],
+      )
+      .await?;
+    Ok(())
+  }
+}
+
+impl Drop for Leadership {
+  fn drop(&mut self) {
+    self.watcher.abort();
+  }
+}
+
+/// Checks that this session still holds `key`, until it doesn't or the
+/// check fails or hangs.
+async fn watch(
+  client: Arc<ClientWrapper>,
+  key: String,
+  lost: CancellationToken,
+  interval: Duration,
+) {
+  let query = r#"
+        select exists (
+            select 1 from pg_locks
+            where locktype = 'advisory'
+              and pid = pg_backend_pid()
+              and granted
+              and objsubid = 1
+              and (classid::bigint << 32 | objid::bigint) = hashtextextended($1, 0)
+        )
+        "#;
+  loop {
+    tokio::time::sleep(interval).await;
+    match tokio::time::timeout(interval, client.query_one(query, &[&key])).await {
+      Ok(Ok(row)) if row.get::<_, bool>(0) => continue,
+      Ok(Ok(_)) => warn!(lock = %key, "advisory lock is no longer held"),
+      Ok(Err(e)) => warn!(lock = %key, "advisory lock connection failed: {e}"),
+      Err(_) => warn!(lock = %key, "advisory lock check timed out"),
+    }
+    LOST.add(1, &[KeyValue::new("lock", key)]);
+    lost.cancel();
+    return;
+  }
+}
+
+/// Runs a task on whichever replica holds the election's lock.
+pub struct Election {
+  pool: Pool,
+  name: String,
+  check_interval: Duration,
+  retry_interval: Duration,
+}
+
+impl Election {
+  /// An election among the replicas using `name`.
+  pub fn new(pool: Pool, name: impl Into<String>) -> Self {
+    Self {
+      pool,
+      name: name.into(),
+      check_interval: CHECK_INTERVAL,
+      retry_interval: RETRY_INTERVAL,
+    }
+  }
+
+  /// How often the leader checks it still is.
+  pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+    self.check_interval = check_interval;
+    self
+  }
+
+  /// How often the other replicas ask to take over.
+  pub fn with_retry_interval(mut self, retry_interval: Duration) -> Self {
+    self.retry_interval = retry_interval;
+    self
+  }
+
+  /// Runs `task` while this replica is leader, until the future is dropped.
+  /// The task is dropped when leadership is lost, and started again once
+  /// it's won back.
+  pub async fn run<F, Fut>(self, mut task: F)
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+  {
+    let attributes = [KeyValue::new("election", self.name.clone())];
+    ELECTED.record(0, &attributes);
+    loop {
+      match Leadership::try_acquire(&self.pool, self.key(), self.check_interval).await {
+        Ok(Some(leadership)) => {
+          info!(election = %self.name, "elected leader");
+          let _elected = Elected::new(&attributes);
+          tokio::select! {
+            () = task() => debug!(election = %self.name, "leader task finished"),
+            () = leadership.lost() => warn!(election = %self.name, "lost leadership"),
+          }
+          if !leadership.is_lost()
+            && let Err(e) = leadership.release().await
+          {
+            warn!(election = %self.name, "failed to step down: {e}");
+          }
+        }
+        Ok(None) => debug!(election = %self.name, "another replica leads"),
+        Err(e) => warn!(election = %self.name, "failed to run for leader: {e}"),
+      }
+      tokio::time::sleep(self.retry_interval).await;
+    }
+  }
+
+  fn key(&self) -> String {
+    format!("leader:{}", self.name)
+  }
+}
+
+/// Reports this replica as leader until dropped.
+struct Elected<'a>(&'a [KeyValue]);
+
+impl<'a> Elected<'a> {
+  fn new(attributes: &'a [KeyValue]) -> Self {
+    ELECTED.record(1, attributes);
+    Self(attributes)
+  }
+}
+
+impl Drop for Elected<'_> {
+  fn drop(&mut self) {
+    ELECTED.record(0, self.0);
+  }
+}
@@ -1,10 +1,24 @@
+pub mod acme;
 mod app;
+pub mod client_cert;
+mod client_info;
 mod config;
+mod content_type;
+mod csp;
+mod csrf;
 mod error;
+mod http3;
+pub mod jobs;
+pub mod leader;
+mod listen;
 pub mod logging;
 mod pgdb;
+pub mod rate_limit;
+mod redirect;
 mod routes;
 pub mod server;
+pub mod shutdown;
+mod tls;
 pub mod tokio_postgres_sessions;
 
 pub use config::load_config;
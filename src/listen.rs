@@ -0,0 +1,131 @@
+//! Binding the server's TCP and Unix domain socket listeners.
+//!
+//! Each listener binds every address in its `server.*_bind` list, or all IPv4
+//! interfaces on its port when the list is empty. IPv6 sockets are bound
+//! IPv6-only, so `0.0.0.0:8080` and `[::]:8080` can be listed together for a
+//! dual-stack listener and behave the same on every platform.
+
+use std::{
+  io,
+  net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+  path::Path,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Pending connections the kernel queues for each TCP listener.
+const BACKLOG: i32 = 1024;
+
+/// Binds a TCP listener on `addr`.
+pub(crate) fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+  let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+  if addr.is_ipv6() {
+    socket.set_only_v6(true)?;
+  }
+  // Matches `TcpListener::bind`, so restarts don't wait out `TIME_WAIT`.
+  #[cfg(unix)]
+  socket.set_reuse_address(true)?;
+  socket.bind(&addr.into())?;
+  socket.listen(BACKLOG)?;
+  Ok(socket.into())
+}
+
+/// Binds a UDP socket on `addr`, for QUIC.
+pub(crate) fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+  let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+  if addr.is_ipv6() {
+    socket.set_only_v6(true)?;
+  }
+  socket.bind(&addr.into())?;
+  Ok(socket.into())
+}
+
+/// Binds a Unix domain socket listener at `path`, replacing a socket left
+/// behind by a previous run.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path) -> io::Result<tokio::net::UnixListener> {
+  use std::os::unix::fs::FileTypeExt as _;
+
+  match std::fs::symlink_metadata(path) {
+    Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+    // Anything else at the path is left for `bind` to refuse.
+    Ok(_) => {}
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => return Err(e),
+  }
+  tokio::net::UnixListener::bind(path)
+}
+
+/// Checks that no two of the named listener addresses would fight over a
+/// port. Port 0 asks the OS for a free port and never collides.
+pub(crate) fn check_collisions(addrs: &[(&str, SocketAddr)]) -> Result<(), String> {
+  for (i, (name, addr)) in addrs.iter().enumerate() {
+    for (other_name, other) in &addrs[i + 1..] {
+      if overlaps(*addr, *other) {
+        return Err(format!(
+          "{name} address {addr} collides with {other_name} address {other}"
+        ));
+      }
+    }
+  }
+  Ok(())
+}
+
+fn overlaps(a: SocketAddr, b: SocketAddr) -> bool {
+  if a.port() == 0 || a.port() != b.port() {
+    return false;
+  }
+  match (a.ip(), b.ip()) {
+    (IpAddr::V4(x), IpAddr::V4(y)) => x == y || x.is_unspecified() || y.is_unspecified(),
+    (IpAddr::V6(x), IpAddr::V6(y)) => x == y || x.is_unspecified() || y.is_unspecified(),
+    // IPv6 sockets are IPv6-only, see `bind_tcp`.
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn collisions_respect_wildcards_and_families() {
+    let ok = |addrs: &[(&str, &str)]| {
+      let addrs: Vec<_> = addrs.iter().map(|(name, a)| (*name, addr(a))).collect();
+      check_collisions(&addrs).is_ok()
+    };
+
+    assert!(ok(&[("http", "0.0.0.0:8080"), ("https", "0.0.0.0:8443")]));
+    assert!(ok(&[("http", "0.0.0.0:8080"), ("http", "[::]:8080")]));
+    assert!(ok(&[
+      ("http", "127.0.0.1:8080"),
+      ("monitoring", "10.0.0.1:8080")
+    ]));
+    assert!(ok(&[
+      ("http", "127.0.0.1:0"),
+      ("monitoring", "127.0.0.1:0")
+    ]));
+
+    assert!(!ok(&[
+      ("http", "0.0.0.0:8080"),
+      ("monitoring", "127.0.0.1:8080")
+    ]));
+    assert!(!ok(&[("http", "[::1]:8080"), ("https", "[::]:8080")]));
+    assert!(!ok(&[("http", "[::1]:8080"), ("http", "[::1]:8080")]));
+  }
+
+  #[test]
+  fn ipv6_wildcard_binds_alongside_ipv4() {
+    // Skip where the host has no IPv6.
+    if bind_tcp(addr("[::1]:0")).is_err() {
+      return;
+    }
+    let v4 = bind_tcp(addr("0.0.0.0:0")).unwrap();
+    let port = v4.local_addr().unwrap().port();
+    let v6 = bind_tcp(SocketAddr::from(([0u16; 8], port))).unwrap();
+    assert_eq!(v6.local_addr().unwrap().port(), port);
+  }
+}
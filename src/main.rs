@@ -1,7 +1,8 @@
-use {{crate_name}}::{load_config, server};
-use tokio::signal;
-use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info};
+use {{crate_name}}::{
+  load_config, server,
+  shutdown::{Coordinator, Phase},
+};
+use tracing::{debug, error};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -9,7 +10,7 @@ async fn main() -> eyre::Result<()> {
     .install_default()
     .expect("Failed to install AWS LC crypto provider");
 
-  let _tracing_guard = {{crate_name}}::logging::init()
+  let tracing_guard = {{crate_name}}::logging::init()
     .map_err(|e| {
       eprintln!("failed to initialize tracing: {e}");
       e
@@ -23,51 +24,19 @@ async fn main() -> eyre::Result<()> {
 
   debug!("Loaded config");
 
-  let (shutdown_token, signal_handle) = create_shutdown_token();
+  let shutdown = Coordinator::from_config(&app_cfg.shutdown);
+  // Dropping the guard flushes buffered spans, which blocks.
+  shutdown.on(Phase::Telemetry, "traces", move || async move {
+    let _ = tokio::task::spawn_blocking(move || drop(tracing_guard)).await;
+  });
+  let signal_handle = shutdown.listen_for_signals();
 
   debug!("Starting server");
-  server::run(app_cfg, Some(shutdown_token))
-    .await
-    .map_err(|e| {
-      error!("Failed to start server: {e}");
-      e
-    })?;
+  server::run(app_cfg, shutdown).await.map_err(|e| {
+    error!("Failed to start server: {e}");
+    e
+  })?;
 
   signal_handle.abort();
   Ok(())
 }
-
-/// Create a cancellation token that triggers on Ctrl+C or SIGTERM
-fn create_shutdown_token() -> (CancellationToken, tokio::task::JoinHandle<()>) {
-  let token = CancellationToken::new();
-  let token_clone = token.clone();
-
-  let handle = tokio::spawn(async move {
-    let ctrl_c = async {
-      signal::ctrl_c()
-        .await
-        .expect("failed to install Ctrl+C handler");
-    };
-
-    #[cfg(unix)]
-    let terminate = async {
-      signal::unix::signal(signal::unix::SignalKind::terminate())
-        .expect("failed to install signal handler")
-        .recv()
-        .await;
-    };
-
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
-
-    tokio::select! {
-      _ = ctrl_c => {},
-      _ = terminate => {},
-    }
-
-    info!("Shutdown signal received");
-    token_clone.cancel();
-  });
-
-  (token, handle)
-}
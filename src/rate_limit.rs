@@ -0,0 +1,743 @@
+//! Token-bucket rate limiting.
+//!
+//! [`rate_limit_layer`] looks up the `[rate_limit]` policy for a request,
+//! takes a token from the sender's bucket and answers `429 Too Many Requests`
+//! once it is empty. Responses on limited routes carry `RateLimit-*` headers
+//! so well-behaved clients can pace themselves.
+//!
+//! Buckets live in memory by default. With the `postgres` backend they are
+//! rows in a shared table, so a client can't multiply its budget by spreading
+//! requests over replicas.
+
+use std::{
+  collections::HashMap,
+  net::{IpAddr, Ipv6Addr},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use axum::{
+  extract::{Request, State},
+  middleware::Next,
+  response::{IntoResponse as _, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL};
+use deadpool_postgres::Pool;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header::RETRY_AFTER};
+use sha2::{Digest, Sha256};
+use tokio_postgres::error::SqlState;
+use tower_sessions::{Session, session::Id};
+use tracing::{debug, error, warn};
+
+use crate::{
+  client_info::ClientInfo,
+  config::{self, RateLimitBackend, RateLimitKey, RateLimitPolicy},
+  tokio_postgres_sessions::is_valid_identifier,
+};
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+/// An error type for bucket stores.
+#[derive(thiserror::Error, Debug)]
+pub enum RateLimitError {
+  /// A variant to map Postgres driver errors.
+  #[error(transparent)]
+  Postgres(#[from] tokio_postgres::Error),
+
+  /// A variant to map pool acquisition errors.
+  #[error(transparent)]
+  Pool(#[from] deadpool_postgres::PoolError),
+}
+
+/// Validates a `rate_limit.routes` entry.
+pub(crate) fn validate_policy(prefix: &str, policy: &RateLimitPolicy) -> Result<(), String> {
+  if !prefix.starts_with('/') {
+    return Err("the prefix must start with '/'".to_string());
+  }
+  if policy.limit == 0 {
+    return Err("limit must be at least 1".to_string());
+  }
+  if !policy.period.is_positive() {
+    return Err("period must be positive".to_string());
+  }
+  for method in &policy.methods {
+    if Method::from_bytes(method.as_bytes()).is_err() {
+      return Err(format!("'{method}' is not a valid method"));
+    }
+  }
+  Ok(())
+}
+
+/// The result of taking a token from a bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Take {
+  /// Whether there was a token to take.
+  pub allowed: bool,
+  /// The tokens left in the bucket afterwards, possibly fractional.
+  pub tokens: f64,
+}
+
+/// Storage for token buckets.
+#[async_trait]
+pub trait BucketStore: Send + Sync + std::fmt::Debug {
+  /// Refills the bucket under `key`, which holds `limit` tokens and refills
+  /// completely over `period`, then takes a token from it if there is one. A
+  /// new bucket starts out full.
+  async fn take(&self, key: &str, limit: u32, period: Duration) -> Result<Take, RateLimitError>;
+
+  /// Forgets buckets that have refilled completely, which behave exactly like
+  /// new ones.
+  async fn prune(&self) -> Result<(), RateLimitError>;
+}
+
+/// Buckets kept in process memory.
+#[derive(Debug, Default)]
+pub struct MemoryBuckets {
+  buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+  full_at: Instant,
+}
+
+impl Bucket {
+  fn new(limit: u32, now: Instant) -> Self {
+    Self {
+      tokens: f64::from(limit),
+      updated: now,
+      full_at: now,
+    }
+  }
+
+  fn take(&mut self, limit: u32, period: Duration, now: Instant) -> Take {
+    let limit = f64::from(limit);
+    let rate = limit / period.as_secs_f64();
+    let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+    self.tokens = (self.tokens + elapsed * rate).min(limit);
+    self.updated = now;
+    let allowed = self.tokens >= 1.0;
+    if allowed {
+      self.tokens -= 1.0;
+    }
+    self.full_at = now + Duration::from_secs_f64((limit - self.tokens) / rate);
+
+    Take {
+      allowed,
+      tokens: self.tokens,
+    }
+  }
+}
+
+#[async_trait]
+impl BucketStore for MemoryBuckets {
+  async fn take(&self, key: &str, limit: u32, period: Duration) -> Result<Take, RateLimitError> {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+    let bucket = buckets
+      .entry(key.to_string())
+      .or_insert_with(|| Bucket::new(limit, now));
+    Ok(bucket.take(limit, period, now))
+  }
+
+  async fn prune(&self) -> Result<(), RateLimitError> {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+    buckets.retain(|_, bucket| bucket.full_at > now);
+    Ok(())
+  }
+}
+
+/// Buckets kept in a Postgres table, shared by every replica using it. Each
+/// take is a single upsert, so concurrent requests never spend the same
+/// token.
+#[derive(Clone, Debug)]
+pub struct PostgresBuckets {
+  pool: Pool,
+  schema_name: String,
+  table_name: String,
+}
+
+impl PostgresBuckets {
+  /// Create a new bucket store with the provided connection pool.
+  pub fn new(pool: Pool) -> Self {
+    Self {
+      pool,
+      schema_name: "rate_limit".to_string(),
+      table_name: "bucket".to_string(),
+    }
+  }
+
+  /// Set the bucket table schema name with the provided name.
+  pub fn with_schema_name(mut self, schema_name: impl AsRef<str>) -> Result<Self, String> {
+    let schema_name = schema_name.as_ref();
+    if !is_valid_identifier(schema_name) {
+      return Err(format!("Invalid schema name '{schema_name}'"));
+    }
+
+    schema_name.clone_into(&mut self.schema_name);
+    Ok(self)
+  }
+
+  /// Set the bucket table name with the provided name.
+  pub fn with_table_name(mut self, table_name: impl AsRef<str>) -> Result<Self, String> {
+    let table_name = table_name.as_ref();
+    if !is_valid_identifier(table_name) {
+      return Err(format!("Invalid table name '{table_name}'"));
+    }
+
+    table_name.clone_into(&mut self.table_name);
+    Ok(self)
+  }
+
+  /// Migrate the bucket schema.
+  pub async fn migrate(&self) -> Result<(), RateLimitError> {
+    let mut client = self.pool.get().await?;
+    let tx = client.transaction().await?;
+
+    let create_schema_query = format!(
+      r#"create schema if not exists "{schema_name}""#,
+      schema_name = self.schema_name,
+    );
+
+    if let Err(err) = tx.batch_execute(&create_schema_query).await {
+      let duplicate = matches!(
+        err.code(),
+        Some(code) if code == &SqlState::DUPLICATE_SCHEMA || code == &SqlState::UNIQUE_VIOLATION
+      );
+
+      if !duplicate {
+        return Err(err.into());
+      }
+    }
+
+    let create_table_query = format!(
+      r#"
+            create table if not exists "{schema_name}"."{table_name}"
+            (
+                key text primary key not null,
+                tokens double precision not null,
+                capacity double precision not null,
+                rate double precision not null,
+                allowed boolean not null,
+                updated_at timestamptz not null
+            )
+            "#,
+      schema_name = self.schema_name,
+      table_name = self.table_name
+    );
+    tx.batch_execute(&create_table_query).await?;
+
+    tx.commit().await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl BucketStore for PostgresBuckets {
+  async fn take(&self, key: &str, limit: u32, period: Duration) -> Result<Take, RateLimitError> {
+    // The bucket's tokens after refilling for the time since its last take.
+    let refilled = "least(excluded.capacity, bucket.tokens \
+                    + extract(epoch from now() - bucket.updated_at)::float8 * excluded.rate)";
+    let query = format!(
+      r#"
+            insert into "{schema_name}"."{table_name}" as bucket
+              (key, tokens, capacity, rate, allowed, updated_at)
+            values ($1, $2::float8 - 1, $2::float8, $3::float8, true, now())
+            on conflict (key) do update
+            set
+              tokens = {refilled} - case when {refilled} >= 1 then 1 else 0 end,
+              capacity = excluded.capacity,
+              rate = excluded.rate,
+              allowed = {refilled} >= 1,
+              updated_at = now()
+            returning allowed, tokens
+            "#,
+      schema_name = self.schema_name,
+      table_name = self.table_name
+    );
+
+    let capacity = f64::from(limit);
+    let rate = capacity / period.as_secs_f64();
+    let client = self.pool.get().await?;
+    let row = client
+      .query_one(query.as_str(), &[&key, &capacity, &rate])
+      .await?;
+
+    Ok(Take {
+      allowed: row.get(0),
+      tokens: row.get(1),
+    })
+  }
+
+  async fn prune(&self) -> Result<(), RateLimitError> {
+    let query = format!(
+      r#"
+            delete from "{schema_name}"."{table_name}"
+            where tokens + extract(epoch from now() - updated_at)::float8 * rate >= capacity
+            "#,
+      schema_name = self.schema_name,
+      table_name = self.table_name
+    );
+    let client = self.pool.get().await?;
+    client.execute(query.as_str(), &[]).await?;
+    Ok(())
+  }
+}
+
+/// The configured policies and the store holding their buckets.
+#[derive(Debug)]
+pub struct RateLimiter {
+  /// Policies by path prefix, longest prefix first.
+  routes: Vec<(String, Policy)>,
+  store: Arc<dyn BucketStore>,
+}
+
+#[derive(Debug)]
+struct Policy {
+  limit: u32,
+  period: Duration,
+  key: RateLimitKey,
+  /// Empty means every method.
+  methods: Vec<Method>,
+}
+
+impl RateLimiter {
+  /// Builds the limiter for `config`, migrating the bucket table when
+  /// buckets are kept in Postgres.
+  pub async fn from_config(config: &config::RateLimit, pool: Pool) -> eyre::Result<Self> {
+    let store: Arc<dyn BucketStore> = match config.backend {
+      RateLimitBackend::Memory => Arc::new(MemoryBuckets::default()),
+      RateLimitBackend::Postgres => {
+        let store = PostgresBuckets::new(pool)
+          .with_schema_name(&config.schema_name)
+          .map_err(|e| eyre::eyre!(e))?
+          .with_table_name(&config.table_name)
+          .map_err(|e| eyre::eyre!(e))?;
+        store.migrate().await?;
+        Arc::new(store)
+      }
+    };
+    Ok(Self::new(config, store))
+  }
+
+  pub fn new(config: &config::RateLimit, store: Arc<dyn BucketStore>) -> Self {
+    let mut routes: Vec<(String, Policy)> = config
+      .routes
+      .iter()
+      .map(|(prefix, policy)| {
+        let policy = Policy {
+          limit: policy.limit,
+          period: policy.period.unsigned_abs(),
+          key: policy.key,
+          methods: policy
+            .methods
+            .iter()
+            .map(|method| {
+              Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .expect("validated at load time")
+            })
+            .collect(),
+        };
+        (prefix.trim_end_matches('/').to_string(), policy)
+      })
+      .collect();
+    routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+    Self { routes, store }
+  }
+
+  /// Returns the policy for a request and the prefix it is registered
+  /// under.
+  fn policy_for(&self, method: &Method, path: &str) -> Option<(&str, &Policy)> {
+    self
+      .routes
+      .iter()
+      .find(|(prefix, policy)| {
+        path
+          .strip_prefix(prefix.as_str())
+          .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+          && (policy.methods.is_empty() || policy.methods.contains(method))
+      })
+      .map(|(prefix, policy)| (prefix.as_str(), policy))
+  }
+
+  /// Takes a token from the bucket under each of `keys` in turn. The request
+  /// is allowed if every bucket had one, and the buckets after the first
+  /// empty one aren't touched.
+  async fn take(&self, keys: &[String], policy: &Policy) -> Result<Take, RateLimitError> {
+    let mut combined = Take {
+      allowed: true,
+      tokens: f64::from(policy.limit),
+    };
+    for key in keys {
+      let take = self.store.take(key, policy.limit, policy.period).await?;
+      combined = Take {
+        allowed: take.allowed,
+        tokens: combined.tokens.min(take.tokens),
+      };
+      if !take.allowed {
+        break;
+      }
+    }
+    Ok(combined)
+  }
+
+  /// Prunes refilled buckets every `interval`, forever.
+  pub async fn continuously_prune(self: Arc<Self>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+      interval.tick().await;
+      if let Err(e) = self.store.prune().await {
+        warn!("failed to prune rate limit buckets: {e}");
+      }
+    }
+  }
+}
+
+impl Policy {
+  fn rate(&self) -> f64 {
+    f64::from(self.limit) / self.period.as_secs_f64()
+  }
+
+  /// Whole seconds until the bucket holds at least `tokens`, at least one.
+  fn seconds_until(&self, current: f64, tokens: f64) -> u64 {
+    (((tokens - current) / self.rate()).ceil() as u64).max(1)
+  }
+
+  fn insert_headers(&self, headers: &mut HeaderMap, take: Take) {
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+    headers.insert(
+      RATELIMIT_REMAINING,
+      HeaderValue::from(take.tokens.max(0.0).floor() as u64),
+    );
+    headers.insert(
+      RATELIMIT_RESET,
+      HeaderValue::from(self.seconds_until(take.tokens, f64::from(self.limit))),
+    );
+    headers.insert(
+      RATELIMIT_POLICY,
+      HeaderValue::from_str(&format!("{};w={}", self.limit, self.period.as_secs()))
+        .expect("numbers are valid header values"),
+    );
+    if !take.allowed {
+      headers.insert(
+        RETRY_AFTER,
+        HeaderValue::from(self.seconds_until(take.tokens, 1.0)),
+      );
+    }
+  }
+}
+
+/// Middleware applying the rate limit policy matching each request. Requests
+/// go through when the store fails, so an outage there doesn't take the app
+/// down with it.
+///
+/// Clients are told apart by the [`ClientInfo`] that
+/// `crate::client_info::client_info_layer` stores, so this has to run inside
+/// that layer.
+pub async fn rate_limit_layer(
+  State(limiter): State<Arc<RateLimiter>>,
+  request: Request,
+  next: Next,
+) -> Response {
+  let Some((prefix, policy)) = limiter.policy_for(request.method(), request.uri().path()) else {
+    return next.run(request).await;
+  };
+
+  let mut keys = vec![format!("{prefix} {}", network_key(&request))];
+  keys.extend(
+    credential_key(
+      policy.key,
+      request.headers(),
+      request.extensions().get::<Session>(),
+    )
+    .await
+    .map(|key| format!("{prefix} {key}")),
+  );
+  let take = match limiter.take(&keys, policy).await {
+    Ok(take) => take,
+    Err(e) => {
+      error!("failed to apply rate limit, letting the request through: {e}");
+      return next.run(request).await;
+    }
+  };
+
+  let mut response = if take.allowed {
+    next.run(request).await
+  } else {
+    debug!(
+      "rate limited {} {} for {}",
+      request.method(),
+      request.uri(),
+      keys.join(", ")
+    );
+    (StatusCode::TOO_MANY_REQUESTS, "too many requests").into_response()
+  };
+  policy.insert_headers(response.headers_mut(), take);
+  response
+}
+
+/// The bucket every request from the client's network is charged to.
+/// Requests over the Unix socket all come from `127.0.0.1` and share one
+/// network, unless a trusted proxy there forwards the real client address.
+fn network_key(request: &Request) -> String {
+  let network = request
+    .extensions()
+    .get::<ClientInfo>()
+    .and_then(|client| client.ip)
+    .map_or_else(
+      || "unknown".to_string(),
+      |ip| client_network(ip).to_string(),
+    );
+  format!("ip:{network}")
+}
+
+/// The bucket for the credential `key` names, charged on top of the network
+/// bucket. Credentials are hashed so the bucket table never holds them.
+///
+/// API keys aren't validated anywhere, so made-up ones do get buckets of
+/// their own, but only as fast as the network bucket lets requests through.
+/// Sessions only count once the store knows them, so dropping the cookie
+/// doesn't buy a fresh bucket.
+async fn credential_key(
+  key: RateLimitKey,
+  headers: &HeaderMap,
+  session: Option<&Session>,
+) -> Option<String> {
+  let (kind, credential) = match key {
+    RateLimitKey::Ip => return None,
+    RateLimitKey::ApiKey => (
+      "api-key",
+      headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())?
+        .to_string(),
+    ),
+    RateLimitKey::User => ("user", stored_session_id(session?).await?.to_string()),
+  };
+  Some(format!(
+    "{kind}:{}",
+    BASE64_URL.encode(Sha256::digest(credential))
+  ))
+}
+
+/// The id of the session the request carries, if the store has it. Reading
+/// any value loads the session, which forgets an id the store never issued
+/// or has since expired.
+async fn stored_session_id(session: &Session) -> Option<Id> {
+  if let Err(e) = session.get_value("").await {
+    warn!("failed to load the session to rate limit by: {e}");
+    return None;
+  }
+  session.id()
+}
+
+/// IPv6 clients usually get a whole /64, so limiting single addresses would
+/// let them rotate through billions of buckets.
+fn client_network(ip: IpAddr) -> IpAddr {
+  match ip {
+    IpAddr::V4(_) => ip,
+    IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+      Some(v4) => IpAddr::V4(v4),
+      None => IpAddr::V6(Ipv6Addr::from_bits(v6.to_bits() & !u128::from(u64::MAX))),
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::{Router, body::Body, middleware::from_fn_with_state, routing::get};
+  use confique::Config as _;
+  use jiff::SignedDuration;
+  use tower::ServiceExt as _;
+
+  fn limiter(routes: &[(&str, RateLimitPolicy)]) -> RateLimiter {
+    let mut config = config::RateLimit::builder().load().unwrap();
+    config.routes = routes
+      .iter()
+      .map(|(prefix, policy)| (prefix.to_string(), policy.clone()))
+      .collect();
+    RateLimiter::new(&config, Arc::new(MemoryBuckets::default()))
+  }
+
+  fn policy(limit: u32, period: SignedDuration, methods: &[&str]) -> RateLimitPolicy {
+    RateLimitPolicy {
+      limit,
+      period,
+      key: RateLimitKey::Ip,
+      methods: methods.iter().map(|m| m.to_string()).collect(),
+    }
+  }
+
+  #[test]
+  fn buckets_refill_over_the_period() {
+    let start = Instant::now();
+    let period = Duration::from_secs(10);
+    let mut bucket = Bucket::new(2, start);
+
+    assert!(bucket.take(2, period, start).allowed);
+    assert!(bucket.take(2, period, start).allowed);
+    let denied = bucket.take(2, period, start);
+    assert!(!denied.allowed);
+    assert_eq!(denied.tokens, 0.0);
+    assert_eq!(bucket.full_at, start + period);
+
+    // One token comes back every five seconds.
+    assert!(
+      !bucket
+        .take(2, period, start + Duration::from_secs(4))
+        .allowed
+    );
+    assert!(
+      bucket
+        .take(2, period, start + Duration::from_secs(5))
+        .allowed
+    );
+    // Refills stop at the limit.
+    let take = bucket.take(2, period, start + Duration::from_secs(60));
+    assert_eq!(take.tokens, 1.0);
+  }
+
+  #[test]
+  fn policies_match_longest_prefix_and_method() {
+    let limiter = limiter(&[
+      ("/api", policy(100, SignedDuration::from_mins(1), &[])),
+      (
+        "/api/login/",
+        policy(5, SignedDuration::from_mins(15), &["post"]),
+      ),
+    ]);
+    let limit_for = |method: Method, path| {
+      limiter
+        .policy_for(&method, path)
+        .map(|(_, policy)| policy.limit)
+    };
+
+    assert_eq!(limit_for(Method::POST, "/api/login"), Some(5));
+    assert_eq!(limit_for(Method::GET, "/api/login"), Some(100));
+    assert_eq!(limit_for(Method::GET, "/api/layout"), Some(100));
+    assert_eq!(limit_for(Method::GET, "/api"), Some(100));
+    assert_eq!(limit_for(Method::GET, "/apiary"), None);
+    assert_eq!(limit_for(Method::GET, "/"), None);
+  }
+
+  #[test]
+  fn ipv6_clients_are_keyed_by_network() {
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert_eq!(client_network(ip("203.0.113.7")), ip("203.0.113.7"));
+    assert_eq!(
+      client_network(ip("2001:db8:1:2:aaaa:bbbb:cccc:dddd")),
+      ip("2001:db8:1:2::")
+    );
+    assert_eq!(client_network(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+  }
+
+  fn request(ip: [u8; 4], api_key: &'static str) -> Request {
+    let mut request = Request::get("/").body(Body::empty()).unwrap();
+    request.extensions_mut().insert(ClientInfo {
+      ip: Some(ip.into()),
+      scheme: http::uri::Scheme::HTTP,
+      host: None,
+    });
+    request
+      .headers_mut()
+      .insert("x-api-key", HeaderValue::from_static(api_key));
+    request
+  }
+
+  fn api_key_app(limit: u32) -> Router {
+    let limiter = Arc::new(limiter(&[(
+      "/",
+      RateLimitPolicy {
+        key: RateLimitKey::ApiKey,
+        ..policy(limit, SignedDuration::from_mins(1), &[])
+      },
+    )]));
+    Router::new()
+      .route("/", get(|| async { "ok" }))
+      .layer(from_fn_with_state(limiter, rate_limit_layer))
+  }
+
+  #[tokio::test]
+  async fn credential_keys_only_add_to_the_network_limit() {
+    let app = api_key_app(2);
+    let send = |ip, api_key| app.clone().oneshot(request(ip, api_key));
+
+    assert_eq!(
+      send([192, 0, 2, 1], "a").await.unwrap().status(),
+      StatusCode::OK
+    );
+    assert_eq!(
+      send([192, 0, 2, 1], "b").await.unwrap().status(),
+      StatusCode::OK
+    );
+    // Making up another key doesn't get around the network's bucket.
+    assert_eq!(
+      send([192, 0, 2, 1], "c").await.unwrap().status(),
+      StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // A key is limited wherever it's used from.
+    assert_eq!(
+      send([198, 51, 100, 1], "a").await.unwrap().status(),
+      StatusCode::OK
+    );
+    assert_eq!(
+      send([203, 0, 113, 1], "a").await.unwrap().status(),
+      StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(
+      send([203, 0, 113, 1], "d").await.unwrap().status(),
+      StatusCode::OK
+    );
+  }
+
+  #[tokio::test]
+  async fn only_stored_sessions_get_a_bucket() {
+    let store = Arc::new(tower_sessions::MemoryStore::default());
+    let user_key = |session: Session| async move {
+      credential_key(RateLimitKey::User, &HeaderMap::new(), Some(&session)).await
+    };
+
+    let made_up = Session::new(Some(Id::default()), store.clone(), None);
+    assert_eq!(user_key(made_up).await, None);
+
+    let stored = Session::new(None, store.clone(), None);
+    stored.insert("user", 1).await.unwrap();
+    stored.save().await.unwrap();
+    let id = stored.id();
+    let key = user_key(Session::new(id, store, None)).await.unwrap();
+    assert!(key.starts_with("user:"), "{key}");
+    assert_eq!(network_key(&request([192, 0, 2, 1], "a")), "ip:192.0.2.1");
+  }
+
+  #[tokio::test]
+  async fn exhausted_buckets_get_429_with_headers() {
+    let app = api_key_app(2);
+    let send = |api_key| app.clone().oneshot(request([192, 0, 2, 1], api_key));
+
+    let response = send("a").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[RATELIMIT_LIMIT], "2");
+    assert_eq!(response.headers()[RATELIMIT_REMAINING], "1");
+    assert_eq!(response.headers()[RATELIMIT_RESET], "30");
+    assert_eq!(response.headers()[RATELIMIT_POLICY], "2;w=60");
+
+    assert_eq!(send("a").await.unwrap().status(), StatusCode::OK);
+    let response = send("a").await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[RATELIMIT_REMAINING], "0");
+    assert_eq!(response.headers()[RETRY_AFTER], "30");
+  }
+}
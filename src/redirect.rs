@@ -0,0 +1,287 @@
+//! The plain HTTP listener that sends clients to HTTPS when TLS is enabled.
+//!
+//! Redirects keep the path and query and point at the host the client asked
+//! for, or at `server.canonical_host` when one is configured. `GET` and
+//! `HEAD` get a `301`; other methods get a `308` so clients repeat them with
+//! the same method and body.
+//!
+//! With `server.acme_challenge_dir` set, ACME HTTP-01 challenges are answered
+//! from that directory instead of being redirected, for certificates issued
+//! by an external client such as certbot.
+
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+  Router,
+  extract::{Path, State},
+  response::{IntoResponse as _, Response},
+  routing::get,
+};
+use http::{
+  HeaderValue, Method, StatusCode, Uri,
+  header::{CONTENT_TYPE, LOCATION},
+  uri::{Authority, Scheme},
+};
+use tracing::debug;
+
+use crate::client_info::{self, ClientInfo, TrustedProxies};
+
+/// Where ACME clients publish HTTP-01 challenge responses.
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/{token}";
+
+/// Validates `server.canonical_host`: a host name or IP literal, without a
+/// port.
+pub(crate) fn validate_canonical_host(host: &str) -> Result<(), String> {
+  let authority: Authority = host
+    .parse()
+    .map_err(|_| format!("'{host}' is not a valid host"))?;
+  if authority.as_str() != authority.host() {
+    return Err(format!("'{host}' must be a bare host without a port"));
+  }
+  Ok(())
+}
+
+/// How plain HTTP requests are redirected.
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+  https_port: u16,
+  canonical_host: Option<String>,
+  acme_challenge_dir: Option<PathBuf>,
+}
+
+impl HttpsRedirect {
+  /// Redirects to `https_port`, which is left out of URLs when it is 443.
+  pub fn new(https_port: u16) -> Self {
+    Self {
+      https_port,
+      canonical_host: None,
+      acme_challenge_dir: None,
+    }
+  }
+
+  /// Redirects to `host` rather than the host each request asked for.
+  pub fn with_canonical_host(mut self, host: Option<String>) -> Self {
+    self.canonical_host = host;
+    self
+  }
+
+  /// Answers ACME HTTP-01 challenges from the files in `dir`.
+  pub fn with_acme_challenge_dir(mut self, dir: Option<PathBuf>) -> Self {
+    self.acme_challenge_dir = dir;
+    self
+  }
+
+  /// The router for the HTTP listener. Forwarded hosts are believed from
+  /// `proxies`.
+  pub fn router(self, proxies: TrustedProxies) -> Router {
+    let mut router = Router::new();
+    if self.acme_challenge_dir.is_some() {
+      router = router.route(ACME_CHALLENGE_PATH, get(acme_challenge));
+    }
+    router.fallback(redirect).with_state(Arc::new(self)).layer(
+      axum::middleware::from_fn_with_state(Arc::new(proxies), client_info::client_info_layer),
+    )
+  }
+
+  /// The HTTPS URL for a request to `uri` on `host`, or `None` when neither
+  /// the request nor the config says which host to send the client to.
+  fn location(&self, host: Option<&Authority>, uri: &Uri) -> Option<Uri> {
+    let host = match &self.canonical_host {
+      Some(host) => host.as_str(),
+      // `Authority::host` drops any port and userinfo, and keeps the
+      // brackets around IPv6 literals.
+      None => host?.host(),
+    };
+    let authority = match self.https_port {
+      443 => host.to_string(),
+      port => format!("{host}:{port}"),
+    };
+    // Asterisk-form (`OPTIONS *`) has no path to keep.
+    let path_and_query = uri
+      .path_and_query()
+      .map(|pq| pq.as_str())
+      .filter(|pq| pq.starts_with('/'))
+      .unwrap_or("/");
+
+    Uri::builder()
+      .scheme(Scheme::HTTPS)
+      .authority(authority)
+      .path_and_query(path_and_query)
+      .build()
+      .ok()
+  }
+}
+
+async fn redirect(
+  State(redirect): State<Arc<HttpsRedirect>>,
+  client: ClientInfo,
+  method: Method,
+  uri: Uri,
+) -> Response {
+  let Some(location) = redirect.location(client.host.as_ref(), &uri) else {
+    debug!("can't redirect {uri} to HTTPS without a host");
+    return (StatusCode::BAD_REQUEST, "missing Host header").into_response();
+  };
+
+  let status = if method == Method::GET || method == Method::HEAD {
+    StatusCode::MOVED_PERMANENTLY
+  } else {
+    StatusCode::PERMANENT_REDIRECT
+  };
+  let location = HeaderValue::from_str(&location.to_string()).expect("URIs are valid headers");
+  (status, [(LOCATION, location)]).into_response()
+}
+
+async fn acme_challenge(
+  State(redirect): State<Arc<HttpsRedirect>>,
+  Path(token): Path<String>,
+) -> Response {
+  // Tokens are base64url, which also keeps them from escaping the directory.
+  let valid = !token.is_empty()
+    && token
+      .bytes()
+      .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+  let Some(dir) = redirect.acme_challenge_dir.as_ref().filter(|_| valid) else {
+    return StatusCode::NOT_FOUND.into_response();
+  };
+
+  match tokio::fs::read(dir.join(&token)).await {
+    Ok(contents) => (
+      [(CONTENT_TYPE, HeaderValue::from_static("text/plain"))],
+      contents,
+    )
+      .into_response(),
+    Err(e) => {
+      debug!("no ACME challenge response for {token}: {e}");
+      StatusCode::NOT_FOUND.into_response()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::body::{Body, to_bytes};
+  use tower::ServiceExt as _;
+
+  fn location(redirect: &HttpsRedirect, host: Option<&str>, uri: &str) -> Option<String> {
+    let host = host.map(|host| host.parse::<Authority>().unwrap());
+    redirect
+      .location(host.as_ref(), &uri.parse().unwrap())
+      .map(|uri| uri.to_string())
+  }
+
+  #[test]
+  fn locations_keep_path_and_swap_port() {
+    let redirect = HttpsRedirect::new(8443);
+    assert_eq!(
+      location(&redirect, Some("example.com:8080"), "/a/b?c=d").as_deref(),
+      Some("https://example.com:8443/a/b?c=d")
+    );
+    // Ports in the host name itself are left alone.
+    assert_eq!(
+      location(&redirect, Some("host8080.example:8080"), "/").as_deref(),
+      Some("https://host8080.example:8443/")
+    );
+    assert_eq!(
+      location(&redirect, Some("[2001:db8::1]:8080"), "/x").as_deref(),
+      Some("https://[2001:db8::1]:8443/x")
+    );
+    assert_eq!(location(&redirect, None, "/"), None);
+
+    let redirect = HttpsRedirect::new(443);
+    assert_eq!(
+      location(&redirect, Some("example.com"), "*").as_deref(),
+      Some("https://example.com/")
+    );
+    assert_eq!(
+      location(&redirect, Some("[::1]:80"), "/").as_deref(),
+      Some("https://[::1]/")
+    );
+  }
+
+  #[test]
+  fn canonical_host_replaces_request_host() {
+    let redirect = HttpsRedirect::new(443).with_canonical_host(Some("www.example.com".into()));
+    assert_eq!(
+      location(&redirect, Some("example.com"), "/login").as_deref(),
+      Some("https://www.example.com/login")
+    );
+    assert_eq!(
+      location(&redirect, None, "/").as_deref(),
+      Some("https://www.example.com/")
+    );
+
+    assert!(validate_canonical_host("www.example.com").is_ok());
+    assert!(validate_canonical_host("[2001:db8::1]").is_ok());
+    assert!(validate_canonical_host("example.com:443").is_err());
+    assert!(validate_canonical_host("user@example.com").is_err());
+    assert!(validate_canonical_host("").is_err());
+  }
+
+  async fn send(router: &Router, method: Method, uri: &str, host: Option<&str>) -> Response {
+    let mut request = http::Request::builder().method(method).uri(uri);
+    if let Some(host) = host {
+      request = request.header("host", host);
+    }
+    router
+      .clone()
+      .oneshot(request.body(Body::empty()).unwrap())
+      .await
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn status_depends_on_method() {
+    let router = HttpsRedirect::new(8443).router(TrustedProxies::new(Vec::new(), Scheme::HTTP));
+
+    // Origin-form requests only name the host in the `Host` header.
+    let response = send(&router, Method::GET, "/a?b", Some("example.com:8080")).await;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(response.headers()[LOCATION], "https://example.com:8443/a?b");
+
+    let response = send(&router, Method::HEAD, "/", Some("example.com")).await;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+
+    let response = send(&router, Method::POST, "/form", Some("example.com")).await;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+      response.headers()[LOCATION],
+      "https://example.com:8443/form"
+    );
+
+    let response = send(&router, Method::GET, "/", None).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn acme_challenges_pass_through() {
+    let dir = std::env::temp_dir().join(format!("acme-{}", uuid::Uuid::now_v7().simple()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("tok-EN_1"), "tok-EN_1.thumbprint").unwrap();
+    let proxies = TrustedProxies::new(Vec::new(), Scheme::HTTP);
+
+    let router = HttpsRedirect::new(443)
+      .with_acme_challenge_dir(Some(dir.clone()))
+      .router(proxies.clone());
+    let path = "/.well-known/acme-challenge/tok-EN_1";
+    let response = send(&router, Method::GET, path, Some("example.com")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"tok-EN_1.thumbprint");
+
+    let missing = "/.well-known/acme-challenge/missing";
+    let response = send(&router, Method::GET, missing, Some("example.com")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let escape = "/.well-known/acme-challenge/..%2Fsecret";
+    let response = send(&router, Method::GET, escape, Some("example.com")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Without a directory, challenges are redirected like everything else.
+    let router = HttpsRedirect::new(443).router(proxies);
+    let response = send(&router, Method::GET, path, Some("example.com")).await;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
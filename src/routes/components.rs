@@ -1,4 +1,11 @@
 use hypertext::{define_elements, prelude::*};
+use tracing::warn;
+
+use crate::{
+  assets::{asset_integrity, asset_url},
+  csp::csp_nonce,
+  csrf::{self, csrf_token},
+};
 
 define_elements! {
   ph_circle_half {
@@ -23,6 +30,49 @@ define_elements! {
   }
 }
 
+/// Renders the tag that loads a frontend asset: a stylesheet `<link>` for CSS
+/// and a deferred module `<script>` for JavaScript. Both carry the asset's SRI
+/// digest, so the browser refuses a copy that doesn't match the build. Other
+/// kinds of asset have no tag and are left out with a warning.
+#[component]
+pub fn asset_tag<'a>(name: &'a str) -> impl Renderable {
+  let url = asset_url(name);
+  let integrity = asset_integrity(name);
+  let is_stylesheet = name.ends_with(".css");
+  let is_script = name.ends_with(".js");
+  if !is_stylesheet && !is_script {
+    warn!(
+      asset = name,
+      "no tag loads this kind of asset, leaving it out"
+    );
+  }
+
+  maud! {
+    @if is_stylesheet {
+      link rel="stylesheet" href=(url) integrity=[integrity.as_deref()] crossorigin="anonymous";
+    } @else if is_script {
+      script src=(url) integrity=[integrity.as_deref()] crossorigin="anonymous" defer=true type="module" {}
+    }
+  }
+}
+
+/// A `<form>` posting to `action` on this app, with the CSRF token in a
+/// hidden field so the post gets past `csrf_layer`. Use it for every plain
+/// HTML form; htmx requests send the token from the `document` body instead.
+#[component]
+pub fn form<'a, R: Renderable>(action: &'a str, children: &R) -> impl Renderable {
+  let token = csrf_token();
+
+  maud! {
+    form method="post" action=(action) {
+      @if let Some(token) = &token {
+        input type="hidden" name=(csrf::FIELD_NAME) value=(token);
+      }
+      (children)
+    }
+  }
+}
+
 #[component]
 pub fn document<R: Renderable>(children: &R) -> impl Renderable {
   maud! {
@@ -32,12 +82,25 @@ pub fn document<R: Renderable>(children: &R) -> impl Renderable {
         meta charset="utf-8";
         meta name="viewport" content="width=device-width, initial-scale=1";
         title { "{{project-name}}" }
-        link rel="stylesheet" href="/static/css/main.css";
-        script src="/static/js/main.js" defer=true type="module" {}
+        // Lets htmx mark inline scripts in swapped content as trusted.
+        meta name="htmx-config" content=(htmx_config());
+        AssetTag name="css/main.css";
+        AssetTag name="js/main.js";
       }
-      body class="min-h-screen bg-base-100 text-base-content overflow-x-hidden" x-data="layoutState" x-init="init()" @mousemove.window="doResize($event)" @mouseup.window="stopResize()" {
+      body hx-headers=[hx_headers()] class="min-h-screen bg-base-100 text-base-content overflow-x-hidden" x-data="layoutState" x-init="init()" @mousemove.window="doResize($event)" @mouseup.window="stopResize()" {
         (children)
       }
     }
   }
 }
+
+/// The `htmx-config` meta content for the current request.
+fn htmx_config() -> String {
+  serde_json::json!({ "inlineScriptNonce": csp_nonce().unwrap_or_default() }).to_string()
+}
+
+/// The `hx-headers` attribute that makes htmx send the CSRF token with every
+/// request.
+fn hx_headers() -> Option<String> {
+  csrf_token().map(|token| serde_json::json!({ csrf::HEADER_NAME: token }).to_string())
+}
@@ -18,6 +18,8 @@ use uuid::Uuid;
 
 use crate::{
   app::AppState,
+  client_cert::ClientCert,
+  csrf,
   pgdb::{GetLayoutState, SaveLayoutState},
 };
 
@@ -35,17 +37,24 @@ where
   #[allow(clippy::manual_async_fn)]
   fn from_request_parts(
     parts: &mut Parts,
-    _state: &S,
+    state: &S,
   ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
     async move {
-      let user_id = parts
+      // Callers with a verified client certificate don't need a key, and a
+      // key never overrides the certificate. The prefixes keep a key from
+      // passing for a certificate identity.
+      let Ok(client_cert) = Option::<ClientCert>::from_request_parts(parts, state).await;
+      let api_key = parts
         .headers
         .get("x-api-key")
         .and_then(|value| value.to_str().ok())
         .map(|value| value.trim())
-        .filter(|value| !value.is_empty())
-        .unwrap_or(ANONYMOUS_USER_ID)
-        .to_string();
+        .filter(|value| !value.is_empty());
+      let user_id = match (&client_cert, api_key) {
+        (Some(cert), _) => format!("cert:{}", cert.identity()),
+        (None, Some(key)) => format!("key:{key}"),
+        (None, None) => ANONYMOUS_USER_ID.to_string(),
+      };
 
       Ok(ApiUserId(user_id))
     }
@@ -85,6 +94,7 @@ pub fn router(app: AppState) -> Router<AppState> {
   Router::new()
     .route("/api/layout", layout_route)
     .merge(pages::routes(app.clone()))
+    .layer(axum::middleware::from_fn(csrf::csrf_layer))
     .layer(AutoVaryLayer)
     .layer(OtelInResponseLayer)
     .layer(OtelAxumLayer::default())
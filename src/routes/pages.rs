@@ -1,12 +1,31 @@
 use crate::routes::components::*;
-use axum::{Router, response::IntoResponse, routing::get};
+use axum::{
+  Router,
+  response::{IntoResponse, Redirect},
+  routing::{get, post},
+};
 use axum_htmx::HxRequest;
+use http::StatusCode;
 use hypertext::prelude::*;
+use tower_sessions::Session;
+use tracing::error;
 
 use crate::app::AppState;
 
 pub fn routes(app: AppState) -> Router<AppState> {
-  Router::new().route("/", get(index_page)).with_state(app)
+  Router::new()
+    .route("/", get(index_page))
+    .route("/sign-out", post(sign_out))
+    .with_state(app)
+}
+
+/// Ends the session and goes back to the index page.
+async fn sign_out(session: Session) -> impl IntoResponse {
+  if let Err(e) = session.flush().await {
+    error!("failed to end session: {e}");
+    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+  }
+  Redirect::to("/").into_response()
 }
 fn maybe_document<R: Renderable>(
   HxRequest(is_hx_request): HxRequest,
@@ -47,7 +66,7 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
               span { "!" }
             }
           }
-          details class="dropdown dropdown-end dropdown-right px-1" data-profile-dropdown @click.outside="$el.removeAttribute('open')" {
+          details class="dropdown dropdown-end dropdown-right px-1" data-profile-dropdown @click.outside="closeDropdown($el)" {
             summary
               id="profile-toggle"
               aria-haspopup="true"
@@ -66,7 +85,11 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
             {
               li { a role="menuitem" class="active:bg-base-200" { "Profile" } }
               li { a role="menuitem" class="active:bg-base-200" { "Settings" } }
-              li { a role="menuitem" class="active:bg-base-200" { "Sign out" } }
+              li {
+                Form action="/sign-out" {
+                  button type="submit" role="menuitem" class="active:bg-base-200" { "Sign out" }
+                }
+              }
             }
           }
         }
@@ -76,7 +99,7 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
             div class="flex items-center justify-between gap-4" {
               div class="flex items-center gap-3" {
                 button
-                  @click="if (window.innerWidth < 1024 && !leftSidebar) rightSidebar = false; leftSidebar = !leftSidebar"
+                  @click="toggleSidebar('left')"
                   class="btn btn-sm btn-ghost btn-square lg:hidden"
                   aria-label="Toggle left sidebar"
                 {
@@ -104,7 +127,7 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
                   ph-moon x-show="theme === 'dark'" size="20" {}
                 }
                 button
-                  @click="if (window.innerWidth < 1024 && !rightSidebar) leftSidebar = false; rightSidebar = !rightSidebar"
+                  @click="toggleSidebar('right')"
                   class="btn btn-sm btn-ghost btn-square lg:hidden"
                   aria-label="Toggle right sidebar"
                 {
@@ -137,14 +160,14 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
             aside
               aria-label="Left sidebar"
               x-show="leftSidebar"
-              @click.outside="if (window.innerWidth < 1024) leftSidebar = false"
+              @click.outside="closeSidebarOnMobile('left')"
               "x-transition:enter"="transition-all ease-out duration-300"
               "x-transition:enter-start"="-ml-80 opacity-0"
               "x-transition:enter-end"="ml-0 opacity-100"
               "x-transition:leave"="transition-all ease-in duration-300"
               "x-transition:leave-start"="ml-0 opacity-100"
               "x-transition:leave-end"="-ml-80 opacity-0"
-              :style="`width: ${leftWidth}px`"
+              :style="sidebarStyle('left')"
               class="border-r border-base-300 bg-base-200 overflow-y-auto shrink-0 fixed lg:relative left-0 top-0 bottom-0 z-30 lg:z-auto"
             {
               div class="p-4 space-y-6" {
@@ -251,14 +274,14 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
             aside
               aria-label="Right sidebar"
               x-show="rightSidebar"
-              @click.outside="if (window.innerWidth < 1024) rightSidebar = false"
+              @click.outside="closeSidebarOnMobile('right')"
               "x-transition:enter"="transition-all ease-out duration-300"
               "x-transition:enter-start"="-mr-80 opacity-0"
               "x-transition:enter-end"="mr-0 opacity-100"
               "x-transition:leave"="transition-all ease-in duration-300"
               "x-transition:leave-start"="mr-0 opacity-100"
               "x-transition:leave-end"="-mr-80 opacity-0"
-              :style="`width: ${rightWidth}px`"
+              :style="sidebarStyle('right')"
               class="border-l border-base-300 bg-base-200 overflow-y-auto shrink-0 fixed lg:relative right-0 top-0 bottom-0 z-30 lg:z-auto"
             {
               div
@@ -314,7 +337,7 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
             span class="text-xl" { "!" }
           }
         }
-        details class="dropdown dropdown-top relative" data-profile-dropdown-mobile @click.outside="$el.removeAttribute('open')" {
+        details class="dropdown dropdown-top relative" data-profile-dropdown-mobile @click.outside="closeDropdown($el)" {
           summary
             aria-haspopup="true"
             aria-label="Profile menu"
@@ -329,7 +352,11 @@ async fn index_page(hx_request: HxRequest) -> impl IntoResponse {
           {
             li { a role="menuitem" class="active:bg-base-200" { "Profile" } }
             li { a role="menuitem" class="active:bg-base-200" { "Settings" } }
-            li { a role="menuitem" class="active:bg-base-200" { "Sign out" } }
+            li {
+              Form action="/sign-out" {
+                button type="submit" role="menuitem" class="active:bg-base-200" { "Sign out" }
+              }
+            }
           }
         }
       }
@@ -1,57 +1,101 @@
 use std::{
-  net::{Ipv4Addr, SocketAddr, TcpListener},
+  net::{SocketAddr, TcpListener},
+  ops::Bound,
   sync::Arc,
   time::Duration,
 };
 
 use axum::{
-  BoxError, Router,
-  extract::{Path, Request as AxumRequest, State},
-  handler::HandlerWithoutStateExt as _,
-  middleware::{self, Next},
+  Router,
+  extract::{Path, State},
   response::{IntoResponse as _, Redirect, Response},
   routing::get,
 };
 
-use axum_helmet::{
-  ContentSecurityPolicy, CrossOriginOpenerPolicy, CrossOriginResourcePolicy, Helmet, HelmetLayer,
-  OriginAgentCluster, ReferrerPolicy, StrictTransportSecurity, XContentTypeOptions,
-  XDNSPrefetchControl, XDownloadOptions, XFrameOptions, XPermittedCrossDomainPolicies,
-  XXSSProtection,
-};
+use axum_helmet::HelmetLayer;
 use axum_otel_metrics::{HttpMetricsLayer, HttpMetricsLayerBuilder};
 use axum_server::{Handle, tls_rustls::RustlsConfig};
 use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
-use futures::StreamExt;
+use deadpool_postgres::Pool;
+use futures::{FutureExt as _, StreamExt, future::BoxFuture};
+use headers::{
+  AcceptRanges, ContentRange, HeaderMapExt as _, IfModifiedSince, IfNoneMatch, IfRange,
+  LastModified, Range,
+};
 #[cfg(debug_assertions)]
 use http::Request;
 use http::{
-  HeaderMap, HeaderValue, StatusCode, Uri,
-  header::{CONTENT_ENCODING, CONTENT_TYPE},
+  HeaderMap, HeaderValue, StatusCode,
+  header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, VARY},
+  uri::Scheme,
 };
 use listenfd::ListenFd;
-use rustls::ServerConfig;
-use rustls_acme::{AcmeConfig, caches::DirCache};
-use tokio::{
-  signal,
-  task::{AbortHandle, JoinHandle},
-  time::sleep,
+use rustls::{ServerConfig, server::ResolvesServerCert};
+use rustls_acme::{acme::ACME_TLS_ALPN_NAME, caches::DirCache};
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinHandle, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tower_http::compression::{
+  CompressionLayer,
+  predicate::{And, DefaultPredicate, Predicate},
 };
-use tower_http::compression::CompressionLayer;
 use tower_sessions::{SessionManagerLayer, session_store::ExpiredDeletion};
 use tracing::{debug, error, info, warn};
 
-use crate::{app::AppState, config::AppConfig, tokio_postgres_sessions::PostgresStore};
+use crate::{
+  acme::{AcmeSettings, PostgresAcme, PostgresAcmeCache},
+  app::AppState,
+  assets::{AssetVersion, Encoding},
+  client_cert::{self, ClientCertAcceptor},
+  client_info::{self, TrustedProxies},
+  config::{AcmeBackend, AppConfig},
+  csp, http3,
+  jobs::{self, Job, JobQueue, Schedule, Worker},
+  leader::Election,
+  listen,
+  rate_limit::{self, RateLimiter},
+  redirect::HttpsRedirect,
+  shutdown::{Coordinator, Phase},
+  tls::{self, KeypairResolver},
+  tokio_postgres_sessions::PostgresStore,
+};
+
+#[cfg(unix)]
+use crate::client_info::UnixPeer;
+
+/// Purges expired sessions, every `session.deletion_interval`.
+#[derive(Serialize, Deserialize)]
+struct DeleteExpiredSessions;
 
-fn build_admin_router() -> Router {
-  Router::new().route("/healthz", get(|| async { "OK" }))
+impl Job for DeleteExpiredSessions {
+  const KIND: &'static str = "delete_expired_sessions";
+}
+
+fn build_admin_router(shutdown: Coordinator, jobs: JobQueue) -> Router {
+  Router::new()
+    .route("/healthz", get(|| async { "OK" }))
+    .route(
+      "/readyz",
+      get(move || async move {
+        if shutdown.is_ready() {
+          (StatusCode::OK, "OK")
+        } else {
+          (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+        }
+      }),
+    )
+    .merge(jobs::admin_router(jobs))
 }
 
 const IDX_HTTP: usize = 0;
 const IDX_HTTPS: usize = 1;
 const IDX_MONITORING: usize = 2;
 
-async fn build_app(state: AppState) -> eyre::Result<Router> {
+async fn build_app(
+  state: AppState,
+  config: &AppConfig,
+  rate_limiter: Arc<RateLimiter>,
+) -> eyre::Result<Router> {
   debug!("Building server app");
 
   // Generate OpenAPI and compose routes
@@ -59,47 +103,38 @@ async fn build_app(state: AppState) -> eyre::Result<Router> {
 
   debug!("Created server routes");
 
-  // Global layers - CSP configured for Alpine.js
-  #[cfg(debug_assertions)]
-  let script_src = vec!["'self'", "'unsafe-eval'", "'unsafe-inline'"];
-  #[cfg(not(debug_assertions))]
-  let script_src = vec!["'self'", "'unsafe-eval'"];
-
-  let csp = ContentSecurityPolicy::new()
-    .default_src(vec!["'self'"])
-    .base_uri(vec!["'self'"])
-    .font_src(vec!["'self'", "https:", "data:"])
-    .form_action(vec!["'self'"])
-    .frame_ancestors(vec!["'self'"])
-    .img_src(vec!["'self'", "data:"])
-    .object_src(vec!["'none'"])
-    .script_src(script_src)
-    .script_src_attr(vec!["'unsafe-eval'"])
-    .style_src(vec!["'self'", "https:", "'unsafe-inline'"])
-    .upgrade_insecure_requests();
-
-  let helmet = Helmet::new()
-    .add(csp)
-    .add(CrossOriginOpenerPolicy::same_origin())
-    .add(CrossOriginResourcePolicy::same_origin())
-    .add(OriginAgentCluster::new(true))
-    .add(ReferrerPolicy::no_referrer())
-    .add(
-      StrictTransportSecurity::new()
-        .max_age(15552000)
-        .include_sub_domains(),
-    )
-    .add(XContentTypeOptions::nosniff())
-    .add(XDNSPrefetchControl::off())
-    .add(XDownloadOptions::noopen())
-    .add(XFrameOptions::same_origin())
-    .add(XPermittedCrossDomainPolicies::none())
-    .add(XXSSProtection::off());
+  // Global layers. The CSP is per request, see `crate::csp`.
+  let helmet = config.security.helmet();
+  let csp_policies = Arc::new(csp::CspPolicies::from_config(&config.security));
+  let local_scheme = if config.server.tls_enabled {
+    Scheme::HTTPS
+  } else {
+    Scheme::HTTP
+  };
+  let trusted_proxies = Arc::new(
+    TrustedProxies::new(config.server.trusted_proxies(), local_scheme)
+      .with_forwarded_headers(config.server.forwarded_headers),
+  );
 
   let app = Router::new()
     .merge(protected_routes)
     .nest_service("/static", static_file_handler(state.clone()))
+    .merge(csp::report_router())
+    .layer(axum::middleware::from_fn_with_state(
+      rate_limiter,
+      rate_limit::rate_limit_layer,
+    ))
     .layer(HelmetLayer::new(helmet))
+    // Outside the helmet so per-route CSP overrides can drop its headers.
+    .layer(axum::middleware::from_fn_with_state(
+      csp_policies,
+      csp::csp_layer,
+    ))
+    // Outermost after tracing, so everything below sees the real client.
+    .layer(axum::middleware::from_fn_with_state(
+      trusted_proxies,
+      client_info::client_info_layer,
+    ))
     .layer(OtelInResponseLayer)
     .layer(OtelAxumLayer::default())
     .layer(metrics_layer())
@@ -109,99 +144,188 @@ async fn build_app(state: AppState) -> eyre::Result<Router> {
   Ok(app)
 }
 
-#[derive(Clone, Copy)]
-struct Ports {
-  http: u16,
-  https: u16,
-}
-
 #[cfg(debug_assertions)]
 fn not_htmx_predicate<T>(req: &Request<T>) -> bool {
   !req.headers().contains_key("hx-request")
 }
 
-pub async fn run(
-  args: crate::config::AppConfig,
-  shutdown_token: Option<tokio_util::sync::CancellationToken>,
-) -> eyre::Result<()> {
+/// Serves the app until `shutdown` has been requested and has run its
+/// phases.
+pub async fn run(args: crate::config::AppConfig, shutdown: Coordinator) -> eyre::Result<()> {
   debug!("Entering run function");
   // Extract all needed fields from args first
   let tls_enabled = args.server.tls_enabled;
-  let http_port = args.server.http_port;
-  let https_port = args.server.https_port;
+
+  let state = AppState::new(&args).await?;
+  let pool = state.pgdb();
+  shutdown.on(Phase::Resources, "Postgres pool", move || async move {
+    pool.close();
+  });
 
   debug!("Enabling TLS: {tls_enabled}");
-  // Get TLS config if needed (before moving indexer)
   let tls_config_result = if tls_enabled {
-    Some(make_tls_config(&args.server).await?)
+    let (tls_config, certificates) = make_tls_config(&args, state.pgdb()).await?;
+    shutdown.abort_on_shutdown("TLS certificates", certificates.abort_handle());
+    Some(tls_config)
   } else {
     None
   };
 
-  let state = AppState::new(&args).await?;
-
-  let session_store = PostgresStore::new(state.pgdb());
+  let session_cfg = &args.session;
+  let session_store = PostgresStore::new(state.pgdb())
+    .with_schema_name(&session_cfg.schema_name)
+    .map_err(|e| eyre::eyre!(e))?
+    .with_table_name(&session_cfg.table_name)
+    .map_err(|e| eyre::eyre!(e))?;
   session_store.migrate().await?;
 
-  let deletion_task = tokio::task::spawn(
-    session_store
+  state.jobs().migrate().await?;
+  let deletion_store = session_store.clone();
+  let worker = Worker::from_config(&args.jobs, state.jobs().clone(), state.clone())
+    .listen(args.postgres.url.parse()?)
+    .with_election(Election::new(
+      state.pgdb(),
+      format!("{}.schedule", args.jobs.schema_name),
+    ))
+    .register(move |_, DeleteExpiredSessions| {
+      let store = deletion_store.clone();
+      async move { Ok(store.delete_expired().await?) }
+    })
+    .schedule(
+      "session deletion",
+      Schedule::Every(session_cfg.deletion_interval()),
+      &DeleteExpiredSessions,
+    )?;
+  let jobs_stopping = CancellationToken::new();
+  let worker = tokio::spawn(worker.run(jobs_stopping.clone(), shutdown.drain_timeout()));
+  shutdown.on(Phase::BackgroundTasks, "job worker", move || async move {
+    jobs_stopping.cancel();
+    let _ = worker.await;
+  });
+
+  let rate_limiter = Arc::new(RateLimiter::from_config(&args.rate_limit, state.pgdb()).await?);
+  let prune_task = tokio::task::spawn(
+    rate_limiter
       .clone()
-      .continuously_delete_expired(tokio::time::Duration::from_secs(60)),
+      .continuously_prune(args.rate_limit.prune_interval()),
   );
+  shutdown.abort_on_shutdown("rate limit pruning", prune_task.abort_handle());
 
   let server_handle = Handle::new();
 
-  let session_layer = SessionManagerLayer::new(session_store)
-    .with_secure(true)
+  let mut session_layer = SessionManagerLayer::new(session_store)
+    .with_name(session_cfg.cookie_name.clone())
+    .with_path(session_cfg.path.clone())
+    .with_secure(session_cfg.secure)
     .with_http_only(true)
-    .with_same_site(tower_sessions::cookie::SameSite::Lax);
+    .with_same_site(session_cfg.same_site.into())
+    .with_expiry(session_cfg.tower_expiry());
+  if let Some(domain) = &session_cfg.domain {
+    session_layer = session_layer.with_domain(domain.clone());
+  }
 
-  let app = build_app(state.clone()).await?.layer(session_layer);
+  let app = build_app(state.clone(), &args, rate_limiter)
+    .await?
+    .layer(session_layer);
   debug!("App built, config: {args:?}");
 
+  // The watcher has to outlive the server, so it is held until `run` returns.
   #[cfg(debug_assertions)]
-  let app = {
-    use notify::Watcher;
+  let (app, _asset_watcher) = {
+    use crate::assets::{FRONTEND_DIR, PUBLIC_DIR};
+    use std::path::Path;
+
     let livereload = tower_livereload::LiveReloadLayer::new().request_predicate(not_htmx_predicate);
     let reloader = livereload.reloader();
-    let mut watcher = notify::recommended_watcher(move |_| reloader.reload()).unwrap();
-    watcher
-      .watch(
-        std::path::Path::new("public"),
-        notify::RecursiveMode::Recursive,
-      )
-      .unwrap();
+    let watcher = crate::assets::watch(
+      state.assets(),
+      &[Path::new(FRONTEND_DIR), Path::new(PUBLIC_DIR)],
+      move || reloader.reload(),
+    )?;
 
     info!("Reloading!");
-    app.layer(livereload)
+    (app.layer(livereload), watcher)
   };
 
-  let app = app.layer(CompressionLayer::new().quality(tower_http::CompressionLevel::Default));
+  let app = app.layer(compression_layer());
 
   // Prepare listenfd and start admin server
   let mut listenfd = prepare_listenfd();
-  start_admin_server(&mut listenfd, &args, server_handle.clone())?;
+  // Stops the Unix socket and HTTP/3 servers, which `server_handle` doesn't
+  // reach.
+  let stopping = CancellationToken::new();
+  // The monitoring servers keep running while connections drain, so
+  // `/readyz` can report that the app is going away.
+  let admin_handle = Handle::new();
+  let admin_stopping = CancellationToken::new();
+  start_admin_server(
+    &mut listenfd,
+    &args,
+    admin_handle.clone(),
+    admin_stopping.clone(),
+    shutdown.clone(),
+    state.jobs().clone(),
+  )?;
+  shutdown.on(
+    Phase::BackgroundTasks,
+    "monitoring servers",
+    move || async move {
+      admin_handle.shutdown();
+      admin_stopping.cancel();
+    },
+  );
+
+  #[cfg(unix)]
+  let unix_server = args
+    .server
+    .unix_socket
+    .as_deref()
+    .map(|path| {
+      spawn_unix_server(
+        path,
+        app.clone(),
+        stopping.clone(),
+        shutdown.drain_timeout(),
+        "app",
+      )
+    })
+    .transpose()?;
+  #[cfg(unix)]
+  if let Some(unix_server) = unix_server {
+    drain_servers(&shutdown, "app Unix socket connections", vec![unix_server]);
+  }
 
-  tokio::spawn(graceful_shutdown(
-    server_handle.clone(),
-    deletion_task.abort_handle(),
-    shutdown_token.clone(),
-  ));
+  register_app_shutdown(&shutdown, server_handle.clone(), stopping.clone());
+  let coordinator = tokio::spawn({
+    let shutdown = shutdown.clone();
+    async move { shutdown.run().await }
+  });
 
-  if let Some((tls_config, _jh)) = tls_config_result {
+  if let Some(tls_config) = tls_config_result {
+    let redirect = HttpsRedirect::new(args.server.public_https_port())
+      .with_canonical_host(args.server.canonical_host.clone())
+      .with_acme_challenge_dir(args.server.acme_challenge_dir.clone())
+      .router(
+        TrustedProxies::new(args.server.trusted_proxies(), Scheme::HTTP)
+          .with_forwarded_headers(args.server.forwarded_headers),
+      );
     run_tls(
       app,
       &mut listenfd,
-      http_port,
-      https_port,
+      &args.server,
       server_handle.clone(),
+      stopping,
+      &shutdown,
       tls_config,
+      redirect,
     )
     .await?;
   } else {
-    run_http(app, &mut listenfd, http_port, server_handle.clone()).await?;
+    run_http(app, &mut listenfd, &args.server, server_handle.clone()).await?;
   }
 
+  coordinator.await?;
+
   debug!("Server run function completing");
   Ok(())
 }
@@ -214,32 +338,52 @@ fn prepare_listenfd() -> ListenFd {
   ListenFd::from_env()
 }
 
-fn acquire_listener(
+fn acquire_listeners(
   listenfd: &mut ListenFd,
   idx: usize,
-  port: u16,
+  addrs: &[SocketAddr],
   name: &str,
-) -> eyre::Result<TcpListener> {
+) -> eyre::Result<Vec<TcpListener>> {
+  // A socket handed over by systemd or `systemfd` replaces the configured
+  // addresses.
   if let Some(l) = listenfd.take_tcp_listener(idx)? {
-    return Ok(l);
+    return Ok(vec![l]);
   }
-  let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
-  TcpListener::bind(addr).map_err(|e| eyre::eyre!("failed to bind {name} to {}: {}", addr, e))
+  addrs
+    .iter()
+    .map(|addr| {
+      listen::bind_tcp(*addr).map_err(|e| eyre::eyre!("failed to bind {name} to {addr}: {e}"))
+    })
+    .collect()
 }
 
 fn start_admin_server(
   listenfd: &mut ListenFd,
   args: &AppConfig,
   handle: Handle,
+  stopping: CancellationToken,
+  shutdown: Coordinator,
+  jobs: JobQueue,
 ) -> eyre::Result<()> {
-  let listener = acquire_listener(
+  let listeners = acquire_listeners(
     listenfd,
     IDX_MONITORING,
-    args.server.monitoring_port,
+    &args.server.monitoring_addrs(),
     "monitoring",
   )?;
-  let router = build_admin_router();
-  spawn_admin_server(listener, router, handle);
+  let grace = shutdown.drain_timeout();
+  let router = build_admin_router(shutdown, jobs);
+  #[cfg(unix)]
+  if let Some(path) = &args.server.monitoring_unix_socket {
+    spawn_unix_server(path, router.clone(), stopping, grace, "monitoring")?;
+  }
+  #[cfg(not(unix))]
+  let _ = grace;
+  #[cfg(not(unix))]
+  drop(stopping);
+  for listener in listeners {
+    spawn_admin_server(listener, router.clone(), handle.clone());
+  }
   Ok(())
 }
 
@@ -257,30 +401,102 @@ fn spawn_admin_server(listener: TcpListener, router: Router, handle: Handle) {
   });
 }
 
+/// Serves `router` over plain HTTP on a Unix domain socket at `path` until
+/// `stopping` is cancelled, gives open requests up to `grace` to finish, then
+/// removes the socket.
+#[cfg(unix)]
+fn spawn_unix_server(
+  path: &std::path::Path,
+  router: Router,
+  stopping: CancellationToken,
+  grace: Duration,
+  name: &'static str,
+) -> eyre::Result<JoinHandle<()>> {
+  let listener = listen::bind_unix(path)
+    .map_err(|e| eyre::eyre!("failed to bind {name} to {}: {e}", path.display()))?;
+  let path = path.to_owned();
+  Ok(tokio::spawn(async move {
+    debug!(path = %path.display(), "starting {name} server on Unix socket");
+    let server = axum::serve(
+      listener,
+      router.into_make_service_with_connect_info::<UnixPeer>(),
+    )
+    .with_graceful_shutdown(stopping.clone().cancelled_owned());
+    let deadline = async {
+      stopping.cancelled().await;
+      sleep(grace).await;
+    };
+    tokio::select! {
+      result = server.into_future() => if let Err(e) = result {
+        error!("{name} Unix socket server error: {e}");
+      },
+      () = deadline => warn!("{name} Unix socket connections outlived the shutdown grace period"),
+    }
+    if let Err(e) = std::fs::remove_file(&path) {
+      warn!("failed to remove {}: {e}", path.display());
+    }
+    info!("{name} Unix socket server stopped");
+  }))
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_tls(
   app: Router,
   listenfd: &mut ListenFd,
-  http_port: u16,
-  https_port: u16,
+  args: &crate::config::Server,
   handle: Handle,
+  stopping: CancellationToken,
+  shutdown: &Coordinator,
   tls_config: RustlsConfig,
+  redirect: Router,
 ) -> eyre::Result<()> {
-  let http_listener = acquire_listener(listenfd, IDX_HTTP, http_port, "HTTP")?;
-  let https_listener = acquire_listener(listenfd, IDX_HTTPS, https_port, "HTTPS")?;
-
-  let ports = Ports {
-    http: http_port,
-    https: https_port,
+  let http_listeners = acquire_listeners(listenfd, IDX_HTTP, &args.http_addrs(), "HTTP")?;
+  let https_listeners = acquire_listeners(listenfd, IDX_HTTPS, &args.https_addrs(), "HTTPS")?;
+
+  // HTTP/3 listens on the same addresses and ports as HTTPS, over UDP.
+  let app = if args.http3 {
+    let tls = tls_config.get_inner();
+    let mut http3_servers = Vec::new();
+    for listener in &https_listeners {
+      let endpoint = http3::endpoint(listener.local_addr()?, &tls)?;
+      http3_servers.push(tokio::spawn(http3::serve(
+        endpoint,
+        app.clone(),
+        stopping.clone(),
+        shutdown.drain_timeout(),
+      )));
+    }
+    drain_servers(shutdown, "HTTP/3 connections", http3_servers);
+    // The same port HTTP requests are redirected to.
+    app.layer(axum::middleware::from_fn_with_state(
+      http3::alt_svc(args.public_https_port()),
+      http3::alt_svc_layer,
+    ))
+  } else {
+    app
   };
 
-  tokio::spawn(redirect_http_to_https(ports, http_listener, handle.clone()));
+  for listener in http_listeners {
+    tokio::spawn(redirect_http_to_https(
+      listener,
+      handle.clone(),
+      redirect.clone(),
+    ));
+  }
 
-  debug!(addr = %https_listener.local_addr().unwrap(), "starting HTTPS server");
-  let mut server = axum_server::from_tcp_rustls(https_listener, tls_config).handle(handle.clone());
-  server.http_builder().http2().enable_connect_protocol();
-  server
-    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-    .await?;
+  let servers = https_listeners.into_iter().map(|listener| {
+    debug!(addr = %listener.local_addr().unwrap(), "starting HTTPS server");
+    let mut server = axum_server::from_tcp(listener)
+      .acceptor(ClientCertAcceptor::new(tls_config.clone()))
+      .handle(handle.clone());
+    server.http_builder().http2().enable_connect_protocol();
+    server.serve(
+      app
+        .clone()
+        .into_make_service_with_connect_info::<SocketAddr>(),
+    )
+  });
+  futures::future::try_join_all(servers).await?;
 
   Ok(())
 }
@@ -288,252 +504,559 @@ async fn run_tls(
 async fn run_http(
   app: Router,
   listenfd: &mut ListenFd,
-  http_port: u16,
+  args: &crate::config::Server,
   handle: Handle,
 ) -> eyre::Result<()> {
-  let http_listener = acquire_listener(listenfd, IDX_HTTP, http_port, "HTTP")?;
-  debug!(addr = %http_listener.local_addr().unwrap(), "starting HTTP server (TLS disabled)");
-  axum_server::from_tcp(http_listener)
-    .handle(handle.clone())
-    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-    .await?;
+  let http_listeners = acquire_listeners(listenfd, IDX_HTTP, &args.http_addrs(), "HTTP")?;
+  let servers = http_listeners.into_iter().map(|listener| {
+    debug!(addr = %listener.local_addr().unwrap(), "starting HTTP server (TLS disabled)");
+    axum_server::from_tcp(listener)
+      .handle(handle.clone())
+      .serve(
+        app
+          .clone()
+          .into_make_service_with_connect_info::<SocketAddr>(),
+      )
+  });
+  futures::future::try_join_all(servers).await?;
 
   Ok(())
 }
 
 async fn make_tls_config(
-  args: &crate::config::Server,
+  config: &AppConfig,
+  pool: Pool,
 ) -> eyre::Result<(RustlsConfig, JoinHandle<()>)> {
-  let (config, maybe_state) = match (&args.tls_cert, &args.tls_key) {
-    (None, None) => {
-      // we're in acme mode
-      let state = AcmeConfig::new(args.domains.iter())
-        .contact(args.email.iter().map(|e| format!("mailto:{}", e)))
-        .cache_option(args.cache.clone().map(DirCache::new))
-        .directory_lets_encrypt(args.production)
-        .state();
-
-      let tls_config = Arc::new(
-        ServerConfig::builder()
-          .with_no_client_auth()
-          .with_cert_resolver(state.resolver()),
-      );
-      tracing::debug!("starting server with letsencrypt");
-
-      (RustlsConfig::from_config(tls_config), Some(state))
+  let args = &config.server;
+  let mut alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+  let (resolver, background): (Option<Arc<dyn ResolvesServerCert>>, BoxFuture<'static, ()>) =
+    match (&args.tls_cert, &args.tls_key) {
+      // Only the hosts in `server.certificates` are served.
+      (None, None) if args.domains.is_empty() && !args.certificates.is_empty() => {
+        (None, futures::future::pending().boxed())
+      }
+      (None, None) => {
+        // we're in acme mode
+        let settings = AcmeSettings::from_config(args, &config.acme)?;
+        // `acme-tls/1` lets TLS-ALPN-01 validation handshakes through.
+        alpn_protocols.push(ACME_TLS_ALPN_NAME.to_vec());
+        tracing::debug!("starting server with ACME");
+
+        match config.acme.backend {
+          AcmeBackend::Dir => {
+            let mut state = settings
+              .config()
+              .cache_option(args.cache.clone().map(DirCache::new))
+              .state();
+            let resolver = state.resolver();
+            let events = async move {
+              loop {
+                match state.next().await.unwrap() {
+                  Ok(ok) => info!("event: {ok:?}"),
+                  Err(err) => error!("error: {:?}", err),
+                }
+              }
+            };
+            (Some(resolver), events.boxed())
+          }
+          AcmeBackend::Postgres => {
+            let cache = PostgresAcmeCache::new(pool)
+              .with_schema_name(&config.acme.schema_name)
+              .map_err(|e| eyre::eyre!(e))?
+              .with_table_name(&config.acme.table_name)
+              .map_err(|e| eyre::eyre!(e))?;
+            cache.migrate().await?;
+            let acme = PostgresAcme::new(settings, cache);
+            (Some(acme.resolver()), acme.run().boxed())
+          }
+        }
+      }
+      (Some(cert_path), Some(key_path)) => {
+        tracing::debug!("starting server: key={key_path:?}, cert={cert_path:?}");
+
+        let resolver = Arc::new(
+          KeypairResolver::load(cert_path, key_path)
+            .map_err(|e| eyre::eyre!("Failed to load TLS certificates: {e:#}"))?,
+        );
+        let watcher = tls::watch_keypair(resolver.clone())?;
+        // The watcher stops when dropped, so the task holds on to it.
+        let keep_watching = async move {
+          let _watcher = watcher;
+          futures::future::pending::<()>().await;
+        };
+        (Some(resolver), keep_watching.boxed())
+      }
+      _ => {
+        return Err(eyre::anyhow!(
+          "Both --tls-cert and --tls-key must be provided together"
+        ));
+      }
+    };
+
+  let (resolver, background) = match resolver {
+    Some(resolver) if args.certificates.is_empty() => (resolver, background),
+    default => {
+      let (sni, watchers) = tls::load_host_certificates(&args.certificates, default)?;
+      let background = async move {
+        let _watchers = watchers;
+        background.await;
+      };
+      (
+        Arc::new(sni) as Arc<dyn ResolvesServerCert>,
+        background.boxed(),
+      )
     }
-    (Some(cert_path), Some(key_path)) => {
-      tracing::debug!("starting server: key={key_path:?}, cert={cert_path:?}");
-
-      let config = RustlsConfig::from_pem_file(cert_path, key_path)
-        .await
-        .map_err(|e| eyre::eyre!("Failed to load TLS certificates: {}", e))?;
+  };
 
-      (config, None)
-    }
-    _ => {
-      return Err(eyre::anyhow!(
-        "Both --tls-cert and --tls-key must be provided together"
-      ));
-    }
+  let builder = ServerConfig::builder();
+  let builder = match client_cert::client_verifier(
+    args.client_auth,
+    args.client_ca.as_deref(),
+    &args.client_crls,
+  )? {
+    Some(verifier) => builder.with_client_cert_verifier(verifier),
+    None => builder.with_no_client_auth(),
   };
+  let mut tls_config = builder.with_cert_resolver(resolver);
+  tls_config.alpn_protocols = alpn_protocols;
 
-  // Clone values for the reloading task before moving into the async block
-  let config_clone = config.clone();
-  let cert_path = args.tls_cert.clone();
-  let key_path = args.tls_key.clone();
-
-  let jh = tokio::spawn(async move {
-    // Start periodic reloading task if we're in keypair mode
-    if let (Some(cert_path), Some(key_path)) = (cert_path, key_path) {
-      tokio::spawn(async move {
-        // Run periodic reload every hour
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(3600));
-
-        loop {
-          interval.tick().await;
-
-          match config_clone
-            .reload_from_pem_file(&cert_path, &key_path)
-            .await
-          {
-            Ok(_) => info!("TLS certificates reloaded successfully"),
-            Err(e) => warn!("Failed to reload TLS certificates: {}", e),
-          }
-        }
-      });
+  Ok((
+    RustlsConfig::from_config(Arc::new(tls_config)),
+    tokio::spawn(background),
+  ))
+}
+
+async fn redirect_http_to_https(listener: TcpListener, handle: Handle, redirect: Router) {
+  tracing::debug!("listening on {}", listener.local_addr().unwrap());
+
+  let server = axum_server::from_tcp(listener)
+    .handle(handle)
+    .serve(redirect.into_make_service_with_connect_info::<SocketAddr>());
+
+  if let Err(e) = server.await {
+    error!("HTTP redirect server error: {}", e);
+  }
+  info!("HTTP redirect server stopped");
+}
+
+/// Waits during the drain phase for `servers`, which stop on their own once
+/// shutdown starts, to finish their open requests.
+fn drain_servers(shutdown: &Coordinator, name: &'static str, servers: Vec<JoinHandle<()>>) {
+  shutdown.on(Phase::Drain, name, move || async move {
+    for server in futures::future::join_all(servers).await {
+      if let Err(e) = server {
+        error!("{name} server failed: {e}");
+      }
     }
+  });
+}
 
-    // Handle ACME state if present
-    if let Some(mut state) = maybe_state {
-      loop {
-        match state.next().await.unwrap() {
-          Ok(ok) => info!("event: {ok:?}"),
-          Err(err) => error!("error: {:?}", err),
-        }
+/// Stops the app's listeners when shutdown starts and waits for their
+/// connections to finish.
+fn register_app_shutdown(shutdown: &Coordinator, handle: Handle, stopping: CancellationToken) {
+  let drain_timeout = shutdown.drain_timeout();
+  let listening = handle.clone();
+  shutdown.on(Phase::StopAccepting, "app listeners", move || async move {
+    // Connections still open after the timeout are closed.
+    listening.graceful_shutdown(Some(drain_timeout));
+    stopping.cancel();
+  });
+  shutdown.on(Phase::Drain, "app connections", move || async move {
+    loop {
+      let count = handle.connection_count();
+      if count == 0 {
+        break;
       }
-    } else {
-      // For keypair mode, we need to keep the task alive
-      // The reloading is handled in the spawned task above
-      futures::future::pending::<()>().await;
+      debug!("alive connections count={count}");
+      sleep(Duration::from_millis(100)).await;
     }
   });
+}
 
-  Ok((config, jh))
+/// Compresses responses on the fly, except those served from precompressed
+/// variants.
+fn compression_layer() -> CompressionLayer<And<DefaultPredicate, NotPrecompressed>> {
+  CompressionLayer::new()
+    .quality(tower_http::CompressionLevel::Default)
+    .compress_when(DefaultPredicate::new().and(NotPrecompressed))
 }
 
-async fn redirect_http_to_https(ports: Ports, listener: TcpListener, handle: Handle) {
-  fn make_https(host: String, uri: Uri, ports: Ports) -> Result<Uri, BoxError> {
-    let mut parts = uri.into_parts();
+/// Marks responses whose body was already negotiated from precompressed
+/// variants, including deliberate `identity` picks, so `CompressionLayer`
+/// leaves them alone.
+#[derive(Clone, Copy)]
+struct Precompressed;
+
+#[derive(Clone, Copy)]
+struct NotPrecompressed;
+
+impl Predicate for NotPrecompressed {
+  fn should_compress<B>(&self, response: &http::Response<B>) -> bool
+  where
+    B: axum::body::HttpBody,
+  {
+    response.extensions().get::<Precompressed>().is_none()
+  }
+}
 
-    parts.scheme = Some(axum::http::uri::Scheme::HTTPS);
+/// Cache lifetime for URLs that carry the asset's content hash.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+/// Cache lifetime for unhashed asset names, whose content changes on deploy.
+const SHORT_CACHE_CONTROL: &str = "public, max-age=60";
 
-    if parts.path_and_query.is_none() {
-      parts.path_and_query = Some("/".parse().unwrap());
-    }
+fn static_file_handler(state: AppState) -> Router {
+  Router::new()
+    .route("/{*file}", get(serve_static_file))
+    .with_state(state)
+}
+
+async fn serve_static_file(
+  State(state): State<AppState>,
+  path: Path<String>,
+  request_headers: HeaderMap,
+) -> Response {
+  info!("serving static file: {}", path.as_str());
+  let assets = state.assets().load();
+  let Some((asset, version)) = assets.resolve(&path) else {
+    return StatusCode::NOT_FOUND.into_response();
+  };
 
-    let https_host = host.replace(&ports.http.to_string(), &ports.https.to_string());
-    parts.authority = Some(https_host.parse()?);
+  let cache_control = match version {
+    AssetVersion::Current => IMMUTABLE_CACHE_CONTROL,
+    AssetVersion::Unversioned => SHORT_CACHE_CONTROL,
+    // An old page asked for a previous build; point it at this one.
+    AssetVersion::Stale => return Redirect::temporary(&asset.url).into_response(),
+  };
 
-    Ok(Uri::from_parts(parts)?)
+  let accept_encoding = request_headers
+    .get(ACCEPT_ENCODING)
+    .and_then(|value| value.to_str().ok());
+  let (encoding, contents) = asset.negotiate(accept_encoding);
+  let etag = asset.etag(encoding);
+  let last_modified = LastModified::from(asset.modified);
+
+  let mut headers = HeaderMap::new();
+  headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+  headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+  headers.typed_insert(etag.clone());
+  headers.typed_insert(last_modified);
+  headers.typed_insert(AcceptRanges::bytes());
+
+  // If-None-Match takes precedence over If-Modified-Since (RFC 9110 §13.1.3)
+  let not_modified = match request_headers.typed_get::<IfNoneMatch>() {
+    Some(if_none_match) => !if_none_match.precondition_passes(&etag),
+    None => request_headers
+      .typed_get::<IfModifiedSince>()
+      .is_some_and(|since| !since.is_modified(asset.modified)),
+  };
+  if not_modified {
+    return precompressed((StatusCode::NOT_MODIFIED, headers).into_response());
   }
 
-  let redirect = move |request: AxumRequest| async move {
-    let host = request.uri().host().unwrap().to_string();
-    let uri = request.uri().clone();
-    match make_https(host, uri, ports) {
-      Ok(uri) => Ok(Redirect::permanent(&uri.to_string())),
-      Err(error) => {
-        tracing::warn!(%error, "failed to convert URI to HTTPS");
-        Err(StatusCode::BAD_REQUEST)
-      }
+  headers.insert(CONTENT_TYPE, asset.content_type.clone());
+
+  if encoding != Encoding::Identity {
+    headers.insert(
+      CONTENT_ENCODING,
+      HeaderValue::from_static(encoding.as_str()),
+    );
+  }
+
+  // A stale If-Range means the client's partial copy is outdated, so it gets
+  // the whole representation instead of the requested range.
+  let range = request_headers.typed_get::<Range>().filter(|_| {
+    request_headers
+      .typed_get::<IfRange>()
+      .is_none_or(|if_range| !if_range.is_modified(Some(&etag), Some(&last_modified)))
+  });
+
+  let len = contents.len() as u64;
+  let response = match range.map(|range| single_byte_range(&range, len)) {
+    None | Some(ByteRange::Full) => {
+      // `bytes::Bytes` clones are cheap
+      (headers, contents.clone()).into_response()
+    }
+    Some(ByteRange::Partial(start, end)) => {
+      headers.typed_insert(ContentRange::bytes(start..=end, len).unwrap());
+      let body = contents.slice(start as usize..=end as usize);
+      (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+    }
+    Some(ByteRange::Unsatisfiable) => {
+      headers.typed_insert(ContentRange::unsatisfied_bytes(len));
+      (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
     }
   };
 
-  tracing::debug!("listening on {}", listener.local_addr().unwrap());
+  precompressed(response)
+}
 
-  let server = axum_server::from_tcp(listener)
-    .handle(handle)
-    .serve(redirect.into_make_service());
+fn precompressed(mut response: Response) -> Response {
+  response.extensions_mut().insert(Precompressed);
+  response
+}
 
-  if let Err(e) = server.await {
-    error!("HTTP redirect server error: {}", e);
-  }
-  info!("HTTP redirect server stopped");
+/// How a `Range` request maps onto a body.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+  /// Serve the whole body. Used for multi-range requests, which aren't worth a
+  /// `multipart/byteranges` response for static assets.
+  Full,
+  /// Serve the inclusive byte range `start..=end`.
+  Partial(u64, u64),
+  /// No requested range overlaps the body.
+  Unsatisfiable,
 }
 
-async fn graceful_shutdown(
-  handle: Handle,
-  deletion_task: AbortHandle,
-  external_token: Option<tokio_util::sync::CancellationToken>,
-) {
-  match external_token {
-    Some(token) => {
-      // Wait only for the external token - signals are handled elsewhere
-      token.cancelled().await;
-      info!("Shutdown requested via external token");
-    }
-    None => {
-      // Only set up signal handlers if no external token is provided
-      let ctrl_c = async {
-        signal::ctrl_c()
-          .await
-          .expect("failed to install Ctrl+C handler");
-      };
+fn single_byte_range(range: &Range, len: u64) -> ByteRange {
+  let mut ranges = range.satisfiable_ranges(len);
+  let (Some((start, end)), None) = (ranges.next(), ranges.next()) else {
+    return ByteRange::Full;
+  };
 
-      #[cfg(unix)]
-      let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-          .expect("failed to install signal handler")
-          .recv()
-          .await;
-      };
+  let start = match start {
+    Bound::Included(start) => start,
+    Bound::Excluded(start) => start + 1,
+    Bound::Unbounded => 0,
+  };
+  let end = match end {
+    Bound::Included(end) => end.min(len.saturating_sub(1)),
+    Bound::Excluded(end) => end.min(len).saturating_sub(1),
+    Bound::Unbounded => len.saturating_sub(1),
+  };
 
-      #[cfg(not(unix))]
-      let terminate = std::future::pending::<()>();
+  if start < len && start <= end {
+    ByteRange::Partial(start, end)
+  } else {
+    ByteRange::Unsatisfiable
+  }
+}
 
-      tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
-      }
-    }
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assets::AssetCache;
+  use axum::body::{Body, to_bytes};
+  use tower::ServiceExt as _;
+
+  /// Large and repetitive enough to be precompressed at load time.
+  const CSS: &str = ".button { color: rebeccapurple; }\n";
+
+  /// Too small to be precompressed, but large enough for `CompressionLayer`.
+  const ROBOTS: &str = "User-agent: *\nDisallow: /admin/\nDisallow: /jobs/\n";
+
+  /// A static file router over a cache holding `css/app.css` and
+  /// `robots.txt`.
+  async fn static_files() -> (Router, AppState) {
+    let dir = std::env::temp_dir().join(format!("server-test-{}", uuid::Uuid::now_v7()));
+    std::fs::create_dir_all(dir.join("css")).unwrap();
+    std::fs::write(dir.join("css/app.css"), CSS.repeat(100)).unwrap();
+    std::fs::write(dir.join("robots.txt"), ROBOTS).unwrap();
+
+    let state = AppState::with_assets(AssetCache::load_files(Some(&dir), &[]).await);
+    (static_file_handler(state.clone()), state)
   }
 
-  info!("waiting for connections to close");
-  handle.graceful_shutdown(Some(Duration::from_secs(10)));
-  loop {
-    let count = handle.connection_count();
-    if count == 0 {
-      break;
+  async fn get(app: &Router, path: &str, headers: &[(&str, &str)]) -> Response {
+    let mut request = http::Request::get(path);
+    for (name, value) in headers {
+      request = request.header(*name, *value);
     }
-    debug!("alive connections count={count}",);
-    sleep(Duration::from_secs(1)).await;
+    app
+      .clone()
+      .oneshot(request.body(Body::empty()).unwrap())
+      .await
+      .unwrap()
   }
-  deletion_task.abort();
-  debug!("graceful shutdown complete");
-}
-
-fn static_file_handler(state: AppState) -> Router {
-  Router::new()
-    .route(
-      "/{*file}",
-      get(
-        |State(state): State<AppState>, path: Path<String>| async move {
-          info!("serving static file: {}", path.as_str());
-          let assets = state.assets();
-          let Some(asset) = assets.get_from_path(&path) else {
-            return StatusCode::NOT_FOUND.into_response();
-          };
-
-          let mut headers = HeaderMap::new();
-
-          // We set the content type explicitly here as it will otherwise
-          // be inferred as an `octet-stream`
-          headers.insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static(asset.ext().unwrap_or("")),
-          );
-
-          if let Some(ext) = asset.ext()
-            && let Some(mime) = mime_guess::from_ext(ext).first()
-          {
-            headers.insert(CONTENT_TYPE, HeaderValue::from_str(mime.as_ref()).unwrap());
-          }
 
-          if [Some("css"), Some("js")].contains(&asset.ext()) {
-            headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
-          }
+  #[tokio::test]
+  async fn compression_leaves_precompressed_assets_alone() {
+    let (app, state) = static_files().await;
+    let app = app.layer(compression_layer());
+    let assets = state.assets().load();
 
-          // `bytes::Bytes` clones are cheap
-          (headers, asset.contents.clone()).into_response()
-        },
-      ),
+    let response = get(
+      &app,
+      "/css/app.css",
+      &[(ACCEPT_ENCODING.as_str(), "br, gzip;q=0.5")],
     )
-    .layer(middleware::from_fn(cache_control))
-    .with_state(state)
-}
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_ENCODING], "br");
+    assert_eq!(
+      response.headers().get_all(VARY).iter().collect::<Vec<_>>(),
+      ["accept-encoding"]
+    );
+    // The stored Brotli variant, not compressed a second time.
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(
+      Some(&body),
+      assets.get("css/app.css").unwrap().brotli.as_ref()
+    );
+
+    // No variant to serve, so the handler picked identity and nothing else
+    // should compress it.
+    let response = get(&app, "/robots.txt", &[(ACCEPT_ENCODING.as_str(), "gzip")]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(response.headers()[VARY], "accept-encoding");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, ROBOTS);
+  }
 
-async fn cache_control(request: AxumRequest, next: Next) -> Response {
-  let mut response = next.run(request).await;
+  #[tokio::test]
+  async fn conditional_requests_answer_not_modified() {
+    let (app, _) = static_files().await;
+    let response = get(&app, "/css/app.css", &[]).await;
+    let etag = response.headers()[http::header::ETAG]
+      .to_str()
+      .unwrap()
+      .to_string();
+    let last_modified = response.headers()[http::header::LAST_MODIFIED]
+      .to_str()
+      .unwrap()
+      .to_string();
+    let status = async |headers: &[(&str, &str)]| get(&app, "/css/app.css", headers).await.status();
+
+    assert_eq!(
+      status(&[("if-none-match", &etag)]).await,
+      StatusCode::NOT_MODIFIED
+    );
+    assert_eq!(
+      status(&[("if-modified-since", &last_modified)]).await,
+      StatusCode::NOT_MODIFIED
+    );
+    // A mismatched If-None-Match wins over a matching If-Modified-Since.
+    assert_eq!(
+      status(&[
+        ("if-none-match", "\"0123456789abcdef\""),
+        ("if-modified-since", &last_modified),
+      ])
+      .await,
+      StatusCode::OK
+    );
+  }
 
-  if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
-    const CACHEABLE_CONTENT_TYPES: [&str; 6] = [
-      "text/css",
-      "application/javascript",
-      "image/svg+xml",
-      "image/webp",
-      "font/woff2",
-      "image/png",
-    ];
+  #[tokio::test]
+  async fn each_encoding_has_its_own_etag() {
+    let (app, state) = static_files().await;
+    let hash = state
+      .assets()
+      .load()
+      .get("css/app.css")
+      .unwrap()
+      .hash
+      .clone();
+    let etag = |response: Response| response.headers()[http::header::ETAG].clone();
+
+    let identity = get(&app, "/css/app.css", &[]).await;
+    assert_eq!(etag(identity), format!("\"{hash}\""));
+    let gzip = get(&app, "/css/app.css", &[("accept-encoding", "gzip")]).await;
+    assert_eq!(etag(gzip), format!("\"{hash}-gzip\""));
+    let brotli = get(&app, "/css/app.css", &[("accept-encoding", "br")]).await;
+    assert_eq!(etag(brotli), format!("\"{hash}-br\""));
+
+    // The identity tag doesn't validate a cached gzip body.
+    let identity_tag = format!("\"{hash}\"");
+    let response = get(
+      &app,
+      "/css/app.css",
+      &[
+        ("accept-encoding", "gzip"),
+        ("if-none-match", &identity_tag),
+      ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+  }
 
-    if CACHEABLE_CONTENT_TYPES.iter().any(|&ct| content_type == ct) {
-      let value = format!("public, max-age={}", 60 * 60 * 24);
+  #[tokio::test]
+  async fn range_requests_serve_partial_content() {
+    let (app, _) = static_files().await;
+    let len = CSS.len() * 100;
+    let etag = get(&app, "/css/app.css", &[]).await.headers()[http::header::ETAG]
+      .to_str()
+      .unwrap()
+      .to_string();
+
+    let response = get(&app, "/css/app.css", &[("range", "bytes=0-9")]).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+      response.headers()[http::header::CONTENT_RANGE],
+      format!("bytes 0-9/{len}").as_str()
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, CSS.as_bytes()[..10]);
+
+    // If-Range still matches, so the range is served.
+    let response = get(
+      &app,
+      "/css/app.css",
+      &[("range", "bytes=10-19"), ("if-range", &etag)],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, CSS.as_bytes()[10..20]);
+
+    // The client's copy is outdated, so it gets the whole asset instead.
+    let response = get(
+      &app,
+      "/css/app.css",
+      &[
+        ("range", "bytes=10-19"),
+        ("if-range", "\"0123456789abcdef\""),
+      ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(
+      response
+        .headers()
+        .get(http::header::CONTENT_RANGE)
+        .is_none()
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body.len(), len);
+
+    let response = get(&app, "/css/app.css", &[("range", &format!("bytes={len}-"))]).await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+      response.headers()[http::header::CONTENT_RANGE],
+      format!("bytes */{len}").as_str()
+    );
+  }
 
-      if let Ok(value) = HeaderValue::from_str(&value) {
-        response.headers_mut().insert("cache-control", value);
-      }
-    }
+  fn range(spec: &str) -> Range {
+    let mut headers = HeaderMap::new();
+    headers.insert(http::header::RANGE, HeaderValue::from_str(spec).unwrap());
+    headers.typed_get().unwrap()
   }
 
-  response
+  #[test]
+  fn single_byte_range_resolves_specs() {
+    assert_eq!(
+      single_byte_range(&range("bytes=0-9"), 100),
+      ByteRange::Partial(0, 9)
+    );
+    assert_eq!(
+      single_byte_range(&range("bytes=90-200"), 100),
+      ByteRange::Partial(90, 99)
+    );
+    assert_eq!(
+      single_byte_range(&range("bytes=50-"), 100),
+      ByteRange::Partial(50, 99)
+    );
+    assert_eq!(
+      single_byte_range(&range("bytes=-10"), 100),
+      ByteRange::Partial(90, 99)
+    );
+    assert_eq!(
+      single_byte_range(&range("bytes=100-"), 100),
+      ByteRange::Unsatisfiable
+    );
+    assert_eq!(
+      single_byte_range(&range("bytes=0-1, 5-6"), 100),
+      ByteRange::Full
+    );
+  }
 }
@@ -0,0 +1,268 @@
+//! Shutting the server down in order.
+//!
+//! Once shutdown is requested, [`Coordinator::run`] goes through the
+//! [`Phase`]s in order. Components register hooks for the phase they belong
+//! in; the hooks of a phase run concurrently and the next phase starts when
+//! they have all finished. Draining is cut off after
+//! `shutdown.drain_timeout`, and if the whole sequence takes longer than
+//! `shutdown.exit_timeout` the process exits without waiting for it.
+
+use std::{
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+  },
+  time::Duration,
+};
+
+use futures::{FutureExt as _, future::BoxFuture};
+use tokio::{signal, task::AbortHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::config;
+
+/// The steps of a shutdown, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+  /// Listeners stop taking new connections.
+  StopAccepting,
+  /// `/readyz` starts failing, so load balancers stop sending traffic.
+  NotReady,
+  /// Open connections finish, for up to `shutdown.drain_timeout`.
+  Drain,
+  /// Background tasks are cancelled.
+  BackgroundTasks,
+  /// Database pools and other shared resources are closed.
+  Resources,
+  /// Buffered traces and metrics are exported.
+  Telemetry,
+}
+
+impl Phase {
+  const ALL: [Phase; 6] = [
+    Phase::StopAccepting,
+    Phase::NotReady,
+    Phase::Drain,
+    Phase::BackgroundTasks,
+    Phase::Resources,
+    Phase::Telemetry,
+  ];
+}
+
+struct Hook {
+  phase: Phase,
+  name: String,
+  run: Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>,
+}
+
+struct Inner {
+  requested: CancellationToken,
+  ready: AtomicBool,
+  hooks: Mutex<Vec<Hook>>,
+  drain_timeout: Duration,
+  exit_timeout: Duration,
+}
+
+/// Runs the shutdown phases and the hooks registered for them. Clones share
+/// the same state.
+#[derive(Clone)]
+pub struct Coordinator {
+  inner: Arc<Inner>,
+}
+
+impl Coordinator {
+  pub fn new(drain_timeout: Duration, exit_timeout: Duration) -> Self {
+    Self {
+      inner: Arc::new(Inner {
+        requested: CancellationToken::new(),
+        ready: AtomicBool::new(true),
+        hooks: Mutex::new(Vec::new()),
+        drain_timeout,
+        exit_timeout,
+      }),
+    }
+  }
+
+  pub fn from_config(config: &config::Shutdown) -> Self {
+    Self::new(config.drain_timeout(), config.exit_timeout())
+  }
+
+  /// Runs `hook` during `phase`. Hooks registered after their phase has
+  /// started never run.
+  pub fn on<F, Fut>(&self, phase: Phase, name: impl Into<String>, hook: F)
+  where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+  {
+    self.inner.hooks.lock().unwrap().push(Hook {
+      phase,
+      name: name.into(),
+      run: Box::new(move || hook().boxed()),
+    });
+  }
+
+  /// Aborts `task` with the other background tasks.
+  pub fn abort_on_shutdown(&self, name: impl Into<String>, task: AbortHandle) {
+    self.on(Phase::BackgroundTasks, name, move || async move {
+      task.abort();
+    });
+  }
+
+  /// Starts shutting down.
+  pub fn request(&self) {
+    self.inner.requested.cancel();
+  }
+
+  /// Cancelled once shutdown has been requested.
+  pub fn requested(&self) -> CancellationToken {
+    self.inner.requested.clone()
+  }
+
+  /// Whether the server should still be sent traffic.
+  pub fn is_ready(&self) -> bool {
+    self.inner.ready.load(Ordering::Relaxed)
+  }
+
+  /// How long open connections get to finish.
+  pub fn drain_timeout(&self) -> Duration {
+    self.inner.drain_timeout
+  }
+
+  /// Requests shutdown on Ctrl+C or SIGTERM.
+  pub fn listen_for_signals(&self) -> tokio::task::JoinHandle<()> {
+    let coordinator = self.clone();
+    tokio::spawn(async move {
+      let ctrl_c = async {
+        signal::ctrl_c()
+          .await
+          .expect("failed to install Ctrl+C handler");
+      };
+
+      #[cfg(unix)]
+      let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+          .expect("failed to install signal handler")
+          .recv()
+          .await;
+      };
+
+      #[cfg(not(unix))]
+      let terminate = std::future::pending::<()>();
+
+      tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+      }
+
+      info!("Shutdown signal received");
+      coordinator.request();
+    })
+  }
+
+  /// Waits for shutdown to be requested, then runs every phase. Exits the
+  /// process if that takes longer than the exit timeout.
+  pub async fn run(&self) {
+    self.inner.requested.cancelled().await;
+    let exit_timeout = self.inner.exit_timeout;
+    let watchdog = tokio::spawn(async move {
+      tokio::time::sleep(exit_timeout).await;
+      error!("shutdown took longer than {exit_timeout:?}, exiting");
+      std::process::exit(1);
+    });
+
+    for phase in Phase::ALL {
+      self.run_phase(phase).await;
+    }
+
+    watchdog.abort();
+    debug!("graceful shutdown complete");
+  }
+
+  async fn run_phase(&self, phase: Phase) {
+    info!("shutdown: {phase:?}");
+    if phase == Phase::NotReady {
+      self.inner.ready.store(false, Ordering::Relaxed);
+    }
+
+    let hooks: Vec<Hook> = {
+      let mut all = self.inner.hooks.lock().unwrap();
+      let (now, later) = std::mem::take(&mut *all)
+        .into_iter()
+        .partition(|hook| hook.phase == phase);
+      *all = later;
+      now
+    };
+    let (names, hooks): (Vec<_>, Vec<_>) = hooks
+      .into_iter()
+      .map(|hook| (hook.name, (hook.run)()))
+      .unzip();
+    let all = futures::future::join_all(hooks);
+
+    if phase == Phase::Drain {
+      if tokio::time::timeout(self.inner.drain_timeout, all)
+        .await
+        .is_err()
+      {
+        warn!(
+          "still draining {names:?} after {:?}, moving on",
+          self.inner.drain_timeout
+        );
+      }
+    } else {
+      all.await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn phases_run_in_order() {
+    let coordinator = Coordinator::new(Duration::from_millis(50), Duration::from_secs(60));
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let record = |phase: Phase, name: &'static str| {
+      let log = log.clone();
+      coordinator.on(phase, name, move || async move {
+        log.lock().unwrap().push(name);
+      });
+    };
+    // Registered out of order on purpose.
+    record(Phase::Telemetry, "flush traces");
+    record(Phase::Resources, "close pool");
+    record(Phase::StopAccepting, "stop listeners");
+    let ready_during_drain = {
+      let coordinator = coordinator.clone();
+      let log = log.clone();
+      move || async move {
+        let ready = coordinator.is_ready();
+        log
+          .lock()
+          .unwrap()
+          .push(if ready { "ready" } else { "not ready" });
+      }
+    };
+    coordinator.on(Phase::Drain, "check readiness", ready_during_drain);
+    // Draining is cut off at the deadline.
+    coordinator.on(Phase::Drain, "stuck connection", || {
+      futures::future::pending::<()>()
+    });
+    let task = tokio::spawn(futures::future::pending::<()>());
+    coordinator.abort_on_shutdown("pending task", task.abort_handle());
+
+    assert!(coordinator.is_ready());
+    coordinator.request();
+    tokio::time::timeout(Duration::from_secs(5), coordinator.run())
+      .await
+      .unwrap();
+
+    assert!(!coordinator.is_ready());
+    assert!(task.await.unwrap_err().is_cancelled());
+    assert_eq!(
+      *log.lock().unwrap(),
+      ["stop listeners", "not ready", "close pool", "flush traces"]
+    );
+  }
+}
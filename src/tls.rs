@@ -0,0 +1,448 @@
+//! TLS key pairs from PEM files, reloaded when the files change.
+//!
+//! [`KeypairResolver`] serves the last good key pair and [`watch_keypair`]
+//! reloads it on file events. The parent directories are watched rather than
+//! the files, because Kubernetes updates secret mounts by swapping a `..data`
+//! symlink to a fresh directory and deleting the old one, which a watch on
+//! the old file would never report. A new pair is only swapped in once its
+//! certificate matches its key and is currently valid; otherwise the old one
+//! keeps being served.
+//!
+//! [`SniResolver`] serves the certificates in `server.certificates` to the
+//! hosts they are listed for, and defers to the `tls_cert` or ACME resolver
+//! for everything else.
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::{Arc, LazyLock},
+  time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use jiff::Timestamp;
+use opentelemetry::{KeyValue, metrics::Gauge};
+use rustls::{
+  crypto::CryptoProvider,
+  pki_types::{CertificateDer, DnsName, PrivateKeyDer, pem::PemObject as _},
+  server::{ClientHello, ResolvesServerCert},
+  sign::CertifiedKey,
+};
+use tracing::{debug, info, warn};
+
+use crate::config::HostCertificate;
+
+/// How long file events settle before reloading; secret updates and
+/// certificate renewals write several files.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The expiry of each certificate loaded, as seconds since the epoch.
+static CERT_NOT_AFTER: LazyLock<Gauge<i64>> = LazyLock::new(|| {
+  opentelemetry::global::meter("{{crate_name}}")
+    .i64_gauge("tls.certificate.not_after")
+    .with_description("When the served TLS certificate expires")
+    .with_unit("s")
+    .build()
+});
+
+/// The subject and validity of a loaded certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+  pub subject: String,
+  pub not_before: Timestamp,
+  pub not_after: Timestamp,
+}
+
+/// Loads a certificate chain and its private key, checking that they match
+/// and that the certificate is valid now.
+pub fn load_keypair(
+  cert_path: &Path,
+  key_path: &Path,
+) -> eyre::Result<(CertifiedKey, CertificateInfo)> {
+  let chain = CertificateDer::pem_file_iter(cert_path)
+    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| eyre::eyre!("failed to read {}: {e}", cert_path.display()))?;
+  let key = PrivateKeyDer::from_pem_file(key_path)
+    .map_err(|e| eyre::eyre!("failed to read {}: {e}", key_path.display()))?;
+  let info = certificate_info(&chain)?;
+
+  let now = Timestamp::now();
+  if now < info.not_before {
+    eyre::bail!(
+      "certificate for {} isn't valid until {}",
+      info.subject,
+      info.not_before
+    );
+  }
+  if now > info.not_after {
+    eyre::bail!(
+      "certificate for {} expired at {}",
+      info.subject,
+      info.not_after
+    );
+  }
+
+  let key = CertifiedKey::from_der(chain, key, &crypto_provider())
+    .map_err(|e| eyre::eyre!("certificate for {} doesn't fit its key: {e}", info.subject))?;
+  Ok((key, info))
+}
+
+/// The process-wide crypto provider `main` installs, or aws-lc-rs when none
+/// is, as in tests.
+pub(crate) fn crypto_provider() -> Arc<CryptoProvider> {
+  CryptoProvider::get_default()
+    .cloned()
+    .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+fn certificate_info(chain: &[CertificateDer<'_>]) -> eyre::Result<CertificateInfo> {
+  let leaf = chain
+    .first()
+    .ok_or_else(|| eyre::eyre!("no certificates"))?;
+  let (_, cert) = x509_parser::parse_x509_certificate(leaf)
+    .map_err(|e| eyre::eyre!("invalid certificate: {e}"))?;
+  let validity = cert.validity();
+  Ok(CertificateInfo {
+    subject: cert.subject().to_string(),
+    not_before: Timestamp::from_second(validity.not_before.timestamp())?,
+    not_after: Timestamp::from_second(validity.not_after.timestamp())?,
+  })
+}
+
+/// Serves the key pair most recently loaded from `cert_path` and `key_path`.
+#[derive(Debug)]
+pub struct KeypairResolver {
+  cert_path: PathBuf,
+  key_path: PathBuf,
+  current: ArcSwap<CertifiedKey>,
+}
+
+impl KeypairResolver {
+  /// Loads the key pair, failing if it isn't usable.
+  pub fn load(cert_path: &Path, key_path: &Path) -> eyre::Result<Self> {
+    let (key, info) = load_keypair(cert_path, key_path)?;
+    record(&info);
+    Ok(Self {
+      cert_path: cert_path.to_owned(),
+      key_path: key_path.to_owned(),
+      current: ArcSwap::from_pointee(key),
+    })
+  }
+
+  /// Loads the files again and swaps the new pair in if it is usable and
+  /// differs from the current one. Returns whether it was swapped.
+  pub fn reload(&self) -> eyre::Result<bool> {
+    let (key, info) = load_keypair(&self.cert_path, &self.key_path)?;
+    if key.cert == self.current.load().cert {
+      return Ok(false);
+    }
+    self.current.store(Arc::new(key));
+    record(&info);
+    Ok(true)
+  }
+}
+
+impl ResolvesServerCert for KeypairResolver {
+  fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    Some(self.current.load_full())
+  }
+}
+
+fn record(info: &CertificateInfo) {
+  info!(
+    subject = %info.subject,
+    not_after = %info.not_after,
+    "serving TLS certificate"
+  );
+  CERT_NOT_AFTER.record(
+    info.not_after.as_second(),
+    &[KeyValue::new(
+      "tls.certificate.subject",
+      info.subject.clone(),
+    )],
+  );
+}
+
+/// Reloads `resolver` whenever something changes next to its files. The
+/// returned watcher has to be kept alive.
+pub fn watch_keypair(resolver: Arc<KeypairResolver>) -> notify::Result<notify::RecommendedWatcher> {
+  use notify::{EventKind, RecursiveMode, Watcher};
+
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+  let mut watcher =
+    notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+      Ok(event)
+        if matches!(
+          event.kind,
+          EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) =>
+      {
+        let _ = tx.send(());
+      }
+      Ok(_) => {}
+      Err(e) => warn!("certificate watcher error: {e}"),
+    })?;
+
+  let mut dirs: Vec<&Path> = [&resolver.cert_path, &resolver.key_path]
+    .into_iter()
+    .map(|path| match path.parent() {
+      Some(dir) if !dir.as_os_str().is_empty() => dir,
+      _ => Path::new("."),
+    })
+    .collect();
+  dirs.dedup();
+  for dir in dirs {
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+  }
+
+  tokio::spawn(async move {
+    while rx.recv().await.is_some() {
+      tokio::time::sleep(RELOAD_DEBOUNCE).await;
+      while rx.try_recv().is_ok() {}
+
+      let reloader = resolver.clone();
+      match tokio::task::spawn_blocking(move || reloader.reload()).await {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => debug!("TLS certificate unchanged"),
+        Ok(Err(e)) => warn!("keeping the current TLS certificate: {e:#}"),
+        Err(e) => warn!("TLS certificate reload panicked: {e}"),
+      }
+    }
+  });
+
+  Ok(watcher)
+}
+
+/// Validates a `server.certificates` domain: a DNS name, or `*.` and a DNS
+/// name.
+pub(crate) fn validate_host_pattern(pattern: &str) -> Result<(), String> {
+  let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+  if name.contains('*') || DnsName::try_from(name).is_err() {
+    return Err(format!("'{pattern}' is not a host name or wildcard"));
+  }
+  Ok(())
+}
+
+/// Picks a certificate by the server name the client asks for, falling back
+/// to `default` for other names and for clients that don't send one.
+#[derive(Debug)]
+pub struct SniResolver {
+  /// Resolvers by lowercase host name.
+  exact: HashMap<String, Arc<dyn ResolvesServerCert>>,
+  /// Resolvers for `*.example.com` by `example.com`.
+  wildcard: HashMap<String, Arc<dyn ResolvesServerCert>>,
+  default: Arc<dyn ResolvesServerCert>,
+}
+
+impl SniResolver {
+  pub fn new(default: Arc<dyn ResolvesServerCert>) -> Self {
+    Self {
+      exact: HashMap::new(),
+      wildcard: HashMap::new(),
+      default,
+    }
+  }
+
+  /// Serves `resolver`'s certificate to `domains`, which may include
+  /// wildcards covering one label, like `*.example.com`.
+  pub fn add(&mut self, domains: &[String], resolver: Arc<dyn ResolvesServerCert>) {
+    for domain in domains {
+      let domain = domain.to_ascii_lowercase();
+      match domain.strip_prefix("*.") {
+        Some(parent) => self.wildcard.insert(parent.to_string(), resolver.clone()),
+        None => self.exact.insert(domain, resolver.clone()),
+      };
+    }
+  }
+
+  /// The resolver for `server_name`, preferring exact names to wildcards.
+  fn lookup(&self, server_name: Option<&str>) -> &Arc<dyn ResolvesServerCert> {
+    server_name
+      .map(str::to_ascii_lowercase)
+      .and_then(|name| {
+        self.exact.get(&name).or_else(|| {
+          let (_, parent) = name.split_once('.')?;
+          self.wildcard.get(parent)
+        })
+      })
+      .unwrap_or(&self.default)
+  }
+}
+
+impl ResolvesServerCert for SniResolver {
+  fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    // TLS-ALPN-01 validation is for the ACME resolver whatever the name.
+    if rustls_acme::is_tls_alpn_challenge(&client_hello) {
+      return self.default.resolve(client_hello);
+    }
+    self
+      .lookup(client_hello.server_name())
+      .resolve(client_hello)
+  }
+}
+
+/// Loads `certificates` into an [`SniResolver`] over `default`, or over the
+/// first of them when there is no default, and watches their files. The
+/// returned watchers have to be kept alive.
+pub fn load_host_certificates(
+  certificates: &[HostCertificate],
+  default: Option<Arc<dyn ResolvesServerCert>>,
+) -> eyre::Result<(SniResolver, Vec<notify::RecommendedWatcher>)> {
+  let mut resolvers = Vec::new();
+  let mut watchers = Vec::new();
+  for certificate in certificates {
+    let resolver = Arc::new(
+      KeypairResolver::load(&certificate.cert, &certificate.key)
+        .map_err(|e| eyre::eyre!("failed to load {}: {e:#}", certificate.cert.display()))?,
+    );
+    watchers.push(watch_keypair(resolver.clone())?);
+    resolvers.push((&certificate.domains, resolver));
+  }
+
+  let default = match default {
+    Some(default) => default,
+    None => resolvers
+      .first()
+      .map(|(_, resolver)| resolver.clone() as Arc<dyn ResolvesServerCert>)
+      .ok_or_else(|| eyre::eyre!("no certificates configured"))?,
+  };
+  let mut sni = SniResolver::new(default);
+  for (domains, resolver) in resolvers {
+    sni.add(domains, resolver);
+  }
+  Ok((sni, watchers))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn keypair(not_after: (i32, u8, u8)) -> (String, String) {
+    let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let mut params = rcgen::CertificateParams::new(vec!["example.com".into()]).unwrap();
+    params.not_before = rcgen::date_time_ymd(2020, 1, 1);
+    params.not_after = rcgen::date_time_ymd(not_after.0, not_after.1, not_after.2);
+    let cert = params.self_signed(&key).unwrap();
+    (cert.pem(), key.serialize_pem())
+  }
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tls-{}", uuid::Uuid::now_v7().simple()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn keypairs_must_match_and_be_valid() {
+    let dir = temp_dir();
+    let (cert, key) = keypair((2100, 1, 1));
+    let (expired_cert, expired_key) = keypair((2021, 1, 1));
+    let write = |name: &str, contents: &str| {
+      let path = dir.join(name);
+      std::fs::write(&path, contents).unwrap();
+      path
+    };
+    let cert = write("cert.pem", &cert);
+    let key = write("key.pem", &key);
+
+    let (_, info) = load_keypair(&cert, &key).unwrap();
+    assert_eq!(info.subject, "CN=rcgen self signed cert");
+    assert_eq!(info.not_after, "2100-01-01T00:00:00Z".parse().unwrap());
+
+    let other_key = write("other-key.pem", &expired_key);
+    let error = load_keypair(&cert, &other_key).unwrap_err();
+    assert!(error.to_string().contains("doesn't fit its key"), "{error}");
+
+    let expired_cert = write("expired.pem", &expired_cert);
+    let error = load_keypair(&expired_cert, &other_key).unwrap_err();
+    assert!(error.to_string().contains("expired"), "{error}");
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn sni_prefers_exact_names_then_wildcards() {
+    #[derive(Debug)]
+    struct Named;
+    impl ResolvesServerCert for Named {
+      fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        None
+      }
+    }
+    let default: Arc<dyn ResolvesServerCert> = Arc::new(Named);
+    let org: Arc<dyn ResolvesServerCert> = Arc::new(Named);
+    let api: Arc<dyn ResolvesServerCert> = Arc::new(Named);
+
+    let mut sni = SniResolver::new(default.clone());
+    sni.add(&["example.org".into(), "*.Example.org".into()], org.clone());
+    sni.add(&["api.example.org".into()], api.clone());
+    let picks = |name: Option<&str>, expected: &Arc<dyn ResolvesServerCert>| {
+      Arc::ptr_eq(sni.lookup(name), expected)
+    };
+
+    assert!(picks(Some("example.org"), &org));
+    assert!(picks(Some("WWW.example.org"), &org));
+    assert!(picks(Some("api.example.org"), &api));
+    // Wildcards cover exactly one label.
+    assert!(picks(Some("a.b.example.org"), &default));
+    assert!(picks(Some("example.com"), &default));
+    assert!(picks(None, &default));
+
+    assert!(validate_host_pattern("*.example.org").is_ok());
+    assert!(validate_host_pattern("example.org").is_ok());
+    assert!(validate_host_pattern("*").is_err());
+    assert!(validate_host_pattern("a.*.example.org").is_err());
+    assert!(validate_host_pattern("exa mple.org").is_err());
+  }
+
+  /// Lays out files the way a Kubernetes secret mount does.
+  #[cfg(unix)]
+  fn mount_version(dir: &Path, version: &str, (cert, key): &(String, String)) {
+    use std::os::unix::fs::symlink;
+
+    let data = dir.join(version);
+    std::fs::create_dir_all(&data).unwrap();
+    std::fs::write(data.join("tls.crt"), cert).unwrap();
+    std::fs::write(data.join("tls.key"), key).unwrap();
+    // Swap `..data` atomically by renaming a new symlink over it.
+    symlink(version, dir.join("..data_tmp")).unwrap();
+    std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn reloads_after_a_secret_mount_swap() {
+    use std::os::unix::fs::symlink;
+
+    let dir = temp_dir();
+    let first = keypair((2100, 1, 1));
+    mount_version(&dir, "..v1", &first);
+    symlink("..data/tls.crt", dir.join("tls.crt")).unwrap();
+    symlink("..data/tls.key", dir.join("tls.key")).unwrap();
+
+    let resolver =
+      Arc::new(KeypairResolver::load(&dir.join("tls.crt"), &dir.join("tls.key")).unwrap());
+    let _watcher = watch_keypair(resolver.clone()).unwrap();
+    let served = || resolver.current.load().cert[0].clone();
+    let original = served();
+
+    // A key pair that doesn't match is not swapped in.
+    let second = keypair((2100, 1, 1));
+    mount_version(&dir, "..v2", &(second.0.clone(), first.1.clone()));
+    std::fs::remove_dir_all(dir.join("..v1")).unwrap();
+    tokio::time::sleep(RELOAD_DEBOUNCE * 3).await;
+    assert_eq!(served(), original);
+
+    mount_version(&dir, "..v3", &second);
+    std::fs::remove_dir_all(dir.join("..v2")).unwrap();
+    for _ in 0..50 {
+      if served() != original {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_ne!(served(), original);
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
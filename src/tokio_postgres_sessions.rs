@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use deadpool_postgres::{GenericClient, Pool};
+use serde::Deserialize as _;
 use time::OffsetDateTime;
 use tokio_postgres::error::SqlState;
 use tower_sessions_core::{
@@ -27,6 +30,18 @@ pub enum PgStoreError {
   #[error(transparent)]
   Decode(#[from] rmp_serde::decode::Error),
 
+  /// A variant to map `serde_json` encode errors.
+  #[error(transparent)]
+  JsonEncode(serde_json::Error),
+
+  /// A variant to map `serde_json` decode errors.
+  #[error(transparent)]
+  JsonDecode(serde_json::Error),
+
+  /// A variant for stored payloads whose format header is not understood.
+  #[error("unsupported session encoding: {0}")]
+  UnsupportedEncoding(String),
+
   #[error(transparent)]
   Generic(#[from] eyre::Report),
 }
@@ -38,17 +53,177 @@ impl From<PgStoreError> for session_store::Error {
       PgStoreError::Pool(inner) => session_store::Error::Backend(inner.to_string()),
       PgStoreError::Decode(inner) => session_store::Error::Decode(inner.to_string()),
       PgStoreError::Encode(inner) => session_store::Error::Encode(inner.to_string()),
+      PgStoreError::JsonEncode(inner) => session_store::Error::Encode(inner.to_string()),
+      PgStoreError::JsonDecode(inner) => session_store::Error::Decode(inner.to_string()),
+      PgStoreError::UnsupportedEncoding(inner) => session_store::Error::Decode(inner),
       PgStoreError::Generic(inner) => session_store::Error::Backend(inner.to_string()),
     }
   }
 }
 
+/// Marks a payload as carrying a format header. Legacy rows written before
+/// headers existed are raw MessagePack arrays, which never start with these
+/// bytes.
+const HEADER_MAGIC: [u8; 2] = [0xd5, 0x5e];
+/// The current version of the format header layout.
+const HEADER_VERSION: u8 = 1;
+const HEADER_LEN: usize = HEADER_MAGIC.len() + 2;
+
+/// The serialization format used to persist session records.
+///
+/// Every stored payload starts with a small versioned header naming the codec
+/// that wrote it, so a store always decodes a row with the codec it was written
+/// with. This lets the configured codec change without invalidating existing
+/// sessions: old rows stay readable until they are rewritten or expire.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionCodec {
+  /// Compact binary encoding via `rmp_serde`, stored in the `data` column.
+  #[default]
+  MessagePack,
+  /// JSON encoding, stored in the `data_json` column as `jsonb` so sessions
+  /// can be inspected and queried from SQL.
+  Json,
+}
+
+impl SessionCodec {
+  fn tag(self) -> u8 {
+    match self {
+      SessionCodec::MessagePack => 1,
+      SessionCodec::Json => 2,
+    }
+  }
+
+  fn from_tag(tag: u8) -> Option<Self> {
+    match tag {
+      1 => Some(SessionCodec::MessagePack),
+      2 => Some(SessionCodec::Json),
+      _ => None,
+    }
+  }
+
+  /// Encode a session record with this codec.
+  pub fn encode(self, record: &Record) -> Result<EncodedRecord, PgStoreError> {
+    let mut data = Vec::with_capacity(HEADER_LEN);
+    data.extend_from_slice(&HEADER_MAGIC);
+    data.push(HEADER_VERSION);
+    data.push(self.tag());
+
+    let data_json = match self {
+      SessionCodec::MessagePack => {
+        rmp_serde::encode::write(&mut data, record)?;
+        None
+      }
+      SessionCodec::Json => {
+        Some(serde_json::to_value(JsonRecord::try_from(record)?).map_err(PgStoreError::JsonEncode)?)
+      }
+    };
+
+    Ok(EncodedRecord { data, data_json })
+  }
+}
+
+/// A session record as persisted in the store's `data` and `data_json`
+/// columns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedRecord {
+  /// The format header, followed by the payload for binary codecs.
+  pub data: Vec<u8>,
+  /// The payload for the JSON codec.
+  pub data_json: Option<serde_json::Value>,
+}
+
+impl EncodedRecord {
+  /// Returns the codec this record was written with. Payloads without a
+  /// header predate versioned encodings and are treated as MessagePack.
+  pub fn codec(&self) -> Result<SessionCodec, PgStoreError> {
+    let Some(header) = self.data.strip_prefix(&HEADER_MAGIC) else {
+      return Ok(SessionCodec::MessagePack);
+    };
+
+    match header {
+      [HEADER_VERSION, tag, ..] => SessionCodec::from_tag(*tag)
+        .ok_or_else(|| PgStoreError::UnsupportedEncoding(format!("unknown codec tag {tag}"))),
+      [HEADER_VERSION] | [] => Err(PgStoreError::UnsupportedEncoding(
+        "truncated header".to_string(),
+      )),
+      [version, ..] => Err(PgStoreError::UnsupportedEncoding(format!(
+        "unknown header version {version}"
+      ))),
+    }
+  }
+
+  /// Decode the session record with the codec named in its header.
+  pub fn decode(&self) -> Result<Record, PgStoreError> {
+    let has_header = self.data.starts_with(&HEADER_MAGIC);
+    match self.codec()? {
+      SessionCodec::MessagePack => {
+        let payload = if has_header {
+          &self.data[HEADER_LEN..]
+        } else {
+          &self.data[..]
+        };
+        Ok(rmp_serde::from_slice(payload)?)
+      }
+      SessionCodec::Json => {
+        let value = self.data_json.as_ref().ok_or_else(|| {
+          PgStoreError::UnsupportedEncoding("JSON record without a data_json payload".to_string())
+        })?;
+        JsonRecord::deserialize(value)
+          .map_err(PgStoreError::JsonDecode)?
+          .try_into()
+      }
+    }
+  }
+}
+
+/// The `jsonb` shape of a session record. `Record` serializes its id as an
+/// `i128` and its expiry as a tuple, neither of which is useful (or, for the
+/// id, representable) in JSON, so both are stored as strings instead.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct JsonRecord {
+  id: String,
+  data: HashMap<String, serde_json::Value>,
+  expiry_date: jiff::Timestamp,
+}
+
+impl TryFrom<&Record> for JsonRecord {
+  type Error = PgStoreError;
+
+  fn try_from(record: &Record) -> Result<Self, Self::Error> {
+    Ok(Self {
+      id: record.id.to_string(),
+      data: record.data.clone(),
+      expiry_date: timestamp_from_offset(record.expiry_date)?,
+    })
+  }
+}
+
+impl TryFrom<JsonRecord> for Record {
+  type Error = PgStoreError;
+
+  fn try_from(record: JsonRecord) -> Result<Self, Self::Error> {
+    let id = record
+      .id
+      .parse()
+      .map_err(|e| PgStoreError::UnsupportedEncoding(format!("invalid session id: {e}")))?;
+    let expiry_date = OffsetDateTime::from_unix_timestamp_nanos(record.expiry_date.as_nanosecond())
+      .map_err(|e| eyre::eyre!(e))?;
+
+    Ok(Self {
+      id,
+      data: record.data,
+      expiry_date,
+    })
+  }
+}
+
 /// A PostgreSQL session store.
 #[derive(Clone, Debug)]
 pub struct PostgresStore {
   pool: Pool,
   schema_name: String,
   table_name: String,
+  codec: SessionCodec,
 }
 
 impl PostgresStore {
@@ -58,9 +233,17 @@ impl PostgresStore {
       pool,
       schema_name: "tower_sessions".to_string(),
       table_name: "session".to_string(),
+      codec: SessionCodec::default(),
     }
   }
 
+  /// Set the codec used to encode newly saved sessions. Sessions written with
+  /// a different codec remain readable.
+  pub fn with_codec(mut self, codec: SessionCodec) -> Self {
+    self.codec = codec;
+    self
+  }
+
   /// Set the session table schema name with the provided name.
   pub fn with_schema_name(mut self, schema_name: impl AsRef<str>) -> Result<Self, String> {
     let schema_name = schema_name.as_ref();
@@ -120,6 +303,7 @@ impl PostgresStore {
             (
                 id text primary key not null,
                 data bytea not null,
+                data_json jsonb,
                 expiry_date timestamptz not null
             )
             "#,
@@ -128,24 +312,19 @@ impl PostgresStore {
     );
     tx.batch_execute(&create_table_query).await?;
 
-    tx.commit().await?;
-    Ok(())
-  }
-
-  async fn id_exists(&self, client: &impl GenericClient, id: &Id) -> session_store::Result<bool> {
-    let query = format!(
+    // Tables created before codecs were pluggable lack the jsonb column.
+    let add_json_column_query = format!(
       r#"
-            select exists(select 1 from "{schema_name}"."{table_name}" where id = $1)
+            alter table "{schema_name}"."{table_name}"
+            add column if not exists data_json jsonb
             "#,
       schema_name = self.schema_name,
       table_name = self.table_name
     );
+    tx.batch_execute(&add_json_column_query).await?;
 
-    let row = client
-      .query_one(query.as_str(), &[&id.to_string()])
-      .await
-      .map_err(PgStoreError::from)?;
-    Ok(row.get::<_, bool>(0))
+    tx.commit().await?;
+    Ok(())
   }
 
   async fn save_with_conn(
@@ -153,28 +332,56 @@ impl PostgresStore {
     client: &impl GenericClient,
     record: &Record,
   ) -> session_store::Result<()> {
-    let query = format!(
+    self.write_with_conn(client, record, true).await?;
+    Ok(())
+  }
+
+  /// Writes `record`, replacing a row with the same id only if `overwrite`.
+  /// Returns whether the record was written.
+  async fn write_with_conn(
+    &self,
+    client: &impl GenericClient,
+    record: &Record,
+    overwrite: bool,
+  ) -> session_store::Result<bool> {
+    let on_conflict = if overwrite {
       r#"
-            insert into "{schema_name}"."{table_name}" (id, data, expiry_date)
-            values ($1, $2, $3)
-            on conflict (id) do update
+            do update
             set
               data = excluded.data,
+              data_json = excluded.data_json,
               expiry_date = excluded.expiry_date
+            "#
+    } else {
+      "do nothing"
+    };
+    let query = format!(
+      r#"
+            insert into "{schema_name}"."{table_name}" (id, data, data_json, expiry_date)
+            values ($1, $2, $3, $4)
+            on conflict (id) {on_conflict}
             "#,
       schema_name = self.schema_name,
       table_name = self.table_name
     );
 
-    let payload = rmp_serde::to_vec(record).map_err(PgStoreError::Encode)?;
+    let encoded = self.codec.encode(record)?;
     let expiry = timestamp_from_offset(record.expiry_date).map_err(PgStoreError::from)?;
 
-    client
-      .execute(query.as_str(), &[&record.id.to_string(), &payload, &expiry])
+    let written = client
+      .execute(
+        query.as_str(),
+        &[
+          &record.id.to_string(),
+          &encoded.data,
+          &encoded.data_json,
+          &expiry,
+        ],
+      )
       .await
       .map_err(PgStoreError::from)?;
 
-    Ok(())
+    Ok(written > 0)
   }
 }
 
@@ -201,15 +408,13 @@ impl ExpiredDeletion for PostgresStore {
 #[async_trait]
 impl SessionStore for PostgresStore {
   async fn create(&self, record: &mut Record) -> session_store::Result<()> {
-    let mut client = self.pool.get().await.map_err(PgStoreError::from)?;
-    let tx = client.transaction().await.map_err(PgStoreError::from)?;
+    let client = self.pool.get().await.map_err(PgStoreError::from)?;
 
-    while self.id_exists(&tx, &record.id).await? {
+    // Checking for the id first would let two concurrent creates both see it
+    // free and the second overwrite the first, so let the insert decide.
+    while !self.write_with_conn(&client, record, false).await? {
       record.id = Id::default();
     }
-    self.save_with_conn(&tx, record).await?;
-
-    tx.commit().await.map_err(PgStoreError::from)?;
 
     Ok(())
   }
@@ -222,7 +427,7 @@ impl SessionStore for PostgresStore {
   async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
     let query = format!(
       r#"
-            select data from "{schema_name}"."{table_name}"
+            select data, data_json from "{schema_name}"."{table_name}"
             where id = $1 and expiry_date > $2
             "#,
       schema_name = self.schema_name,
@@ -236,10 +441,11 @@ impl SessionStore for PostgresStore {
       .map_err(PgStoreError::from)?;
 
     if let Some(row) = record_value {
-      let data: Vec<u8> = row.get(0);
-      Ok(Some(
-        rmp_serde::from_slice(&data).map_err(PgStoreError::Decode)?,
-      ))
+      let encoded = EncodedRecord {
+        data: row.get(0),
+        data_json: row.get(1),
+      };
+      Ok(Some(encoded.decode()?))
     } else {
       Ok(None)
     }
@@ -265,7 +471,7 @@ impl SessionStore for PostgresStore {
 /// (including letters with diacritical marks and non-Latin letters). Subsequent
 /// characters in an identifier or key word can be letters, underscores, digits
 /// (0-9), or dollar signs ($). See https://www.postgresql.org/docs/current/sql-syntax-lexical.html#SQL-SYNTAX-IDENTIFIERS for details.
-fn is_valid_identifier(name: &str) -> bool {
+pub(crate) fn is_valid_identifier(name: &str) -> bool {
   !name.is_empty()
     && name
       .chars()
@@ -280,3 +486,65 @@ fn is_valid_identifier(name: &str) -> bool {
 fn timestamp_from_offset(dt: OffsetDateTime) -> eyre::Result<jiff::Timestamp> {
   Ok(jiff::Timestamp::from_nanosecond(dt.unix_timestamp_nanos())?)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn sample_record() -> Record {
+    Record {
+      id: Id::default(),
+      data: [("user".to_string(), json!({ "id": 42, "name": "Ada" }))].into(),
+      expiry_date: OffsetDateTime::now_utc().replace_nanosecond(0).unwrap(),
+    }
+  }
+
+  #[test]
+  fn codecs_round_trip() {
+    let record = sample_record();
+    for codec in [SessionCodec::MessagePack, SessionCodec::Json] {
+      let encoded = codec.encode(&record).unwrap();
+      assert_eq!(encoded.codec().unwrap(), codec);
+      assert_eq!(encoded.decode().unwrap(), record);
+    }
+  }
+
+  #[test]
+  fn json_codec_stores_payload_as_json() {
+    let record = sample_record();
+    let encoded = SessionCodec::Json.encode(&record).unwrap();
+    assert_eq!(encoded.data.len(), HEADER_LEN);
+    let data_json = encoded.data_json.unwrap();
+    assert_eq!(data_json["data"]["user"]["name"], "Ada");
+  }
+
+  #[test]
+  fn legacy_messagepack_without_header_decodes() {
+    let record = sample_record();
+    let encoded = EncodedRecord {
+      data: rmp_serde::to_vec(&record).unwrap(),
+      data_json: None,
+    };
+    assert_eq!(encoded.codec().unwrap(), SessionCodec::MessagePack);
+    assert_eq!(encoded.decode().unwrap(), record);
+  }
+
+  #[test]
+  fn unknown_header_is_rejected() {
+    for data in [
+      vec![HEADER_MAGIC[0], HEADER_MAGIC[1], HEADER_VERSION, 99],
+      vec![HEADER_MAGIC[0], HEADER_MAGIC[1], HEADER_VERSION + 1, 1],
+      vec![HEADER_MAGIC[0], HEADER_MAGIC[1]],
+    ] {
+      let encoded = EncodedRecord {
+        data,
+        data_json: None,
+      };
+      assert!(matches!(
+        encoded.decode(),
+        Err(PgStoreError::UnsupportedEncoding(_))
+      ));
+    }
+  }
+}
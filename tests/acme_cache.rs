@@ -0,0 +1,111 @@
+//! Integration tests for `PostgresAcmeCache` against a live Postgres.
+
+mod common;
+
+use common::TestDb;
+use {{crate_name}}::acme::PostgresAcmeCache;
+use rustls_acme::{AccountCache, CertCache};
+
+const DIRECTORY: &str = "https://localhost:14000/dir";
+
+/// A cache backed by a freshly migrated schema of its own.
+async fn cache() -> Option<(TestDb, PostgresAcmeCache)> {
+  let db = TestDb::new("acme")?;
+  let cache = PostgresAcmeCache::new(db.pool.clone())
+    .with_schema_name(&db.schema_name)
+    .unwrap();
+  cache.migrate().await.unwrap();
+  Some((db, cache))
+}
+
+fn domains(names: &[&str]) -> Vec<String> {
+  names.iter().map(|name| name.to_string()).collect()
+}
+
+#[tokio::test]
+async fn certs_and_accounts_round_trip() {
+  let Some((_db, cache)) = cache().await else {
+    return;
+  };
+  let example = domains(&["example.com", "www.example.com"]);
+
+  assert_eq!(cache.load_cert(&example, DIRECTORY).await.unwrap(), None);
+  cache
+    .store_cert(&example, DIRECTORY, b"first")
+    .await
+    .unwrap();
+  cache
+    .store_cert(&example, DIRECTORY, b"second")
+    .await
+    .unwrap();
+  assert_eq!(
+    cache
+      .load_cert(&example, DIRECTORY)
+      .await
+      .unwrap()
+      .as_deref(),
+    Some(&b"second"[..])
+  );
+
+  // Certificates are per domain list and directory.
+  let other = domains(&["example.com"]);
+  assert_eq!(cache.load_cert(&other, DIRECTORY).await.unwrap(), None);
+  let staging = "https://acme-staging-v02.api.letsencrypt.org/directory";
+  assert_eq!(cache.load_cert(&example, staging).await.unwrap(), None);
+
+  // Accounts don't collide with certificates for the same names.
+  let contact = domains(&["mailto:admin@example.com"]);
+  assert_eq!(cache.load_account(&contact, DIRECTORY).await.unwrap(), None);
+  cache
+    .store_account(&contact, DIRECTORY, b"account key")
+    .await
+    .unwrap();
+  assert_eq!(
+    cache
+      .load_account(&contact, DIRECTORY)
+      .await
+      .unwrap()
+      .as_deref(),
+    Some(&b"account key"[..])
+  );
+}
+
+#[tokio::test]
+async fn one_replica_orders_at_a_time() {
+  let Some((_db, cache)) = cache().await else {
+    return;
+  };
+  let example = domains(&["example.com"]);
+
+  let lock = cache.try_lock_order(&example, DIRECTORY).await.unwrap();
+  assert!(lock.is_some());
+  assert!(
+    cache
+      .try_lock_order(&example, DIRECTORY)
+      .await
+      .unwrap()
+      .is_none()
+  );
+  // Other domains are ordered independently.
+  let other = cache
+    .try_lock_order(&domains(&["example.org"]), DIRECTORY)
+    .await
+    .unwrap();
+  assert!(other.is_some());
+
+  lock.unwrap().release().await.unwrap();
+  let relocked = cache.try_lock_order(&example, DIRECTORY).await.unwrap();
+  assert!(relocked.is_some());
+
+  // Dropping the lock, say when the ordering task dies, frees it too.
+  drop(relocked);
+  let mut freed = None;
+  for _ in 0..50 {
+    freed = cache.try_lock_order(&example, DIRECTORY).await.unwrap();
+    if freed.is_some() {
+      break;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+  }
+  assert!(freed.is_some());
+}
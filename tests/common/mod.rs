@@ -0,0 +1,76 @@
+//! Shared setup for the integration tests that run against a live Postgres.
+//!
+//! The tests connect to `DATABASE_URL` (set by `mise test:rust`) and are
+//! skipped when it is absent. Every test works in its own schema, so they can
+//! run in parallel against a shared database.
+
+// Each test crate compiles this module on its own and uses only part of it.
+#![allow(dead_code)]
+
+use deadpool_postgres::Pool;
+use uuid::Uuid;
+
+/// A connection pool and a schema name no other test uses. The schema is
+/// dropped along with the fixture, whether or not the test passed.
+pub struct TestDb {
+  pub pool: Pool,
+  pub schema_name: String,
+  pub config: tokio_postgres::Config,
+}
+
+impl TestDb {
+  /// Returns `None` when no database is configured. The schema name starts
+  /// with `prefix`; creating the schema is up to the store under test.
+  pub fn new(prefix: &str) -> Option<Self> {
+    let Ok(url) = std::env::var("DATABASE_URL") else {
+      eprintln!("DATABASE_URL is not set, skipping {prefix} test");
+      return None;
+    };
+
+    let config: tokio_postgres::Config = url.parse().unwrap();
+    Some(Self {
+      pool: create_pool(config.clone()),
+      schema_name: format!("{prefix}_test_{}", Uuid::now_v7().simple()),
+      config,
+    })
+  }
+}
+
+impl Drop for TestDb {
+  fn drop(&mut self) {
+    let config = self.config.clone();
+    let query = format!(
+      r#"
+      set lock_timeout = '5s';
+      drop schema if exists "{}" cascade;
+      "#,
+      self.schema_name
+    );
+    // The test's runtime is blocked in here, so the pool's connections can't
+    // make progress. Drop the schema over a connection of its own instead.
+    let dropped = std::thread::spawn(move || {
+      tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async move {
+          let (client, connection) = config.connect(tokio_postgres::NoTls).await?;
+          tokio::spawn(connection);
+          client.batch_execute(&query).await
+        })
+    })
+    .join();
+    match dropped {
+      Ok(Ok(())) => {}
+      Ok(Err(e)) => eprintln!("failed to drop schema {}: {e}", self.schema_name),
+      Err(_) => eprintln!("failed to drop schema {}", self.schema_name),
+    }
+  }
+}
+
+/// Building a pool does not connect, so this needs no database.
+pub fn create_pool(config: tokio_postgres::Config) -> Pool {
+  let mgr =
+    deadpool_postgres::Manager::from_config(config, tokio_postgres::NoTls, Default::default());
+  Pool::builder(mgr).max_size(16).build().unwrap()
+}
@@ -0,0 +1,250 @@
+//! Integration tests for the Postgres job queue.
+
+mod common;
+
+use std::{
+  sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+  },
+  time::Duration,
+};
+
+use common::TestDb;
+use {{crate_name}}::jobs::{Job, JobQueue, KindStats, Schedule, Worker};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Serialize, Deserialize)]
+struct Greet {
+  name: String,
+}
+
+impl Job for Greet {
+  const KIND: &'static str = "greet";
+}
+
+/// Same kind as `Greet`, but a payload `Greet` can't read.
+#[derive(Serialize, Deserialize)]
+struct Garbled {
+  name: u32,
+}
+
+impl Job for Garbled {
+  const KIND: &'static str = "greet";
+}
+
+#[derive(Serialize, Deserialize)]
+struct Tick;
+
+impl Job for Tick {
+  const KIND: &'static str = "tick";
+}
+
+/// A queue backed by a freshly migrated schema of its own.
+async fn queue() -> Option<(TestDb, JobQueue)> {
+  let db = TestDb::new("jobs")?;
+  let queue = JobQueue::new(db.pool.clone())
+    .with_schema_name(&db.schema_name)
+    .unwrap();
+  queue.migrate().await.unwrap();
+  Some((db, queue))
+}
+
+/// Waits up to five seconds for `check` to hold.
+async fn eventually<F, Fut>(mut check: F)
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = bool>,
+{
+  for _ in 0..100 {
+    if check().await {
+      return;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  panic!("condition not met in time");
+}
+
+#[tokio::test]
+async fn enqueued_jobs_run_without_waiting_for_a_poll() {
+  let Some((db, queue)) = queue().await else {
+    return;
+  };
+  let (greeted, mut greetings) = mpsc::unbounded_channel();
+  let stopping = CancellationToken::new();
+  let worker = Worker::new(queue.clone(), greeted)
+    // Only the notification can wake the worker in time.
+    .with_poll_interval(Duration::from_secs(600))
+    .listen(db.config.clone())
+    .register(
+      |greeted: mpsc::UnboundedSender<String>, job: Greet| async move {
+        greeted.send(job.name)?;
+        Ok(())
+      },
+    );
+  let worker = tokio::spawn(worker.run(stopping.clone(), Duration::from_secs(1)));
+
+  // Let the worker go idle first.
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  queue.enqueue(&Greet { name: "ada".into() }).await.unwrap();
+  let name = tokio::time::timeout(Duration::from_secs(5), greetings.recv())
+    .await
+    .unwrap();
+  assert_eq!(name.as_deref(), Some("ada"));
+  eventually(|| async { queue.stats().await.unwrap().is_empty() }).await;
+
+  stopping.cancel();
+  tokio::time::timeout(Duration::from_secs(5), worker)
+    .await
+    .unwrap()
+    .unwrap();
+}
+
+#[tokio::test]
+async fn failing_jobs_retry_then_go_to_the_dead_letters() {
+  let Some((_db, queue)) = queue().await else {
+    return;
+  };
+  let queue = queue.with_max_attempts(3);
+  let attempts = Arc::new(AtomicU32::new(0));
+  let stopping = CancellationToken::new();
+  let worker = Worker::new(queue.clone(), attempts.clone())
+    .with_poll_interval(Duration::from_millis(20))
+    .with_retry_backoff(Duration::from_millis(10), Duration::from_millis(40))
+    .register(|attempts: Arc<AtomicU32>, _: Greet| async move {
+      // Fails every attempt of the first run, then succeeds.
+      if attempts.fetch_add(1, Ordering::SeqCst) < 3 {
+        eyre::bail!("not today");
+      }
+      Ok(())
+    });
+  let worker = tokio::spawn(worker.run(stopping.clone(), Duration::from_secs(1)));
+
+  let id = queue
+    .enqueue(&Greet {
+      name: "grace".into(),
+    })
+    .await
+    .unwrap();
+  let dead_stats = || KindStats {
+    kind: "greet".into(),
+    ready: 0,
+    running: 0,
+    waiting: 0,
+    dead: 1,
+  };
+  eventually(|| async { queue.stats().await.unwrap() == [dead_stats()] }).await;
+  assert_eq!(attempts.load(Ordering::SeqCst), 3);
+  let dead = queue.dead_jobs(10).await.unwrap();
+  assert_eq!(dead.len(), 1);
+  assert_eq!(dead[0].id, id);
+  assert_eq!(dead[0].attempts, 3);
+  assert_eq!(dead[0].last_error, "not today");
+  assert_eq!(dead[0].payload["name"], "grace");
+
+  // Retrying from the dead letters runs it again.
+  assert!(queue.retry_dead(id).await.unwrap());
+  assert!(!queue.retry_dead(id).await.unwrap());
+  eventually(|| async { queue.stats().await.unwrap().is_empty() }).await;
+  assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+  // A payload the handler can't read is given up on at once.
+  queue.enqueue(&Garbled { name: 7 }).await.unwrap();
+  eventually(|| async { queue.stats().await.unwrap() == [dead_stats()] }).await;
+  assert_eq!(attempts.load(Ordering::SeqCst), 4);
+  let dead = queue.dead_jobs(10).await.unwrap();
+  assert!(
+    dead[0].last_error.starts_with("invalid payload"),
+    "{dead:?}"
+  );
+
+  stopping.cancel();
+  worker.await.unwrap();
+}
+
+#[tokio::test]
+async fn a_last_attempt_that_outlives_its_lease_goes_to_the_dead_letters() {
+  let Some((_db, queue)) = queue().await else {
+    return;
+  };
+  let queue = queue.with_max_attempts(1);
+  let runs = Arc::new(AtomicU32::new(0));
+  let stopping = CancellationToken::new();
+  let worker = Worker::new(queue.clone(), runs.clone())
+    .with_poll_interval(Duration::from_millis(20))
+    .with_lease(Duration::from_millis(200))
+    .register(|runs: Arc<AtomicU32>, _: Greet| async move {
+      runs.fetch_add(1, Ordering::SeqCst);
+      tokio::time::sleep(Duration::from_secs(1)).await;
+      Ok(())
+    });
+  let worker = tokio::spawn(worker.run(stopping.clone(), Duration::from_secs(2)));
+
+  let id = queue
+    .enqueue(&Greet {
+      name: "hopper".into(),
+    })
+    .await
+    .unwrap();
+  let dead = || async { queue.dead_jobs(10).await.unwrap() };
+  eventually(|| async { !dead().await.is_empty() }).await;
+  let buried = dead().await;
+  assert_eq!(buried[0].id, id);
+  assert_eq!(buried[0].last_error, "lease ran out on the last attempt");
+
+  // The attempt finishing late doesn't take the job back out.
+  tokio::time::sleep(Duration::from_millis(1200)).await;
+  assert_eq!(runs.load(Ordering::SeqCst), 1);
+  assert_eq!(
+    queue.stats().await.unwrap(),
+    [KindStats {
+      kind: "greet".into(),
+      ready: 0,
+      running: 0,
+      waiting: 0,
+      dead: 1,
+    }]
+  );
+  assert_eq!(dead().await.len(), 1);
+
+  stopping.cancel();
+  worker.await.unwrap();
+}
+
+#[tokio::test]
+async fn schedules_fire_once_across_replicas() {
+  let Some((_db, queue)) = queue().await else {
+    return;
+  };
+  let ticks = Arc::new(AtomicU32::new(0));
+  let stopping = CancellationToken::new();
+  let replica = || {
+    let worker = Worker::new(queue.clone(), ticks.clone())
+      .with_poll_interval(Duration::from_millis(20))
+      .register(|ticks: Arc<AtomicU32>, Tick| async move {
+        ticks.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+      })
+      .schedule("tick", Schedule::Every(Duration::from_millis(400)), &Tick)
+      .unwrap();
+    tokio::spawn(worker.run(stopping.clone(), Duration::from_secs(1)))
+  };
+  let replicas = [replica(), replica()];
+
+  // Due at 0.4s, 0.8s and 1.2s; twice that if both replicas fired.
+  tokio::time::sleep(Duration::from_millis(1400)).await;
+  let fired = ticks.load(Ordering::SeqCst);
+  assert!((2..=4).contains(&fired), "fired {fired} times");
+
+  let schedules = queue.schedules().await.unwrap();
+  assert_eq!(schedules.len(), 1);
+  assert_eq!(schedules[0].name, "tick");
+  assert_eq!(schedules[0].pattern, "every 400ms");
+
+  stopping.cancel();
+  for replica in replicas {
+    replica.await.unwrap();
+  }
+}
@@ -0,0 +1,139 @@
+//! Integration tests for leader election against a live Postgres.
+
+mod common;
+
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use common::TestDb;
+use {{crate_name}}::leader::{Election, Leadership};
+
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits up to five seconds for `check` to hold.
+async fn eventually(mut check: impl FnMut() -> bool) {
+  for _ in 0..100 {
+    if check() {
+      return;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  panic!("condition not met in time");
+}
+
+/// Which replicas are running the task right now.
+#[derive(Clone, Default)]
+struct Leaders(Arc<Mutex<Vec<usize>>>);
+
+impl Leaders {
+  fn get(&self) -> Vec<usize> {
+    self.0.lock().unwrap().clone()
+  }
+}
+
+/// Marks `replica` as running the task until dropped.
+struct Leading(Leaders, usize);
+
+impl Drop for Leading {
+  fn drop(&mut self) {
+    self
+      .0
+      .0
+      .lock()
+      .unwrap()
+      .retain(|replica| *replica != self.1);
+  }
+}
+
+#[tokio::test]
+async fn one_replica_leads_until_it_goes_away() {
+  // Elections need no tables, so the unused schema name serves as a unique
+  // election name.
+  let Some(db) = TestDb::new("leader") else {
+    return;
+  };
+  let (pool, name) = (&db.pool, &db.schema_name);
+  let leaders = Leaders::default();
+  let replica = |id: usize| {
+    let election = Election::new(pool.clone(), name.clone())
+      .with_check_interval(INTERVAL)
+      .with_retry_interval(INTERVAL);
+    let leaders = leaders.clone();
+    tokio::spawn(election.run(move || {
+      let leaders = leaders.clone();
+      async move {
+        leaders.0.lock().unwrap().push(id);
+        let _leading = Leading(leaders, id);
+        futures::future::pending::<()>().await;
+      }
+    }))
+  };
+  let replicas = [replica(0), replica(1)];
+
+  eventually(|| leaders.get().len() == 1).await;
+  // The other replica keeps asking, and keeps being told no.
+  tokio::time::sleep(INTERVAL * 5).await;
+  let leader = leaders.get();
+  assert_eq!(leader.len(), 1);
+
+  // Stopping the leader closes its connection, which frees the lock.
+  replicas[leader[0]].abort();
+  let follower = 1 - leader[0];
+  eventually(|| leaders.get() == [follower]).await;
+
+  replicas[follower].abort();
+}
+
+#[tokio::test]
+async fn losing_the_lock_is_noticed() {
+  let Some(db) = TestDb::new("leader") else {
+    return;
+  };
+  let (pool, key) = (&db.pool, &db.schema_name);
+
+  let held = Leadership::try_acquire(pool, key.as_str(), INTERVAL)
+    .await
+    .unwrap()
+    .unwrap();
+  assert!(
+    Leadership::try_acquire(pool, key.as_str(), INTERVAL)
+      .await
+      .unwrap()
+      .is_none()
+  );
+  held.release().await.unwrap();
+  let held = Leadership::try_acquire(pool, key.as_str(), INTERVAL)
+    .await
+    .unwrap()
+    .unwrap();
+  assert!(!held.is_lost());
+
+  // Postgres drops the session holding the lock, say on a failover.
+  let client = pool.get().await.unwrap();
+  let terminated = client
+    .execute(
+      r#"
+      select pg_terminate_backend(pid) from pg_locks
+      where locktype = 'advisory'
+        and granted
+        and (classid::bigint << 32 | objid::bigint) = hashtextextended($1, 0)
+      "#,
+      &[&key],
+    )
+    .await
+    .unwrap();
+  assert_eq!(terminated, 1);
+
+  tokio::time::timeout(Duration::from_secs(5), held.lost())
+    .await
+    .unwrap();
+  assert!(held.is_lost());
+  assert!(
+    Leadership::try_acquire(pool, key.as_str(), INTERVAL)
+      .await
+      .unwrap()
+      .is_some()
+  );
+}
@@ -0,0 +1,119 @@
+//! Integration tests for `PostgresBuckets` against a live Postgres.
+
+mod common;
+
+use std::time::Duration;
+
+use common::TestDb;
+use {{crate_name}}::rate_limit::{BucketStore, PostgresBuckets};
+
+/// A store backed by a freshly migrated schema of its own.
+struct TestBuckets {
+  db: TestDb,
+  store: PostgresBuckets,
+}
+
+impl TestBuckets {
+  /// Returns `None` when no database is configured.
+  async fn new() -> Option<Self> {
+    let db = TestDb::new("rate_limit")?;
+    let store = PostgresBuckets::new(db.pool.clone())
+      .with_schema_name(&db.schema_name)
+      .unwrap();
+    store.migrate().await.unwrap();
+
+    Some(Self { db, store })
+  }
+
+  async fn keys(&self) -> Vec<String> {
+    let client = self.db.pool.get().await.unwrap();
+    let query = format!(
+      r#"select key from "{}"."bucket" order by key"#,
+      self.db.schema_name
+    );
+    client
+      .query(query.as_str(), &[])
+      .await
+      .unwrap()
+      .iter()
+      .map(|row| row.get(0))
+      .collect()
+  }
+}
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+#[tokio::test]
+async fn buckets_are_shared_between_stores() {
+  let Some(tb) = TestBuckets::new().await else {
+    return;
+  };
+
+  let replica = tb.store.clone();
+  let first = tb.store.take("ip:192.0.2.1", 2, HOUR).await.unwrap();
+  assert!(first.allowed);
+  assert!((first.tokens - 1.0).abs() < 0.01);
+  assert!(replica.take("ip:192.0.2.1", 2, HOUR).await.unwrap().allowed);
+  assert!(
+    !tb
+      .store
+      .take("ip:192.0.2.1", 2, HOUR)
+      .await
+      .unwrap()
+      .allowed
+  );
+  assert!(
+    tb.store
+      .take("ip:192.0.2.2", 2, HOUR)
+      .await
+      .unwrap()
+      .allowed
+  );
+}
+
+#[tokio::test]
+async fn concurrent_takes_never_share_a_token() {
+  let Some(tb) = TestBuckets::new().await else {
+    return;
+  };
+
+  let tasks: Vec<_> = (0..16)
+    .map(|_| {
+      let store = tb.store.clone();
+      tokio::spawn(async move { store.take("ip:192.0.2.1", 10, HOUR).await.unwrap() })
+    })
+    .collect();
+
+  let mut allowed = 0;
+  for task in tasks {
+    allowed += usize::from(task.await.unwrap().allowed);
+  }
+  assert_eq!(allowed, 10);
+}
+
+#[tokio::test]
+async fn prune_forgets_refilled_buckets() {
+  let Some(tb) = TestBuckets::new().await else {
+    return;
+  };
+
+  tb.store.take("ip:192.0.2.1", 2, HOUR).await.unwrap();
+  tb.store
+    .take("ip:192.0.2.2", 1, Duration::from_millis(100))
+    .await
+    .unwrap();
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  tb.store.prune().await.unwrap();
+
+  assert_eq!(tb.keys().await, ["ip:192.0.2.1"]);
+}
+
+#[tokio::test]
+async fn migrate_is_idempotent() {
+  let Some(tb) = TestBuckets::new().await else {
+    return;
+  };
+
+  tb.store.migrate().await.unwrap();
+  tb.store.migrate().await.unwrap();
+}
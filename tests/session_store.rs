@@ -0,0 +1,229 @@
+//! Integration tests for `PostgresStore` against a live Postgres.
+
+mod common;
+
+use std::collections::HashSet;
+
+use common::{TestDb, create_pool};
+use {{crate_name}}::tokio_postgres_sessions::{PostgresStore, SessionCodec};
+use futures::future::join_all;
+use serde_json::json;
+use time::{Duration, OffsetDateTime};
+use tower_sessions_core::{
+  ExpiredDeletion, SessionStore,
+  session::{Id, Record},
+};
+
+/// A store backed by a freshly migrated schema of its own.
+struct TestStore {
+  db: TestDb,
+  store: PostgresStore,
+}
+
+impl TestStore {
+  /// Returns `None` when no database is configured.
+  async fn new() -> Option<Self> {
+    Self::with_codec(SessionCodec::default()).await
+  }
+
+  async fn with_codec(codec: SessionCodec) -> Option<Self> {
+    let db = TestDb::new("session")?;
+    let store = PostgresStore::new(db.pool.clone())
+      .with_schema_name(&db.schema_name)
+      .unwrap()
+      .with_codec(codec);
+    store.migrate().await.unwrap();
+
+    Some(Self { db, store })
+  }
+
+  async fn row_count(&self) -> i64 {
+    let client = self.db.pool.get().await.unwrap();
+    let query = format!(
+      r#"select count(*) from "{}"."session""#,
+      self.db.schema_name
+    );
+    client.query_one(query.as_str(), &[]).await.unwrap().get(0)
+  }
+}
+
+fn record_expiring_in(duration: Duration) -> Record {
+  Record {
+    id: Id::default(),
+    data: [("user".to_string(), json!({ "id": 42, "name": "Ada" }))].into(),
+    // Postgres stores microseconds, so drop the rest to compare round-trips.
+    expiry_date: (OffsetDateTime::now_utc() + duration)
+      .replace_nanosecond(0)
+      .unwrap(),
+  }
+}
+
+#[tokio::test]
+async fn save_and_load_round_trip() {
+  for codec in [SessionCodec::MessagePack, SessionCodec::Json] {
+    let Some(ts) = TestStore::with_codec(codec).await else {
+      return;
+    };
+
+    let mut record = record_expiring_in(Duration::hours(1));
+    ts.store.save(&record).await.unwrap();
+    assert_eq!(
+      ts.store.load(&record.id).await.unwrap(),
+      Some(record.clone())
+    );
+
+    record.data.insert("theme".to_string(), json!("dark"));
+    ts.store.save(&record).await.unwrap();
+    assert_eq!(ts.store.load(&record.id).await.unwrap(), Some(record));
+    assert_eq!(ts.row_count().await, 1);
+  }
+}
+
+#[tokio::test]
+async fn load_reads_rows_written_with_another_codec() {
+  let Some(ts) = TestStore::with_codec(SessionCodec::MessagePack).await else {
+    return;
+  };
+
+  let record = record_expiring_in(Duration::hours(1));
+  ts.store.save(&record).await.unwrap();
+
+  let json_store = ts.store.clone().with_codec(SessionCodec::Json);
+  assert_eq!(json_store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn create_retries_on_id_collision() {
+  let Some(ts) = TestStore::new().await else {
+    return;
+  };
+
+  let existing = record_expiring_in(Duration::hours(1));
+  ts.store.save(&existing).await.unwrap();
+
+  let mut colliding = record_expiring_in(Duration::hours(1));
+  colliding.id = existing.id;
+  colliding.data.insert("other".to_string(), json!(true));
+  ts.store.create(&mut colliding).await.unwrap();
+
+  assert_ne!(colliding.id, existing.id);
+  assert_eq!(
+    ts.store.load(&existing.id).await.unwrap(),
+    Some(existing.clone())
+  );
+  assert_eq!(ts.store.load(&colliding.id).await.unwrap(), Some(colliding));
+}
+
+#[tokio::test]
+async fn concurrent_creates_get_distinct_ids() {
+  let Some(ts) = TestStore::new().await else {
+    return;
+  };
+
+  // Every record starts out with the same id, so the creates race for it.
+  let id = Id::default();
+  let mut records: Vec<Record> = (0..16)
+    .map(|n| {
+      let mut record = record_expiring_in(Duration::hours(1));
+      record.id = id;
+      record.data.insert("n".to_string(), json!(n));
+      record
+    })
+    .collect();
+  let created = join_all(records.iter_mut().map(|record| ts.store.create(record))).await;
+  assert!(created.iter().all(Result::is_ok), "{created:?}");
+
+  let ids: HashSet<Id> = records.iter().map(|record| record.id).collect();
+  assert_eq!(ids.len(), records.len());
+  for record in records {
+    assert_eq!(ts.store.load(&record.id).await.unwrap(), Some(record));
+  }
+  assert_eq!(ts.row_count().await, 16);
+}
+
+#[tokio::test]
+async fn load_ignores_expired_sessions() {
+  let Some(ts) = TestStore::new().await else {
+    return;
+  };
+
+  let expired = record_expiring_in(-Duration::minutes(1));
+  ts.store.save(&expired).await.unwrap();
+
+  assert_eq!(ts.store.load(&expired.id).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn delete_expired_keeps_live_sessions() {
+  let Some(ts) = TestStore::new().await else {
+    return;
+  };
+
+  let expired = record_expiring_in(-Duration::minutes(1));
+  let live = record_expiring_in(Duration::hours(1));
+  ts.store.save(&expired).await.unwrap();
+  ts.store.save(&live).await.unwrap();
+  assert_eq!(ts.row_count().await, 2);
+
+  ts.store.delete_expired().await.unwrap();
+
+  assert_eq!(ts.row_count().await, 1);
+  assert_eq!(ts.store.load(&live.id).await.unwrap(), Some(live));
+}
+
+#[tokio::test]
+async fn delete_removes_session() {
+  let Some(ts) = TestStore::new().await else {
+    return;
+  };
+
+  let record = record_expiring_in(Duration::hours(1));
+  ts.store.save(&record).await.unwrap();
+  ts.store.delete(&record.id).await.unwrap();
+
+  assert_eq!(ts.store.load(&record.id).await.unwrap(), None);
+  assert_eq!(ts.row_count().await, 0);
+}
+
+#[tokio::test]
+async fn migrate_is_idempotent() {
+  let Some(ts) = TestStore::new().await else {
+    return;
+  };
+
+  ts.store.migrate().await.unwrap();
+  ts.store.migrate().await.unwrap();
+}
+
+#[test]
+fn rejects_invalid_identifiers() {
+  let pool = create_pool("postgres://localhost/unused".parse().unwrap());
+
+  for name in ["", "1session", "bad-name", "has space", r#"quo"te"#] {
+    assert!(
+      PostgresStore::new(pool.clone())
+        .with_schema_name(name)
+        .is_err(),
+      "schema name {name:?} should be rejected"
+    );
+    assert!(
+      PostgresStore::new(pool.clone())
+        .with_table_name(name)
+        .is_err(),
+      "table name {name:?} should be rejected"
+    );
+  }
+
+  for name in ["session", "_private", "sessions$v2", "sesión"] {
+    assert!(
+      PostgresStore::new(pool.clone())
+        .with_schema_name(name)
+        .is_ok()
+    );
+    assert!(
+      PostgresStore::new(pool.clone())
+        .with_table_name(name)
+        .is_ok()
+    );
+  }
+}